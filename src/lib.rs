@@ -0,0 +1,5959 @@
+//! Rename images based on the folder they are in, as used by the `imgmv` command.
+//!
+//! The options are the command-line arguments in [`Args`], which can be parsed with
+//! [`clap::Parser`]. [`move_images`] plans and performs a run, built from [`get_source_files`],
+//! [`generate_source_destination_pairs`] and [`get_prefix`].
+
+use chrono::{Datelike, NaiveDate, NaiveDateTime, Timelike};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, Parser, Subcommand, ValueEnum};
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use log::{debug, error, info, warn};
+use notify::Watcher;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+/// The default target folder when only a source folder is given.
+const DEFAULT_DESTINATION: &str = ".";
+
+/// The name of the config file providing defaults for the command-line arguments.
+const CONFIG_FILE_NAME: &str = "imgmv.toml";
+
+/// The name of the undo log written to the destination folder.
+const UNDO_LOG_NAME: &str = ".imgmv-undo.jsonl";
+
+/// The name of the file listing glob patterns of files to ignore in a source folder.
+const IGNORE_FILE_NAME: &str = ".imgmvignore";
+
+/// The exit status when `--check` finds existing destination files.
+const COLLISIONS_EXIT_CODE: u8 = 3;
+
+/// The characters that are unsafe in file names on common file systems.
+const UNSAFE_NAME_CHARS: &[char] = &['/', '\\', ':', '*', '?', '"', '<', '>', '|'];
+
+/// The longest file name that common file systems allow, in bytes (UTF-16 units on Windows).
+const MAX_NAME_LEN: usize = 255;
+
+/// How often the sizes of new files are checked with `--watch`. A file is handled once its size
+/// hasn't changed between two checks.
+const WATCH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// The file extensions recognized as images, in lowercase.
+const IMAGE_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "gif", "bmp", "tiff", "webp", "heic"];
+
+/// The command-line arguments of `imgmv`, which are also the options of the library functions.
+#[derive(Parser, Clone, Debug)]
+#[command(
+    name = "imgmv",
+    version,
+    about = "Rename images based on their folder.",
+    long_about = None,
+    after_help = "Defaults for many options can be set in imgmv.toml in the current folder or in \
+        $XDG_CONFIG_HOME/imgmv/. Options given on the command line take precedence.",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// The source folders containing the images, followed by the target folder to put the renamed
+    /// images in. With a single folder, the target folder defaults to ".". With `--stdin`, only
+    /// the target folder is given.
+    #[arg(
+        value_name = "PATH",
+        required_unless_present_any = ["undo", "stdin"],
+        num_args = 1..,
+    )]
+    pub paths: Vec<PathBuf>,
+
+    /// Read the source files from stdin, one path per line, instead of scanning source folders.
+    #[arg(long, conflicts_with = "undo")]
+    pub stdin: bool,
+
+    /// Separate the paths read from stdin by NUL characters instead of newlines.
+    #[arg(short = '0', long, requires = "stdin")]
+    pub null: bool,
+
+    /// Verify that copied files match their source, removing copies that don't.
+    #[arg(long, requires = "copy")]
+    pub verify: bool,
+
+    /// Hard-link copies of files with the same content to the first copy, instead of copying them.
+    #[arg(long, requires = "copy")]
+    pub hardlink_dupes: bool,
+
+    /// Retry a failed move or copy up to this many times, unless the error is permanent, such as
+    /// a missing source file.
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub retries: u32,
+
+    /// The delay before retrying a failed move or copy, in milliseconds. Doubles for each retry.
+    #[arg(long, value_name = "MS", default_value_t = 1000)]
+    pub retry_delay: u64,
+
+    /// Create the destination folder if it does not exist.
+    #[arg(short, long)]
+    pub make_dest: bool,
+
+    /// Copy instead of moving.
+    #[arg(short, long)]
+    pub copy: bool,
+
+    /// The image prefix to use. Defaults to the source folder name. May be empty.
+    #[arg(short, long)]
+    pub prefix: Option<String>,
+
+    /// Without --prefix, use the first line of this file in the source folder as the prefix, if
+    /// the file exists.
+    #[arg(long, value_name = "NAME")]
+    pub prefix_file: Option<PathBuf>,
+
+    /// Without --prefix, join the names of this many folders ending with the source folder, such
+    /// as 2023_Summer for 2023/Summer with 2.
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 1,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+    )]
+    pub prefix_from_ancestors: usize,
+
+    /// Use a different prefix for files with these extensions, such as jpg=photo,mp4=clip. Each
+    /// prefix is numbered separately.
+    #[arg(long, value_name = "EXT=PREFIX", value_delimiter = ',', value_parser = parse_ext_prefix)]
+    pub ext_prefix: Vec<(String, String)>,
+
+    /// The suffix to put after the index, before the extension.
+    #[arg(long)]
+    pub suffix: Option<String>,
+
+    /// Overwrite existing files in the destination folder. Short for --collision-strategy
+    /// overwrite.
+    #[arg(short, long)]
+    pub force: bool,
+
+    /// What to do with files whose destination already exists. Defaults to skip, or to overwrite
+    /// with --force.
+    #[arg(long, value_enum, conflicts_with = "force")]
+    pub collision_strategy: Option<CollisionStrategy>,
+
+    /// Move overwritten destination files to the trash instead of deleting them.
+    #[arg(long)]
+    pub trash: bool,
+
+    /// The number of files to process in parallel. Defaults to the number of CPUs.
+    #[arg(
+        short,
+        long,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+    )]
+    pub jobs: Option<usize>,
+
+    /// Log file actions.
+    #[arg(short, long)]
+    pub verbose: bool,
+
+    /// Always show a progress bar.
+    #[arg(long, overrides_with = "no_progress")]
+    pub progress: bool,
+
+    /// Never show a progress bar. By default, a progress bar is shown unless logging file actions,
+    /// doing a dry run, or writing to something other than a terminal.
+    #[arg(long, overrides_with = "progress")]
+    pub no_progress: bool,
+
+    /// Stop at the first file that fails, instead of handling the remaining files.
+    #[arg(long)]
+    pub strict: bool,
+
+    /// Stop at the first file that fails, and revert the files handled so far. Overwritten
+    /// destination files cannot be restored.
+    #[arg(long)]
+    pub atomic: bool,
+
+    /// Put the renamed images in this zip archive instead of in the destination folder. The
+    /// archive is not overwritten unless requested.
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["atomic", "trash", "verify", "hardlink_dupes", "throttle"],
+    )]
+    pub zip: Option<PathBuf>,
+
+    /// Ask for confirmation before touching any files. Ignored if stdin is not a terminal.
+    #[arg(short, long)]
+    pub interactive: bool,
+
+    /// Ask for confirmation before handling more than this many files. Fails instead if stdin is
+    /// not a terminal.
+    #[arg(long, value_name = "N")]
+    pub confirm_above: Option<usize>,
+
+    /// Handle any number of files without asking, overriding --confirm-above.
+    #[arg(short, long, conflicts_with = "interactive")]
+    pub yes: bool,
+
+    /// After handling the files, keep watching the source folders and handle new files once they
+    /// are completely written, numbered after the files already in the destination folder. Runs
+    /// until interrupted.
+    #[arg(long, conflicts_with_all = ["stdin", "zip", "dry_run", "check", "plan", "undo"])]
+    pub watch: bool,
+
+    /// Print the source files that would be handled, one per line in the order they are
+    /// numbered, and exit without moving anything.
+    #[arg(long, conflicts_with_all = ["watch", "undo", "plan", "check"])]
+    pub list_only: bool,
+
+    /// Only print errors.
+    #[arg(short, long, conflicts_with = "verbose")]
+    pub quiet: bool,
+
+    /// Do nothing.
+    #[arg(short, long)]
+    pub dry_run: bool,
+
+    /// Check that the destination has enough free space for the copied files, and for files moved
+    /// from another device, before touching any files. A dry run always warns if it hasn't.
+    #[arg(long)]
+    pub check_space: bool,
+
+    /// Only list the destination files that already exist, without touching any files. Exits with
+    /// status 3 if there are any.
+    #[arg(long, conflicts_with = "plan")]
+    pub check: bool,
+
+    /// Print the plan of file actions as JSON without touching any files.
+    #[arg(long)]
+    pub plan: bool,
+
+    /// Show the planned destination layout as a tree in a dry run with the text output format.
+    #[arg(long, requires = "dry_run")]
+    pub tree: bool,
+
+    /// The format of the file actions printed to stdout.
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub format: OutputFormat,
+
+    /// When to color the file actions and the summary.
+    #[arg(long, value_enum, default_value_t = ColorMode::Auto)]
+    pub color: ColorMode,
+
+    /// Also write all log messages of imgmv, including debug messages, to this file.
+    #[arg(long, value_name = "FILE")]
+    pub log_file: Option<PathBuf>,
+
+    /// Write a CSV report of every file action, including skipped and failed files.
+    #[arg(long, value_name = "FILE")]
+    pub report: Option<PathBuf>,
+
+    /// Revert the operations recorded in an undo log instead of moving images.
+    #[arg(long, value_name = "LOGFILE", conflicts_with = "paths")]
+    pub undo: Option<PathBuf>,
+
+    /// Include images in subdirectories of the source folder.
+    #[arg(short, long)]
+    pub recursive: bool,
+
+    /// The maximum depth to recurse to, where 1 is the source folder itself.
+    #[arg(
+        long,
+        requires = "recursive",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+    )]
+    pub max_depth: Option<usize>,
+
+    /// Recreate the subfolders of the source folders in the destination folder, instead of putting
+    /// all images directly in it.
+    #[arg(long, requires = "recursive")]
+    pub preserve_structure: bool,
+
+    /// Include the files that symlinks point to. By default, symlinks are skipped.
+    #[arg(long)]
+    pub follow_symlinks: bool,
+
+    /// Include hidden files and folders, whose names begin with a dot.
+    #[arg(long)]
+    pub hidden: bool,
+
+    /// Include all files, not just images.
+    #[arg(short, long)]
+    pub all: bool,
+
+    /// Include empty files, which are skipped by default since they are usually broken downloads.
+    #[arg(long)]
+    pub allow_empty: bool,
+
+    /// Only include files with this extension instead of the built-in image extensions. The
+    /// leading dot is optional. May be repeated.
+    #[arg(
+        short,
+        long,
+        value_name = "EXTENSION",
+        conflicts_with = "all",
+        value_parser = parse_extension,
+    )]
+    pub ext: Vec<String>,
+
+    /// The order in which the images are numbered.
+    #[arg(long, value_enum, default_value_t = SortOrder::None)]
+    pub sort: SortOrder,
+
+    /// Reverse the order in which the images are numbered, after sorting.
+    #[arg(long)]
+    pub reverse: bool,
+
+    /// The character to replace whitespace in the prefix with, such as `_` or `-`. Runs of
+    /// whitespace are replaced by one character. Spaces are kept by default.
+    #[arg(long, value_name = "CHAR", value_parser = parse_space_replacement)]
+    pub space_replacement: Option<char>,
+
+    /// The separator between the prefix and the index. May be empty.
+    #[arg(long, default_value = "_", value_parser = parse_separator)]
+    pub separator: String,
+
+    /// The destination file name template. Supports the placeholders {prefix}, {separator}
+    /// (left out after an empty prefix), {index}, {suffix} (the separator and the suffix, if one
+    /// is given), {ext} (including the leading dot), {name} (the original file name without
+    /// extension, with whitespace replaced by underscores), and {width} and {height} (the image
+    /// dimensions in pixels).
+    #[arg(short, long, default_value = "{prefix}{separator}{index}{suffix}{ext}")]
+    pub template: Template,
+
+    /// Keep the original file name after the index. Short for the template
+    /// {prefix}{separator}{index}{separator}{name}{suffix}{ext}.
+    #[arg(short, long, conflicts_with = "template")]
+    pub keep_name: bool,
+
+    /// Keep the original file names, only moving or copying the files into the destination.
+    #[arg(long, conflicts_with_all = ["template", "keep_name", "prefix", "ext_prefix", "suffix", "date_prefix", "mtime_prefix", "lowercase_name"])]
+    pub no_rename: bool,
+
+    /// Rename the files as listed in this CSV file, with the columns original and new, instead of
+    /// numbering them. Files that aren't listed are skipped. The new names are put in the folders
+    /// chosen by --preserve-structure, --by-camera and --by-date.
+    #[arg(long, value_name = "CSV", conflicts_with_all = ["no_rename", "hash_name", "template", "keep_name", "prefix", "prefix_file", "ext_prefix", "suffix", "date_prefix", "mtime_prefix", "skip_renamed", "continue_numbering", "batch_size"])]
+    pub map: Option<PathBuf>,
+
+    /// Fail if some source files are not listed in the --map file, instead of skipping them.
+    #[arg(long, requires = "map")]
+    pub map_strict: bool,
+
+    /// Name each file by the start of the hash of its contents, as in ab12cd34.jpg, instead of
+    /// by prefix and index. Files with the same contents get the same name, so only the first one
+    /// is handled, and the hash is lengthened for files with different contents.
+    #[arg(long, conflicts_with_all = ["no_rename", "template", "keep_name", "prefix", "prefix_file", "ext_prefix", "suffix", "date_prefix", "mtime_prefix", "skip_renamed", "continue_numbering"])]
+    pub hash_name: bool,
+
+    /// The hash algorithm for --hash-name.
+    #[arg(long, value_enum, default_value_t = HashAlgo::Blake3, requires = "hash_name")]
+    pub hash_algo: HashAlgo,
+
+    /// The number of hexadecimal digits of the hash in names with --hash-name.
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 8,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(4..=64),
+        requires = "hash_name",
+    )]
+    pub hash_length: usize,
+
+    /// Leave files alone whose new name would only differ in case, such as an extension change
+    /// from .JPG to .jpg, counting them as unchanged.
+    #[arg(long)]
+    pub skip_unchanged: bool,
+
+    /// Lowercase the whole file names of the renamed images, including the prefix and extension.
+    #[arg(long)]
+    pub lowercase_name: bool,
+
+    /// Lowercase the extensions of the renamed images. Short for --ext-case lower.
+    #[arg(long)]
+    pub lowercase_ext: bool,
+
+    /// Give files without an extension the extension of the type detected from their contents,
+    /// such as .jpg. Such files are also selected by that extension.
+    #[arg(long)]
+    pub sniff_ext: bool,
+
+    /// How to write the extensions of the renamed images. Defaults to lower with
+    /// --lowercase-ext, and to keep otherwise.
+    #[arg(long, value_enum, conflicts_with = "lowercase_ext")]
+    pub ext_case: Option<ExtCase>,
+
+    /// Change these extensions of the renamed images, such as jpeg=jpg,tif=tiff. Extensions are
+    /// matched regardless of case, and the files are only renamed, not converted.
+    #[arg(long, value_name = "EXT=NEW", value_delimiter = ',', value_parser = parse_ext_rewrite)]
+    pub ext_rewrite: Vec<(String, String)>,
+
+    /// Only include files whose name matches this glob pattern. May be repeated. Includes are
+    /// applied before excludes, so a file matching both is skipped.
+    #[arg(long, value_name = "GLOB")]
+    pub include: Vec<globset::Glob>,
+
+    /// Skip files whose name matches this glob pattern. May be repeated. Excludes take precedence
+    /// over includes.
+    #[arg(short = 'x', long, value_name = "GLOB")]
+    pub exclude: Vec<globset::Glob>,
+
+    /// Limit copying to this many bytes per second, such as 5M, across all parallel copies.
+    /// Supports the suffixes k, M and G.
+    #[arg(long, value_name = "RATE", value_parser = parse_rate, requires = "copy")]
+    pub throttle: Option<u64>,
+
+    /// Skip files smaller than this size, such as 10k. Supports the suffixes k, M and G.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub min_size: Option<u64>,
+
+    /// Skip files larger than this size, such as 5M. Supports the suffixes k, M and G.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub max_size: Option<u64>,
+
+    /// Skip files captured before this date, such as 2024-05-01.
+    #[arg(long, value_name = "DATE", value_parser = parse_date)]
+    pub after: Option<NaiveDate>,
+
+    /// Skip files captured after this date, such as 2024-05-31.
+    #[arg(long, value_name = "DATE", value_parser = parse_date)]
+    pub before: Option<NaiveDate>,
+
+    /// Skip files modified less than this long ago, such as 30m, as they may still be edited.
+    /// Supports the units s, m, h and d, which can be combined as in 1h30m.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub min_age: Option<std::time::Duration>,
+
+    /// Only handle files modified after the time stored in this file, and store the time of this
+    /// run in it afterwards. All files are handled if the file does not exist.
+    #[arg(long, value_name = "PATH")]
+    pub since_file: Option<PathBuf>,
+
+    /// Skip files with the same content as an earlier file.
+    #[arg(long)]
+    pub dedup: bool,
+
+    /// Skip images that look nearly the same as an earlier image, such as burst shots.
+    #[arg(long)]
+    pub dedup_perceptual: bool,
+
+    /// The largest number of differing bits between the perceptual hashes of images that are
+    /// considered the same.
+    #[arg(long, default_value_t = 5, requires = "dedup_perceptual")]
+    pub dedup_threshold: u32,
+
+    /// Split the images into subfolders named batch_000, batch_001 and so on, with at most this
+    /// many images each. Numbering restarts in each batch.
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+    )]
+    pub batch_size: Option<usize>,
+
+    /// Number the images across batches instead of restarting in each batch.
+    #[arg(long, requires = "batch_size")]
+    pub global_index: bool,
+
+    /// Put the images in subfolders of the destination named after the camera model in their
+    /// EXIF data, or "unknown". Numbering restarts in each subfolder.
+    #[arg(long)]
+    pub by_camera: bool,
+
+    /// Put the images in subfolders of the destination named after their EXIF capture date, or
+    /// modification time, using a strftime-style format such as --by-date=%Y. Defaults to %Y/%m.
+    /// Numbering restarts in each subfolder.
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "%Y/%m",
+        value_parser = parse_date_format,
+    )]
+    pub by_date: Option<String>,
+
+    /// Use the EXIF capture date, or modification time, of each image as the prefix, using a
+    /// strftime-style format such as %Y%m%d_%H%M%S. An explicit --prefix follows the date.
+    #[arg(long, value_name = "FORMAT", value_parser = parse_date_format)]
+    pub date_prefix: Option<String>,
+
+    /// Like --date-prefix, but always use the modification time, for files without EXIF data
+    /// such as documents and videos.
+    #[arg(long, value_name = "FORMAT", value_parser = parse_date_format, conflicts_with = "date_prefix")]
+    pub mtime_prefix: Option<String>,
+
+    /// The index of the first image.
+    #[arg(short, long, default_value_t = 0)]
+    pub start: usize,
+
+    /// Handle at most this many files, the first ones in sort order.
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+    )]
+    pub max_files: Option<usize>,
+
+    /// Continue numbering after the highest index already used in the destination folder.
+    #[arg(long = "continue")]
+    pub continue_numbering: bool,
+
+    /// Skip source files that are already named by the template with the prefix. Combine with
+    /// --continue to number new files after them.
+    #[arg(long)]
+    pub skip_renamed: bool,
+
+    /// Zero-pad a decimal index to this width. Defaults to the width of the largest index.
+    #[arg(long)]
+    pub pad_width: Option<usize>,
+
+    /// A printf-style format for the index, such as %05d to zero-pad it to 5 digits, or %d to not
+    /// pad it at all.
+    #[arg(long, value_name = "FORMAT", value_parser = parse_index_format, conflicts_with_all = ["pad_width", "index_style"])]
+    pub index_format: Option<usize>,
+
+    /// How to write the index.
+    #[arg(long, value_enum, default_value_t = IndexStyle::Decimal)]
+    pub index_style: IndexStyle,
+}
+
+/// The subcommands of `imgmv`, which replace renaming images.
+#[derive(Subcommand, Clone, Debug)]
+pub enum Command {
+    /// Print a shell completion script to stdout.
+    #[command(hide = true)]
+    Completions {
+        /// The shell to complete in.
+        shell: clap_complete::Shell,
+    },
+}
+
+/// What to do with a file whose destination already exists.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CollisionStrategy {
+    /// Leave the existing file and skip the source file.
+    Skip,
+    /// Replace the existing file.
+    Overwrite,
+    /// Append a counter to the destination file name, as in name-1.jpg.
+    Rename,
+    /// Stop before handling any files.
+    Error,
+}
+
+/// How the extension of a destination file name is written.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExtCase {
+    /// Keep the extension of each file as it is.
+    Keep,
+    /// Lowercase the extensions.
+    Lower,
+    /// Write each extension as the first file with that extension, ignoring case, has it.
+    Consistent,
+}
+
+/// How the index in a destination file name is written.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IndexStyle {
+    /// Decimal digits, zero-padded: 0, 1, ... 9, 10.
+    Decimal,
+    /// Lowercase letters, not padded: a, b, ... z, aa.
+    Alpha,
+}
+
+/// The hash algorithm for naming files by their contents.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HashAlgo {
+    /// BLAKE3, which is fast.
+    Blake3,
+    /// SHA-256, which other tools can check.
+    Sha256,
+}
+
+/// The order in which source files are numbered.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SortOrder {
+    /// Keep the order in which the files are read from the source folder, which depends on the
+    /// operating system.
+    None,
+    /// Sort by file name in natural order, ignoring case and comparing numbers by value, so that
+    /// img2 sorts before img10. Also available as "natural".
+    #[value(alias = "natural")]
+    #[serde(alias = "natural")]
+    Name,
+    /// Sort by modification time.
+    Mtime,
+    /// Sort by file size.
+    Size,
+    /// Sort by the EXIF capture date, falling back to the modification time.
+    ExifDate,
+}
+
+impl Args {
+    /// Get how to write the extensions of destination file names.
+    pub fn ext_case(&self) -> ExtCase {
+        self.ext_case.unwrap_or(if self.lowercase_ext {
+            ExtCase::Lower
+        } else {
+            ExtCase::Keep
+        })
+    }
+
+    /// Get what to do with files whose destination already exists.
+    pub fn collision_strategy(&self) -> CollisionStrategy {
+        self.collision_strategy.unwrap_or(if self.force {
+            CollisionStrategy::Overwrite
+        } else {
+            CollisionStrategy::Skip
+        })
+    }
+
+    /// Get the source folders, which are all the paths except the destination, or none when
+    /// reading the source files from stdin.
+    pub fn sources(&self) -> &[PathBuf] {
+        if self.stdin {
+            return &[];
+        }
+        match self.paths.len() {
+            0 | 1 => &self.paths,
+            len => &self.paths[..len - 1],
+        }
+    }
+
+    /// Get the destination folder, which is the last path if more than one is given, or the only
+    /// path when reading the source files from stdin.
+    pub fn destination(&self) -> PathBuf {
+        if self.stdin {
+            return self
+                .paths
+                .last()
+                .cloned()
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_DESTINATION));
+        }
+        match self.paths.len() {
+            0 | 1 => PathBuf::from(DEFAULT_DESTINATION),
+            len => self.paths[len - 1].clone(),
+        }
+    }
+}
+
+/// Defaults for the command-line arguments, loaded from `imgmv.toml`.
+///
+/// Keys are the long option names, such as `copy = true` or `lowercase-ext = true`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    copy: Option<bool>,
+    prefix: Option<String>,
+    prefix_file: Option<PathBuf>,
+    prefix_from_ancestors: Option<usize>,
+    ext_prefix: Option<std::collections::BTreeMap<String, String>>,
+    suffix: Option<String>,
+    force: Option<bool>,
+    collision_strategy: Option<CollisionStrategy>,
+    jobs: Option<usize>,
+    confirm_above: Option<usize>,
+    retries: Option<u32>,
+    retry_delay: Option<u64>,
+    verbose: Option<bool>,
+    quiet: Option<bool>,
+    color: Option<ColorMode>,
+    recursive: Option<bool>,
+    max_depth: Option<usize>,
+    preserve_structure: Option<bool>,
+    hidden: Option<bool>,
+    all: Option<bool>,
+    allow_empty: Option<bool>,
+    ext: Option<Vec<String>>,
+    sort: Option<SortOrder>,
+    reverse: Option<bool>,
+    space_replacement: Option<char>,
+    separator: Option<String>,
+    template: Option<String>,
+    keep_name: Option<bool>,
+    lowercase_name: Option<bool>,
+    lowercase_ext: Option<bool>,
+    ext_case: Option<ExtCase>,
+    ext_rewrite: Option<std::collections::BTreeMap<String, String>>,
+    include: Option<Vec<String>>,
+    exclude: Option<Vec<String>>,
+    dedup: Option<bool>,
+    dedup_perceptual: Option<bool>,
+    dedup_threshold: Option<u32>,
+    pad_width: Option<usize>,
+    index_format: Option<String>,
+    index_style: Option<IndexStyle>,
+}
+
+impl Config {
+    /// Load the first config file found in the current folder or in `$XDG_CONFIG_HOME/imgmv/`.
+    ///
+    /// # Returns
+    /// The path and contents of the config file, `None` if there is no config file, or an error
+    /// if the config file cannot be read or parsed.
+    pub fn load() -> Result<Option<(PathBuf, Config)>, ImgmvError> {
+        let config_home = std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+        let paths = std::iter::once(PathBuf::from(CONFIG_FILE_NAME))
+            .chain(config_home.map(|dir| dir.join("imgmv").join(CONFIG_FILE_NAME)));
+
+        for path in paths {
+            match fs::read_to_string(&path) {
+                Ok(text) => {
+                    let config = toml::from_str(&text).map_err(|e| ImgmvError::InvalidConfig {
+                        path: path.clone(),
+                        message: e.to_string(),
+                    })?;
+                    debug!("Loaded config from {:?}", path);
+                    return Ok(Some((path, config)));
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(ImgmvError::InvalidConfig {
+                        path,
+                        message: e.to_string(),
+                    });
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Apply the config to the command-line arguments, except for those given on the command line.
+    ///
+    /// # Arguments
+    /// * `path` - The path of the config file, used in error messages.
+    /// * `args` - The command-line arguments to update.
+    /// * `matches` - The matches the arguments were parsed from.
+    ///
+    /// # Returns
+    /// A Result indicating success, or an error if a config value is invalid.
+    pub fn apply(
+        self,
+        path: &Path,
+        args: &mut Args,
+        matches: &ArgMatches,
+    ) -> Result<(), ImgmvError> {
+        let invalid = |message: String| ImgmvError::InvalidConfig {
+            path: path.to_path_buf(),
+            message,
+        };
+        let globs = |patterns: Vec<String>| {
+            patterns
+                .iter()
+                .map(|pattern| globset::Glob::new(pattern).map_err(|e| invalid(e.to_string())))
+                .collect::<Result<Vec<_>, _>>()
+        };
+
+        macro_rules! merge {
+            ($field:ident) => {
+                merge!($field, |value| Ok::<_, ImgmvError>(value))
+            };
+            ($field:ident, $convert:expr) => {
+                if let Some(value) = self.$field
+                    && matches.value_source(stringify!($field)) != Some(ValueSource::CommandLine)
+                {
+                    args.$field = $convert(value)?;
+                }
+            };
+        }
+
+        merge!(copy);
+        merge!(prefix, |value| Ok::<_, ImgmvError>(Some(value)));
+        merge!(prefix_file, |value| Ok::<_, ImgmvError>(Some(value)));
+        merge!(prefix_from_ancestors, |value| if value == 0 {
+            Err(invalid(
+                "prefix-from-ancestors must be at least 1".to_string(),
+            ))
+        } else {
+            Ok(value)
+        });
+        merge!(ext_prefix, |mapping: std::collections::BTreeMap<
+            String,
+            String,
+        >| mapping
+            .into_iter()
+            .map(|(ext, prefix)| parse_ext_prefix(&format!("{}={}", ext, prefix)))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(invalid));
+        merge!(suffix, |value| Ok::<_, ImgmvError>(Some(value)));
+        merge!(force);
+        merge!(collision_strategy, |value| Ok::<_, ImgmvError>(Some(value)));
+        merge!(jobs, |value| Ok::<_, ImgmvError>(Some(value)));
+        merge!(confirm_above, |value| Ok::<_, ImgmvError>(Some(value)));
+        merge!(retries);
+        merge!(retry_delay);
+        merge!(verbose);
+        merge!(quiet);
+        merge!(color);
+        merge!(recursive);
+        merge!(max_depth, |value| Ok::<_, ImgmvError>(Some(value)));
+        merge!(preserve_structure);
+        merge!(hidden);
+        merge!(all);
+        merge!(allow_empty);
+        merge!(ext, |extensions: Vec<String>| extensions
+            .iter()
+            .map(|ext| parse_extension(ext).map_err(invalid))
+            .collect::<Result<Vec<_>, _>>());
+        merge!(sort);
+        merge!(reverse);
+        merge!(space_replacement, |value: char| parse_space_replacement(
+            &value.to_string()
+        )
+        .map(Some)
+        .map_err(invalid));
+        merge!(separator, |value: String| parse_separator(&value)
+            .map_err(invalid));
+        merge!(template, |value: String| value
+            .parse::<Template>()
+            .map_err(invalid));
+        merge!(keep_name);
+        merge!(lowercase_name);
+        merge!(lowercase_ext);
+        merge!(ext_case, |value| Ok::<_, ImgmvError>(Some(value)));
+        merge!(ext_rewrite, |mapping: std::collections::BTreeMap<
+            String,
+            String,
+        >| mapping
+            .into_iter()
+            .map(|(ext, new)| parse_ext_rewrite(&format!("{}={}", ext, new)))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(invalid));
+        merge!(include, globs);
+        merge!(exclude, globs);
+        merge!(dedup);
+        merge!(dedup_perceptual);
+        merge!(dedup_threshold);
+        merge!(pad_width, |value| Ok::<_, ImgmvError>(Some(value)));
+        merge!(index_format, |value: String| parse_index_format(&value)
+            .map(Some)
+            .map_err(invalid));
+        merge!(index_style);
+
+        // A flag given on the command line wins over the opposite one from the config file.
+        if args.verbose && args.quiet {
+            if matches.value_source("verbose") == Some(ValueSource::CommandLine) {
+                args.quiet = false;
+            } else if matches.value_source("quiet") == Some(ValueSource::CommandLine) {
+                args.verbose = false;
+            } else {
+                return Err(invalid(
+                    "verbose and quiet are mutually exclusive".to_string(),
+                ));
+            }
+        }
+        if args.force && args.collision_strategy.is_some() {
+            if matches.value_source("force") == Some(ValueSource::CommandLine) {
+                args.collision_strategy = None;
+            } else if matches.value_source("collision_strategy") == Some(ValueSource::CommandLine) {
+                args.force = false;
+            } else {
+                return Err(invalid(
+                    "force and collision-strategy are mutually exclusive".to_string(),
+                ));
+            }
+        }
+        if args.lowercase_ext && args.ext_case.is_some() {
+            if matches.value_source("lowercase_ext") == Some(ValueSource::CommandLine) {
+                args.ext_case = None;
+            } else if matches.value_source("ext_case") == Some(ValueSource::CommandLine) {
+                args.lowercase_ext = false;
+            } else {
+                return Err(invalid(
+                    "lowercase-ext and ext-case are mutually exclusive".to_string(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The errors that can stop `imgmv` from processing images.
+#[derive(Debug, thiserror::Error)]
+pub enum ImgmvError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error("Cannot determine prefix from source path. Supply a prefix using the --prefix option.")]
+    NoPrefix,
+
+    #[error(
+        "Cannot determine prefix from source folders with different names {0:?}. Supply a prefix using the --prefix option."
+    )]
+    AmbiguousPrefix(Vec<String>),
+
+    #[error(
+        "Cannot build a prefix from {count} folder names of {path:?}, which has fewer. Supply a prefix using the --prefix option."
+    )]
+    TooFewAncestors { path: PathBuf, count: usize },
+
+    #[error("Source folder {path:?} not found: {source}")]
+    SourceNotFound {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Destination folder {path:?} not found: {source}")]
+    DestinationNotFound {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Cannot write to destination {path:?}: {source}")]
+    DestinationNotWritable {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Invalid config file {path:?}: {message}")]
+    InvalidConfig { path: PathBuf, message: String },
+
+    #[error("Invalid undo log {path:?}: {source}")]
+    InvalidUndoLog {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+
+    #[error("Invalid mapping file {path:?}: {message}")]
+    InvalidMap { path: PathBuf, message: String },
+
+    #[error("{0} source files are not in the mapping file")]
+    Unmapped(usize),
+
+    #[error("Invalid ignore file {path:?}: {source}")]
+    InvalidIgnoreFile {
+        path: PathBuf,
+        source: globset::Error,
+    },
+
+    #[error("Failed to handle {failed} of {total} files")]
+    FilesFailed { failed: usize, total: usize },
+
+    #[error("{0} destination files already exist")]
+    Collisions(usize),
+
+    #[error("{0} destination paths are too long")]
+    PathsTooLong(usize),
+
+    #[error(
+        "Refusing to handle {count} files, more than {limit}, without confirmation. Use --yes to confirm."
+    )]
+    ConfirmationRequired { count: usize, limit: usize },
+
+    #[error(
+        "Not enough free space in {path:?}: {} needed, {} available",
+        format_size(*.required),
+        format_size(*.available)
+    )]
+    InsufficientSpace {
+        path: PathBuf,
+        required: u64,
+        available: u64,
+    },
+
+    #[error("Invalid marker file {path:?}: {source}")]
+    InvalidSinceFile {
+        path: PathBuf,
+        source: chrono::ParseError,
+    },
+
+    #[error("Cannot write marker file {path:?}: {source}")]
+    SinceFileNotWritable {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Cannot write log file {path:?}: {source}")]
+    LogFileNotWritable {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Rolled back {reverted} of {completed} file operations after {failed} files failed")]
+    RolledBack {
+        failed: usize,
+        reverted: usize,
+        completed: usize,
+    },
+
+    #[error("Cannot write report {path:?}: {source}")]
+    ReportNotWritable { path: PathBuf, source: csv::Error },
+
+    #[error(transparent)]
+    Glob(#[from] globset::Error),
+
+    #[error(transparent)]
+    Pattern(#[from] glob::PatternError),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    ThreadPool(#[from] rayon::ThreadPoolBuildError),
+
+    #[error("Cannot watch the source folders: {0}")]
+    Watch(#[from] notify::Error),
+
+    #[error("Cannot write zip archive {path:?}: {source}")]
+    ZipNotWritable {
+        path: PathBuf,
+        source: zip::result::ZipError,
+    },
+}
+
+impl ImgmvError {
+    /// Get the exit status to report the error with.
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            ImgmvError::Collisions(_) => ExitCode::from(COLLISIONS_EXIT_CODE),
+            _ => ExitCode::FAILURE,
+        }
+    }
+}
+
+/// When to color the output.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ColorMode {
+    /// Color the output if stdout is a terminal and `NO_COLOR` is not set.
+    Auto,
+    /// Always color the output.
+    Always,
+    /// Never color the output.
+    Never,
+}
+
+impl ColorMode {
+    /// Use this color mode for everything printed to stdout.
+    pub fn apply(self) {
+        match self {
+            ColorMode::Auto => anstream::ColorChoice::Auto,
+            ColorMode::Always => anstream::ColorChoice::Always,
+            ColorMode::Never => anstream::ColorChoice::Never,
+        }
+        .write_global();
+    }
+}
+
+/// The style of the lines for handled files, and of the summary if no files failed.
+pub const OK_STYLE: anstyle::Style = anstyle::AnsiColor::Green.on_default();
+
+/// The style of the summary if some files failed.
+pub const FAILED_STYLE: anstyle::Style = anstyle::AnsiColor::Red.on_default();
+
+/// Print a line for a handled file to stdout, colored if enabled by `--color`.
+///
+/// # Arguments
+/// * `op_text` - The description of the file action.
+fn print_ok(op_text: &str) {
+    anstream::println!("{OK_STYLE}{}{OK_STYLE:#}", op_text);
+}
+
+/// The format of the file actions printed to stdout.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum OutputFormat {
+    /// One line per file action, printed with --verbose.
+    Text,
+    /// A JSON array of the performed (or, in a dry run, planned) file actions.
+    Json,
+    /// The source and destination file names of the performed (or, in a dry run, planned) file
+    /// actions, in two aligned columns.
+    Table,
+}
+
+/// A file operation.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Copy,
+    Move,
+}
+
+impl Operation {
+    /// Get the name of the operation, as used in log messages.
+    fn name(self) -> &'static str {
+        match self {
+            Operation::Copy => "copy",
+            Operation::Move => "move",
+        }
+    }
+}
+
+/// A file operation from a source to a destination, as recorded in the undo log and printed with
+/// `--format json`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FileOperation {
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    pub operation: Operation,
+}
+
+/// A log of completed file operations that can be reverted with `--undo`.
+///
+/// Entries are written one JSON object per line as soon as each operation completes, so the log
+/// stays usable if the run is interrupted.
+struct UndoLog {
+    path: PathBuf,
+    append: bool,
+    file: std::sync::Mutex<Option<fs::File>>,
+}
+
+impl UndoLog {
+    /// Create an undo log, which is only written once it is opened.
+    ///
+    /// # Arguments
+    /// * `path` - The path to the undo log.
+    /// * `append` - Whether to add to an existing log at the same path, instead of truncating it.
+    fn new(path: PathBuf, append: bool) -> Self {
+        UndoLog {
+            path,
+            append,
+            file: std::sync::Mutex::new(None),
+        }
+    }
+
+    /// Lock the file of the undo log, which is `None` until the log is opened.
+    fn lock(&self) -> std::sync::MutexGuard<'_, Option<fs::File>> {
+        self.file
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Open the undo log, unless it is already open.
+    ///
+    /// # Returns
+    /// The length of the log before the operations of this run are recorded, or an error if it
+    /// cannot be opened.
+    fn open(&self) -> std::io::Result<u64> {
+        let mut file = self.lock();
+        if file.is_none() {
+            *file = Some(
+                fs::OpenOptions::new()
+                    .create(true)
+                    .write(true)
+                    .append(self.append)
+                    .truncate(!self.append)
+                    .open(&self.path)?,
+            );
+        }
+        Ok(file.as_ref().expect("the log is open").metadata()?.len())
+    }
+
+    /// Record a completed operation.
+    ///
+    /// # Arguments
+    /// * `entry` - The completed operation.
+    ///
+    /// # Returns
+    /// A Result indicating success or failure.
+    fn record(&self, entry: &FileOperation) -> std::io::Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        match self.lock().as_mut() {
+            Some(file) => file.write_all(line.as_bytes()),
+            None => Err(std::io::Error::other("the undo log is not open")),
+        }
+    }
+
+    /// Remove the operations recorded since the log had a length, and the log itself if no
+    /// operations are left.
+    ///
+    /// # Arguments
+    /// * `len` - The length of the log to keep, as returned by `open`.
+    ///
+    /// # Returns
+    /// A Result indicating success or failure.
+    fn discard_since(&self, len: u64) -> std::io::Result<()> {
+        let mut file = self.lock();
+        if len == 0 {
+            *file = None;
+            return fs::remove_file(&self.path);
+        }
+        file.as_ref().map_or(Ok(()), |file| file.set_len(len))
+    }
+}
+
+/// The number of files handled by each outcome, and the size of the moved and copied files.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Summary {
+    pub moved: usize,
+    pub copied: usize,
+    pub unchanged: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub bytes: u64,
+}
+
+impl Summary {
+    /// Get the total number of files.
+    pub fn total(&self) -> usize {
+        self.moved + self.copied + self.unchanged + self.skipped + self.failed
+    }
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} moved, {} copied, {} unchanged, {} skipped, {} failed ({})",
+            self.moved,
+            self.copied,
+            self.unchanged,
+            self.skipped,
+            self.failed,
+            format_size(self.bytes)
+        )
+    }
+}
+
+/// A part of a destination file name template.
+#[derive(Clone, Debug, PartialEq)]
+enum TemplatePart {
+    Literal(String),
+    Prefix,
+    Separator,
+    Index,
+    Ext,
+    Name,
+    Width,
+    Height,
+    Suffix,
+}
+
+/// The values of the placeholders in a destination file name template.
+struct TemplateValues<'a> {
+    /// The value of the `{prefix}` placeholder.
+    prefix: &'a str,
+    /// The value of the `{separator}` placeholder.
+    separator: &'a str,
+    /// The value of the `{index}` placeholder, already padded.
+    index: &'a str,
+    /// The value of the `{ext}` placeholder, including the leading dot if not empty.
+    ext: &'a str,
+    /// The value of the `{name}` placeholder.
+    name: &'a str,
+    /// The values of the `{width}` and `{height}` placeholders, which are empty if `None`.
+    dimensions: Option<(u32, u32)>,
+    /// The suffix rendered by the `{suffix}` placeholder.
+    suffix: Option<&'a str>,
+}
+
+/// A parsed destination file name template, such as `{prefix}_{index}{ext}`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Template(Vec<TemplatePart>);
+
+impl Template {
+    /// The template used with `--keep-name`:
+    /// `{prefix}{separator}{index}{separator}{name}{suffix}{ext}`.
+    fn keep_name() -> Self {
+        Template(vec![
+            TemplatePart::Prefix,
+            TemplatePart::Separator,
+            TemplatePart::Index,
+            TemplatePart::Separator,
+            TemplatePart::Name,
+            TemplatePart::Suffix,
+            TemplatePart::Ext,
+        ])
+    }
+
+    /// Check whether the template uses the image dimensions, which are costly to read.
+    fn uses_dimensions(&self) -> bool {
+        self.0
+            .iter()
+            .any(|part| matches!(part, TemplatePart::Width | TemplatePart::Height))
+    }
+
+    /// Check whether the template uses the suffix.
+    fn uses_suffix(&self) -> bool {
+        self.0.contains(&TemplatePart::Suffix)
+    }
+
+    /// Render the template into a file name.
+    ///
+    /// A `{separator}` directly after an empty `{prefix}` is left out, and `{suffix}` is rendered
+    /// as the separator followed by the suffix, or as nothing if there is no suffix.
+    ///
+    /// # Arguments
+    /// * `values` - The values of the placeholders.
+    ///
+    /// # Returns
+    /// The rendered file name.
+    fn render(&self, values: &TemplateValues) -> String {
+        let (width, height) = values
+            .dimensions
+            .map_or((String::new(), String::new()), |(w, h)| {
+                (w.to_string(), h.to_string())
+            });
+        let suffix = values.suffix.map_or(String::new(), |suffix| {
+            format!("{}{}", values.separator, suffix)
+        });
+        let mut previous = None;
+        let mut name = String::new();
+        for part in &self.0 {
+            name.push_str(match part {
+                TemplatePart::Literal(text) => text,
+                TemplatePart::Prefix => values.prefix,
+                TemplatePart::Separator
+                    if previous == Some(&TemplatePart::Prefix) && values.prefix.is_empty() =>
+                {
+                    ""
+                }
+                TemplatePart::Separator => values.separator,
+                TemplatePart::Index => values.index,
+                TemplatePart::Ext => values.ext,
+                TemplatePart::Name => values.name,
+                TemplatePart::Width => &width,
+                TemplatePart::Height => &height,
+                TemplatePart::Suffix => &suffix,
+            });
+            previous = Some(part);
+        }
+        name
+    }
+}
+
+impl std::str::FromStr for Template {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut rest = s;
+        // Literals must not lead out of the destination folder, as with `--separator`.
+        let literal = |text: &str| {
+            if text.chars().any(std::path::is_separator) {
+                Err(format!(
+                    "Template {:?} must not contain path separators outside placeholders",
+                    s
+                ))
+            } else {
+                Ok(TemplatePart::Literal(text.to_string()))
+            }
+        };
+
+        while let Some(open) = rest.find('{') {
+            if open > 0 {
+                parts.push(literal(&rest[..open])?);
+            }
+            let close = rest[open..]
+                .find('}')
+                .ok_or_else(|| format!("Unclosed placeholder in template: {:?}", s))?
+                + open;
+            parts.push(match &rest[open + 1..close] {
+                "prefix" => TemplatePart::Prefix,
+                "separator" => TemplatePart::Separator,
+                "index" => TemplatePart::Index,
+                "ext" => TemplatePart::Ext,
+                "name" => TemplatePart::Name,
+                "width" => TemplatePart::Width,
+                "height" => TemplatePart::Height,
+                "suffix" => TemplatePart::Suffix,
+                other => {
+                    return Err(format!(
+                        "Unknown placeholder {{{}}} in template. Supported placeholders are {{prefix}}, {{separator}}, {{index}}, {{suffix}}, {{ext}}, {{name}}, {{width}} and {{height}}.",
+                        other
+                    ));
+                }
+            });
+            rest = &rest[close + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(literal(rest)?);
+        }
+
+        Ok(Template(parts))
+    }
+}
+
+/// What will happen to a source file.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// The file is moved or copied to a new destination.
+    Create,
+    /// The file is moved or copied over an existing destination.
+    Overwrite,
+    /// The file is skipped since the destination exists.
+    Skip,
+    /// The file is left alone since it already has its destination name.
+    Unchanged,
+}
+
+impl Action {
+    /// Check whether the file is moved or copied.
+    pub fn is_handled(self) -> bool {
+        matches!(self, Action::Create | Action::Overwrite)
+    }
+}
+
+/// A planned file action from a source to a destination.
+#[derive(Debug, Serialize)]
+pub struct PlannedFile {
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    pub action: Action,
+    /// Whether the source is also the destination of another file, and must be renamed out of the
+    /// way before any file is moved.
+    #[serde(skip)]
+    pub stage: bool,
+}
+
+impl PlannedFile {
+    /// Describe the file action, as printed with `--verbose`.
+    ///
+    /// # Arguments
+    /// * `operation` - The operation applied to the file.
+    /// * `dry_run_prefix` - The prefix marking a dry run, or an empty string.
+    ///
+    /// # Returns
+    /// A description of the file action.
+    fn describe(&self, operation: Operation, dry_run_prefix: &str) -> String {
+        format!(
+            "{}{} {:?} -> {:?}{}",
+            dry_run_prefix,
+            operation.name(),
+            self.source,
+            self.destination,
+            if self.action == Action::Overwrite {
+                " (overwrite)"
+            } else {
+                ""
+            }
+        )
+    }
+
+    /// Get the file operation performed for this file, unless it is skipped.
+    fn file_operation(&self, operation: Operation) -> Option<FileOperation> {
+        self.action.is_handled().then(|| FileOperation {
+            source: self.source.clone(),
+            destination: self.destination.clone(),
+            operation,
+        })
+    }
+}
+
+/// The file actions of a run, computed before any file is touched.
+#[derive(Debug, Serialize)]
+pub struct Plan {
+    pub operation: Operation,
+    pub files: Vec<PlannedFile>,
+}
+
+/// Plan moving or copying images from the source paths to the destination path.
+///
+/// The source files are sorted, limited to `--max-files` and named. An existing destination file
+/// is handled according to the collision strategy.
+///
+/// When renaming in place, a source file may be the destination of another one. Such sources are
+/// renamed out of the way first, so they aren't overwritten before they have been handled.
+///
+/// # Arguments
+/// * `source_files` - The source files to handle.
+/// * `source_paths` - The paths to the source directories the files were found in.
+/// * `destination_path` - The path to the destination directory.
+/// * `prefix` - The prefix to be added to the destination file names.
+/// * `case_insensitive` - Whether destination names that only differ in case are the same file.
+/// * `args` - A reference to the command-line arguments controlling the operation.
+///
+/// # Returns
+/// A Result containing the plan, or an error if a destination exists and the collision strategy
+/// is `error`.
+pub fn build_plan(
+    source_files: Vec<PathBuf>,
+    source_paths: &[PathBuf],
+    destination_path: &Path,
+    prefix: &str,
+    case_insensitive: bool,
+    args: &Args,
+) -> Result<Plan, ImgmvError> {
+    let source_files = select_source_files(source_files, prefix, args)?;
+    let operation = if args.copy {
+        Operation::Copy
+    } else {
+        Operation::Move
+    };
+    let mut pairs = match &args.map {
+        Some(map_path) => mapped_pairs(
+            source_files,
+            map_path,
+            source_paths,
+            destination_path,
+            case_insensitive,
+            args,
+        )?,
+        None => generate_source_destination_pairs(
+            source_files,
+            source_paths,
+            destination_path,
+            prefix,
+            case_insensitive,
+            args,
+        ),
+    };
+    if let Some(zip_path) = &args.zip {
+        for (_, destination_file) in &mut pairs {
+            *destination_file = zip_path.join(
+                destination_file
+                    .strip_prefix(destination_path)
+                    .unwrap_or(destination_file),
+            );
+        }
+    }
+
+    // Moving or copying a file onto itself could truncate it, so such files are left alone, as are
+    // files that would only change case with --skip-unchanged.
+    let own_destinations: HashSet<&PathBuf> = pairs
+        .iter()
+        .filter(|(source_file, destination_file)| is_same_file(source_file, destination_file))
+        .map(|(source_file, _)| source_file)
+        .collect();
+    let case_changes: HashSet<&PathBuf> = pairs
+        .iter()
+        .filter(|(source_file, destination_file)| {
+            args.skip_unchanged
+                && !own_destinations.contains(source_file)
+                && is_case_change(source_file, destination_file)
+        })
+        .map(|(source_file, _)| source_file)
+        .collect();
+    let mut in_place: HashSet<&PathBuf> = HashSet::new();
+    if operation == Operation::Move && args.zip.is_none() {
+        let sources: HashSet<&PathBuf> = pairs
+            .iter()
+            .map(|(source_file, _)| source_file)
+            .filter(|source_file| {
+                !own_destinations.contains(source_file) && !case_changes.contains(source_file)
+            })
+            .collect();
+        in_place = pairs
+            .iter()
+            .map(|(_, destination_file)| destination_file)
+            .filter(|destination_file| sources.contains(destination_file))
+            .collect();
+    }
+    let strategy = args.collision_strategy();
+    let is_collision = |in_place: &HashSet<&PathBuf>, destination_file: &PathBuf| {
+        args.zip.is_none() && destination_file.exists() && !in_place.contains(destination_file)
+    };
+    let is_skipped = |in_place: &HashSet<&PathBuf>, destination_file: &PathBuf| {
+        strategy == CollisionStrategy::Skip && is_collision(in_place, destination_file)
+    };
+    // A skipped source stays where it is, so files planned to move there must be skipped too.
+    loop {
+        let skipped: Vec<&PathBuf> = pairs
+            .iter()
+            .filter(|(source_file, destination_file)| {
+                in_place.contains(source_file) && is_skipped(&in_place, destination_file)
+            })
+            .map(|(source_file, _)| source_file)
+            .collect();
+        if skipped.is_empty() {
+            break;
+        }
+        for source_file in skipped {
+            in_place.remove(source_file);
+        }
+    }
+
+    if strategy == CollisionStrategy::Error {
+        let collisions: Vec<_> = pairs
+            .iter()
+            .filter(|(_, destination_file)| is_collision(&in_place, destination_file))
+            .collect();
+        if !collisions.is_empty() {
+            for (source_file, destination_file) in &collisions {
+                error!(
+                    "Cannot {} {:?} -> {:?}: destination already exists",
+                    operation.name(),
+                    source_file,
+                    destination_file
+                );
+            }
+            return Err(ImgmvError::Collisions(collisions.len()));
+        }
+    }
+
+    // With --hash-name, files with the same contents share a destination, of which only the
+    // first is handled.
+    let mut destinations: HashMap<&PathBuf, &PathBuf> = HashMap::new();
+    let same_contents: HashMap<&PathBuf, &PathBuf> = pairs
+        .iter()
+        .filter(|_| args.hash_name)
+        .filter_map(|(source_file, destination_file)| {
+            let first = *destinations.entry(destination_file).or_insert(source_file);
+            (first != source_file).then_some((source_file, first))
+        })
+        .collect();
+
+    let dry_run_prefix = if args.dry_run { "[dry-run] " } else { "" };
+    let mut used_destinations = UsedDestinations::new(case_insensitive);
+    for (_, destination_file) in &pairs {
+        used_destinations.insert(destination_file);
+    }
+    let files = pairs
+        .iter()
+        .map(|(source_file, destination_file)| {
+            let mut destination_file = destination_file.clone();
+            let action = if own_destinations.contains(source_file) {
+                warn!(
+                    "{}Skipping {:?}: it is its own destination {:?}",
+                    dry_run_prefix, source_file, destination_file
+                );
+                Action::Unchanged
+            } else if case_changes.contains(source_file) {
+                info!(
+                    "{}Leaving {:?} unchanged: its destination is {:?}",
+                    dry_run_prefix, source_file, destination_file
+                );
+                Action::Unchanged
+            } else if let Some(first) = same_contents.get(source_file) {
+                info!(
+                    "{}Skipping {:?}: it has the same contents as {:?}",
+                    dry_run_prefix, source_file, first
+                );
+                Action::Skip
+            } else if !is_collision(&in_place, &destination_file) {
+                Action::Create
+            } else {
+                match strategy {
+                    CollisionStrategy::Overwrite => Action::Overwrite,
+                    CollisionStrategy::Rename => {
+                        let renamed = counted_name(&destination_file, |candidate| {
+                            used_destinations.contains(candidate) || candidate.exists()
+                        });
+                        info!(
+                            "{}Renaming {:?} to {:?}: destination already exists",
+                            dry_run_prefix, destination_file, renamed
+                        );
+                        used_destinations.insert(&renamed);
+                        destination_file = renamed;
+                        Action::Create
+                    }
+                    CollisionStrategy::Skip | CollisionStrategy::Error => {
+                        warn!(
+                            "{}Skipping {:?} -> {:?}: destination already exists. Use --force to overwrite.",
+                            dry_run_prefix, source_file, destination_file
+                        );
+                        Action::Skip
+                    }
+                }
+            };
+            PlannedFile {
+                source: source_file.clone(),
+                destination: destination_file,
+                action,
+                stage: in_place.contains(source_file),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // Zip entry names are not file system paths, so only real destinations are checked.
+    if args.zip.is_none() {
+        let too_long: Vec<_> = files
+            .iter()
+            .filter(|file| file.action.is_handled())
+            .filter_map(|file| Some((file, path_length_error(&file.destination)?)))
+            .collect();
+        if !too_long.is_empty() {
+            for (file, reason) in &too_long {
+                error!(
+                    "Cannot {} {:?} -> {:?}: {}",
+                    operation.name(),
+                    file.source,
+                    file.destination,
+                    reason
+                );
+            }
+            return Err(ImgmvError::PathsTooLong(too_long.len()));
+        }
+    }
+
+    Ok(Plan { operation, files })
+}
+
+/// Select the source files to handle, in the order they are numbered.
+///
+/// Already renamed files are skipped with `--skip-renamed`, the files are sorted, duplicates are
+/// removed with `--dedup`, and the files are limited to `--max-files`.
+///
+/// # Arguments
+/// * `source_files` - The source files found in the source paths.
+/// * `prefix` - The prefix to be added to the destination file names.
+/// * `args` - A reference to the command-line arguments.
+///
+/// # Returns
+/// A Result containing the selected source files, or an error if the duplicates cannot be found.
+fn select_source_files(
+    mut source_files: Vec<PathBuf>,
+    prefix: &str,
+    args: &Args,
+) -> Result<Vec<PathBuf>, ImgmvError> {
+    if args.skip_renamed {
+        source_files.retain(|source_file| {
+            let renamed =
+                numbered_index(source_file, &file_prefix(source_file, prefix, args), args)
+                    .is_some();
+            if renamed {
+                info!("Skipping already renamed file {:?}", source_file);
+            }
+            !renamed
+        });
+    }
+    sort_source_files(&mut source_files, args);
+    let mut source_files = remove_duplicates(source_files, args)?;
+    if let Some(max_files) = args.max_files
+        && source_files.len() > max_files
+    {
+        info!(
+            "Skipping {} files over the limit of {} files",
+            source_files.len() - max_files,
+            max_files
+        );
+        source_files.truncate(max_files);
+    }
+    Ok(source_files)
+}
+
+/// Pair source files with the destination names given for their file names in a mapping file.
+///
+/// The names are put in the folder of each file, as with `--preserve-structure`, `--by-camera` and
+/// `--by-date`.
+///
+/// # Arguments
+/// * `source_files` - The source file paths.
+/// * `map_path` - The path to the CSV mapping file.
+/// * `source_paths` - The source directory paths.
+/// * `destination_path` - The destination directory path.
+/// * `case_insensitive` - Whether destination names that only differ in case are the same file.
+/// * `args` - A reference to the command-line arguments.
+///
+/// # Returns
+/// The source files in the mapping with their destination file paths, `ImgmvError::InvalidMap`
+/// if the mapping cannot be read, or with `--map-strict`, `ImgmvError::Unmapped` if some source
+/// files are not in the mapping.
+fn mapped_pairs(
+    source_files: Vec<PathBuf>,
+    map_path: &Path,
+    source_paths: &[PathBuf],
+    destination_path: &Path,
+    case_insensitive: bool,
+    args: &Args,
+) -> Result<Vec<(PathBuf, PathBuf)>, ImgmvError> {
+    let mapping = read_map(map_path)?;
+    let mut used_destinations = UsedDestinations::new(case_insensitive);
+    let mut unmapped = 0;
+    let pairs = source_files
+        .into_iter()
+        .filter_map(|source_file| {
+            let name = source_file.file_name()?.to_string_lossy();
+            let Some(new_name) = mapping.get(name.as_ref()) else {
+                if args.map_strict {
+                    error!("{:?} is not in the mapping {:?}", source_file, map_path);
+                } else {
+                    info!(
+                        "Skipping {:?}: it is not in the mapping {:?}",
+                        source_file, map_path
+                    );
+                }
+                unmapped += 1;
+                return None;
+            };
+            let folder = destination_folder(&source_file, source_paths, destination_path, args);
+            let destination_file = disambiguate(folder.join(new_name), &mut used_destinations);
+            Some((source_file, destination_file))
+        })
+        .collect();
+    if args.map_strict && unmapped > 0 {
+        return Err(ImgmvError::Unmapped(unmapped));
+    }
+    Ok(pairs)
+}
+
+/// Read a CSV mapping file of original file names and their new names.
+///
+/// Each line has two columns, the original name and the new name, optionally under an
+/// `original,new` header. New names may contain subfolders, but must stay in the destination.
+///
+/// # Arguments
+/// * `path` - The path to the mapping file.
+///
+/// # Returns
+/// The new names by original name, or `ImgmvError::InvalidMap` if the file cannot be read or a
+/// line is invalid.
+fn read_map(path: &Path) -> Result<HashMap<String, PathBuf>, ImgmvError> {
+    let invalid = |message: String| ImgmvError::InvalidMap {
+        path: path.to_path_buf(),
+        message,
+    };
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .trim(csv::Trim::All)
+        .from_path(path)
+        .map_err(|e| invalid(e.to_string()))?;
+    let mut mapping = HashMap::new();
+    for (i, record) in reader.records().enumerate() {
+        let record = record.map_err(|e| invalid(e.to_string()))?;
+        let (Some(original), Some(new), 2) = (record.get(0), record.get(1), record.len()) else {
+            return Err(invalid(format!(
+                "line {} must have the two columns original and new",
+                i + 1
+            )));
+        };
+        if i == 0 && original == "original" && new == "new" {
+            continue;
+        }
+        let new_path = PathBuf::from(new);
+        if new.is_empty()
+            || !new_path
+                .components()
+                .all(|component| matches!(component, std::path::Component::Normal(_)))
+        {
+            return Err(invalid(format!(
+                "line {} has the new name {:?}, which is not a relative path in the destination",
+                i + 1,
+                new
+            )));
+        }
+        if mapping.insert(original.to_string(), new_path).is_some() {
+            return Err(invalid(format!("line {} maps {:?} again", i + 1, original)));
+        }
+    }
+    Ok(mapping)
+}
+
+/// Asks the user to confirm file operations, such as with `--interactive`.
+pub trait Prompt: Sync {
+    /// Check whether the user can be asked, which is not the case when running from a script.
+    fn is_interactive(&self) -> bool;
+
+    /// Ask the user a yes/no question.
+    ///
+    /// # Returns
+    /// `true` if the answer is yes, `false` otherwise, or an error if the answer cannot be read.
+    fn confirm(&self, question: &str) -> std::io::Result<bool>;
+}
+
+/// Asks questions on stderr and reads the answers from stdin, if stdin is a terminal.
+pub struct TerminalPrompt;
+
+impl Prompt for TerminalPrompt {
+    fn is_interactive(&self) -> bool {
+        std::io::stdin().is_terminal()
+    }
+
+    fn confirm(&self, question: &str) -> std::io::Result<bool> {
+        confirm(question, &mut std::io::stdin().lock())
+    }
+}
+
+/// A function that moves or copies a source file to a destination file whose folder exists.
+pub type FileOperationFn<'a> = dyn Fn(Operation, &Path, &Path) -> std::io::Result<()> + Sync + 'a;
+
+/// An observer of the progress of moving or copying files, such as a progress bar in a GUI.
+///
+/// The file events may come from several threads at once. All methods do nothing by default, and
+/// `()` is a sink that ignores all events.
+pub trait ProgressSink: Sync {
+    /// Called before the first file is handled, with the number of files to be handled.
+    fn on_start(&self, _total: usize) {}
+
+    /// Called when a file has been moved or copied, or would have been in a dry run.
+    fn on_file_done(&self, _file_operation: &FileOperation) {}
+
+    /// Called when a file fails, with the error message.
+    fn on_error(&self, _source: &Path, _message: &str) {}
+
+    /// Called after the last file, with the summary of all files.
+    fn on_finish(&self, _summary: &Summary) {}
+}
+
+impl ProgressSink for () {}
+
+/// A progress bar on stdout, shown according to `--progress` and `--no-progress`.
+pub struct TerminalProgress(ProgressBar);
+
+impl TerminalProgress {
+    /// Create a progress bar for the command-line arguments, which is hidden unless it should be
+    /// shown.
+    pub fn new(args: &Args) -> Self {
+        TerminalProgress(progress_bar(0, args))
+    }
+}
+
+impl ProgressSink for TerminalProgress {
+    fn on_start(&self, total: usize) {
+        self.0.set_length(total as u64);
+        self.0.reset();
+    }
+
+    fn on_file_done(&self, _file_operation: &FileOperation) {
+        self.0.inc(1);
+    }
+
+    fn on_error(&self, _source: &Path, _message: &str) {
+        self.0.inc(1);
+    }
+
+    fn on_finish(&self, _summary: &Summary) {
+        self.0.finish_and_clear();
+    }
+}
+
+/// Report the result of handling a planned file to a progress sink.
+///
+/// # Arguments
+/// * `progress` - The sink to report to.
+/// * `file` - The planned file.
+/// * `result` - The result of handling the file, which is `None` if it was skipped.
+///
+/// # Returns
+/// The result, unchanged.
+fn report_progress(
+    progress: &dyn ProgressSink,
+    file: &PlannedFile,
+    result: FileResult,
+) -> FileResult {
+    match &result {
+        Ok(Some(file_operation)) => progress.on_file_done(file_operation),
+        Ok(None) => {}
+        Err(message) => progress.on_error(&file.source, message),
+    }
+    result
+}
+
+/// Create the function that moves or copies the files on disk.
+///
+/// # Arguments
+/// * `args` - A reference to the command-line arguments controlling the operation.
+///
+/// # Returns
+/// The file operation function. With `--hardlink-dupes`, it remembers the files it has copied,
+/// and with `--throttle`, it limits the rate of all copies together.
+pub fn disk_file_operation(
+    args: &Args,
+) -> impl Fn(Operation, &Path, &Path) -> std::io::Result<()> + Sync {
+    let hardlink_dupes = args.hardlink_dupes;
+    let first_copies = std::sync::Mutex::new(HashMap::new());
+    let throttle = args.throttle.map(Throttle::new);
+    move |operation, source, destination| match operation {
+        Operation::Copy if hardlink_dupes => {
+            copy_or_link(source, destination, &first_copies, throttle.as_ref())
+        }
+        Operation::Copy => copy_preserving_times(source, destination, throttle.as_ref()),
+        Operation::Move => move_file(source, destination),
+    }
+}
+
+/// A token bucket limiting the rate of copying, shared by the parallel copies.
+struct Throttle {
+    /// The allowed number of bytes per second, which is also the size of the bucket.
+    rate: u64,
+    /// The time the bucket was last filled, and the bytes left in it, which may be negative.
+    bucket: std::sync::Mutex<(std::time::Instant, f64)>,
+}
+
+impl Throttle {
+    /// Create a full bucket.
+    fn new(rate: u64) -> Self {
+        Throttle {
+            rate,
+            bucket: std::sync::Mutex::new((std::time::Instant::now(), rate as f64)),
+        }
+    }
+
+    /// Take bytes from the bucket, waiting until the rate allows them.
+    fn take(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+            let (filled, tokens) = &mut *bucket;
+            let now = std::time::Instant::now();
+            *tokens = (*tokens + now.duration_since(*filled).as_secs_f64() * self.rate as f64)
+                .min(self.rate as f64)
+                - bytes as f64;
+            *filled = now;
+            (*tokens < 0.0).then(|| std::time::Duration::from_secs_f64(-*tokens / self.rate as f64))
+        };
+        if let Some(wait) = wait {
+            std::thread::sleep(wait);
+        }
+    }
+}
+
+/// Move or copy images from the source paths to the destination path with a specified prefix.
+///
+/// In a dry run, the plan is printed instead of executed.
+///
+/// # Arguments
+/// * `source_paths` - The paths to the source directories.
+/// * `destination_path` - The path to the destination directory.
+/// * `prefix` - The prefix to be added to the destination file names.
+/// * `args` - A reference to the command-line arguments controlling the operation.
+/// * `file_operation` - The function that moves or copies each file.
+/// * `progress` - The sink to report the progress of the file operations to.
+/// * `prompt` - The prompt to confirm the file operations with.
+///
+/// # Returns
+/// A Result containing a summary of the handled files, or an error.
+pub fn move_images(
+    source_paths: Vec<PathBuf>,
+    destination_path: PathBuf,
+    prefix: &str,
+    args: &Args,
+    file_operation: &FileOperationFn,
+    progress: &dyn ProgressSink,
+    prompt: &dyn Prompt,
+) -> Result<Summary, ImgmvError> {
+    let started = std::time::SystemTime::now();
+    let copy_file = args.copy;
+    let verbose = args.verbose;
+    let dry_run = args.dry_run;
+
+    debug!("Source paths: {:?}", source_paths);
+    debug!("Destination path: {:?}", destination_path);
+    debug!("Copy file: {}", copy_file);
+    debug!("Verify: {}", args.verify);
+    debug!("Hardlink dupes: {}", args.hardlink_dupes);
+    debug!("Prefix: {}", prefix);
+    debug!("Extension prefixes: {:?}", args.ext_prefix);
+    debug!("Suffix: {:?}", args.suffix);
+    debug!("Collision strategy: {:?}", args.collision_strategy());
+    debug!("Trash: {}", args.trash);
+    debug!("Verbose: {}", verbose);
+    debug!("Dry run: {}", dry_run);
+    debug!("Check space: {}", args.check_space);
+    debug!("Strict: {}", args.strict);
+    debug!("Atomic: {}", args.atomic);
+    debug!("Zip: {:?}", args.zip);
+    debug!("Interactive: {}", args.interactive);
+    debug!("Confirm above: {:?}", args.confirm_above);
+    debug!("Yes: {}", args.yes);
+    debug!("Watch: {}", args.watch);
+    debug!("Recursive: {}", args.recursive);
+    debug!("Max depth: {:?}", args.max_depth);
+    debug!("Preserve structure: {}", args.preserve_structure);
+    debug!("Follow symlinks: {}", args.follow_symlinks);
+    debug!("Stdin: {}", args.stdin);
+    debug!("Hidden: {}", args.hidden);
+    debug!("All files: {}", args.all);
+    debug!("Allow empty: {}", args.allow_empty);
+    debug!("Extensions: {:?}", args.ext);
+    debug!("Include: {:?}", args.include);
+    debug!("Exclude: {:?}", args.exclude);
+    debug!("Min size: {:?}", args.min_size);
+    debug!("Max size: {:?}", args.max_size);
+    debug!("After: {:?}", args.after);
+    debug!("Before: {:?}", args.before);
+    debug!("Min age: {:?}", args.min_age);
+    debug!("Since file: {:?}", args.since_file);
+    debug!("Dedup: {}", args.dedup);
+    debug!("Dedup perceptual: {}", args.dedup_perceptual);
+    debug!("Dedup threshold: {}", args.dedup_threshold);
+    debug!("Sort: {:?}", args.sort);
+    debug!("Reverse: {}", args.reverse);
+    debug!("Space replacement: {:?}", args.space_replacement);
+    debug!("Separator: {:?}", args.separator);
+    debug!("Template: {:?}", args.template);
+    debug!("Keep name: {}", args.keep_name);
+    debug!("No rename: {}", args.no_rename);
+    debug!("Hash name: {}", args.hash_name);
+    debug!("Map: {:?}", args.map);
+    debug!("Map strict: {}", args.map_strict);
+    debug!("Hash algorithm: {:?}", args.hash_algo);
+    debug!("Hash length: {}", args.hash_length);
+    debug!("Lowercase names: {}", args.lowercase_name);
+    debug!("Extension case: {:?}", args.ext_case());
+    debug!("Extension rewrites: {:?}", args.ext_rewrite);
+    debug!("Sniff extensions: {}", args.sniff_ext);
+    debug!("Batch size: {:?}", args.batch_size);
+    debug!("Global index: {}", args.global_index);
+    debug!("By camera: {}", args.by_camera);
+    debug!("By date: {:?}", args.by_date);
+    debug!("Date prefix: {:?}", args.date_prefix);
+    debug!("Mtime prefix: {:?}", args.mtime_prefix);
+    debug!("Start: {}", args.start);
+    debug!("Max files: {:?}", args.max_files);
+    debug!("Continue: {}", args.continue_numbering);
+    debug!("Skip renamed: {}", args.skip_renamed);
+    debug!("Skip unchanged: {}", args.skip_unchanged);
+    debug!("Pad width: {:?}", args.pad_width);
+    debug!("Index format width: {:?}", args.index_format);
+    debug!("Index style: {:?}", args.index_style);
+    debug!("Jobs: {:?}", args.jobs);
+    debug!("Retries: {}", args.retries);
+    debug!("Retry delay: {} ms", args.retry_delay);
+    debug!("Throttle: {:?}", args.throttle);
+
+    let source_files = get_source_files(&source_paths, args)?;
+    let undo_log = UndoLog::new(destination_path.join(UNDO_LOG_NAME), false);
+    let summary = move_source_files(
+        source_files,
+        &source_paths,
+        &destination_path,
+        prefix,
+        args,
+        file_operation,
+        progress,
+        prompt,
+        &undo_log,
+    )?;
+    update_since_file(args, started, &summary)?;
+    Ok(summary)
+}
+
+/// List the source files in the source paths that would be handled, in the order they are
+/// numbered.
+///
+/// No destinations are computed. The prefix is only needed, and computed, with `--skip-renamed`.
+///
+/// # Arguments
+/// * `source_paths` - The paths to the source directories.
+/// * `args` - A reference to the command-line arguments.
+///
+/// # Returns
+/// The selected source file paths, as selected by `build_plan`.
+pub fn list_source_files(
+    source_paths: &[PathBuf],
+    args: &Args,
+) -> Result<Vec<PathBuf>, ImgmvError> {
+    let prefix = if args.skip_renamed {
+        get_prefix(args)?
+    } else {
+        String::new()
+    };
+    select_source_files(get_source_files(source_paths, args)?, &prefix, args)
+}
+
+/// Watch the source paths, and move or copy new images to the destination path as they appear.
+///
+/// New files are numbered after the files already in the destination folder. A file is only
+/// handled once its size has stopped changing, so that files still being written are left alone.
+/// Files in the destination folder are never handled, so that renamed files aren't renamed again.
+///
+/// # Arguments
+/// * `source_paths` - The paths to the source directories.
+/// * `destination_path` - The path to the destination directory.
+/// * `prefix` - The prefix to be added to the destination file names.
+/// * `args` - A reference to the command-line arguments controlling the operation.
+/// * `file_operation` - The function that moves or copies each file.
+/// * `progress` - The sink to report the progress of the file operations to.
+/// * `prompt` - The prompt to confirm the file operations of each batch with.
+/// * `on_summary` - Called with the summary of each batch of handled files, returning whether to
+///   keep watching.
+///
+/// # Returns
+/// A Result that is `Ok` once `on_summary` breaks or the file system events stop, or an error if
+/// the source folders cannot be watched.
+#[allow(clippy::too_many_arguments)]
+pub fn watch_images(
+    source_paths: &[PathBuf],
+    destination_path: &Path,
+    prefix: &str,
+    args: &Args,
+    file_operation: &FileOperationFn,
+    progress: &dyn ProgressSink,
+    prompt: &dyn Prompt,
+    mut on_summary: impl FnMut(&Summary) -> std::ops::ControlFlow<()>,
+) -> Result<(), ImgmvError> {
+    let args = &Args {
+        continue_numbering: true,
+        ..args.clone()
+    };
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    let mode = if args.recursive {
+        notify::RecursiveMode::Recursive
+    } else {
+        notify::RecursiveMode::NonRecursive
+    };
+    for source_path in source_paths {
+        let folder = if is_glob(source_path) {
+            glob_base(source_path)
+        } else {
+            source_path.clone()
+        };
+        if folder.starts_with(destination_path) {
+            warn!(
+                "New files in {:?} are not handled, since it is in the destination folder",
+                folder
+            );
+        }
+        watcher.watch(&folder, mode)?;
+        info!("Watching {:?} for new files", folder);
+    }
+
+    // All batches are recorded in the same undo log, after the operations of the initial pass.
+    let undo_log = UndoLog::new(destination_path.join(UNDO_LOG_NAME), true);
+    // The size of each new file at the last check, or `None` if it hasn't been checked yet.
+    let mut pending: HashMap<PathBuf, Option<u64>> = HashMap::new();
+    let mut next_check = std::time::Instant::now() + WATCH_INTERVAL;
+    loop {
+        let timeout = next_check.saturating_duration_since(std::time::Instant::now());
+        match receiver.recv_timeout(timeout) {
+            Ok(Ok(event)) => {
+                if matches!(
+                    event.kind,
+                    notify::EventKind::Create(_) | notify::EventKind::Modify(_)
+                ) {
+                    for path in event.paths {
+                        if !path.starts_with(destination_path) {
+                            pending.insert(path, None);
+                        }
+                    }
+                }
+                continue;
+            }
+            Ok(Err(e)) => {
+                warn!("Failed to watch for new files: {}", e);
+                continue;
+            }
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {}
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+        next_check = std::time::Instant::now() + WATCH_INTERVAL;
+
+        let mut settled = HashSet::new();
+        pending.retain(|path, last_size| match fs::metadata(path) {
+            Ok(metadata) if metadata.is_file() => {
+                if *last_size == Some(metadata.len()) {
+                    settled.insert(path.clone());
+                    false
+                } else {
+                    *last_size = Some(metadata.len());
+                    true
+                }
+            }
+            // Folders, and files that are gone again, are not handled.
+            _ => false,
+        });
+        if settled.is_empty() {
+            continue;
+        }
+
+        // The source folders are scanned again, so that new files are selected like the others.
+        let started = std::time::SystemTime::now();
+        let source_files: Vec<PathBuf> = get_source_files(source_paths, args)?
+            .into_iter()
+            .filter(|source_file| settled.contains(source_file))
+            .collect();
+        if source_files.is_empty() {
+            continue;
+        }
+        info!("Handling {} new files", source_files.len());
+        match move_source_files(
+            source_files,
+            source_paths,
+            destination_path,
+            prefix,
+            args,
+            file_operation,
+            progress,
+            prompt,
+            &undo_log,
+        ) {
+            Ok(summary) => {
+                update_since_file(args, started, &summary)?;
+                if on_summary(&summary).is_break() {
+                    return Ok(());
+                }
+            }
+            Err(e) => error!("{}", e),
+        }
+    }
+}
+
+/// Store the start of a run in the `--since-file` marker, unless some files failed.
+///
+/// # Arguments
+/// * `args` - A reference to the command-line arguments.
+/// * `started` - When the source files were selected.
+/// * `summary` - The summary of the handled files.
+///
+/// # Returns
+/// A Result indicating success, or an error if the marker file cannot be written.
+fn update_since_file(
+    args: &Args,
+    started: std::time::SystemTime,
+    summary: &Summary,
+) -> Result<(), ImgmvError> {
+    let Some(since_path) = &args.since_file else {
+        return Ok(());
+    };
+    if args.dry_run {
+        return Ok(());
+    }
+    if summary.failed == 0 {
+        write_since_file(since_path, started)?;
+    } else {
+        warn!(
+            "Marker file {:?} not updated, so the failed files are retried in the next run",
+            since_path
+        );
+    }
+    Ok(())
+}
+
+/// Move or copy source files to the destination path with a specified prefix.
+///
+/// # Arguments
+/// * `source_files` - The source files to handle.
+/// * `source_paths` - The paths to the source directories the files were found in.
+/// * `destination_path` - The path to the destination directory.
+/// * `prefix` - The prefix to be added to the destination file names.
+/// * `args` - A reference to the command-line arguments controlling the operation.
+/// * `file_operation` - The function that moves or copies each file.
+/// * `progress` - The sink to report the progress of the file operations to.
+/// * `prompt` - The prompt to confirm the file operations with.
+/// * `undo_log` - The undo log to record the completed file operations in.
+///
+/// # Returns
+/// A Result containing a summary of the handled files, or an error.
+#[allow(clippy::too_many_arguments)]
+fn move_source_files(
+    source_files: Vec<PathBuf>,
+    source_paths: &[PathBuf],
+    destination_path: &Path,
+    prefix: &str,
+    args: &Args,
+    file_operation: &FileOperationFn,
+    progress: &dyn ProgressSink,
+    prompt: &dyn Prompt,
+    undo_log: &UndoLog,
+) -> Result<Summary, ImgmvError> {
+    let dry_run = args.dry_run;
+    let verbose = args.verbose;
+    // Dry runs and archives write nothing to the destination folder, so it is not probed.
+    let case_insensitive = if dry_run || args.zip.is_some() {
+        cfg!(any(windows, target_os = "macos"))
+    } else {
+        is_case_insensitive(destination_path)
+    };
+    if case_insensitive {
+        debug!(
+            "Comparing destination names ignoring case in {:?}",
+            destination_path
+        );
+    }
+    let plan = build_plan(
+        source_files,
+        source_paths,
+        destination_path,
+        prefix,
+        case_insensitive,
+        args,
+    )?;
+    if args.plan {
+        println!("{}", serde_json::to_string_pretty(&plan)?);
+        return Ok(Summary::default());
+    }
+    if args.tree && args.format == OutputFormat::Text {
+        print!(
+            "{}",
+            render_tree(args.zip.as_deref().unwrap_or(destination_path), &plan.files)
+        );
+    }
+    if dry_run && !args.quiet && args.format == OutputFormat::Text {
+        print!("{}", render_collisions(&plan));
+    }
+    if args.check {
+        let collisions = plan
+            .files
+            .iter()
+            .filter(|file| matches!(file.action, Action::Overwrite | Action::Skip))
+            .count();
+        if collisions > 0 {
+            return Err(ImgmvError::Collisions(collisions));
+        }
+        return Ok(Summary::default());
+    }
+
+    let operation = plan.operation;
+    let name = operation.name();
+    let undo_log_path = &undo_log.path;
+    let mut undo_log_start = 0;
+    // The sources are measured up front, since moved files may be gone afterwards.
+    let sizes: Vec<u64> = plan
+        .files
+        .iter()
+        .map(|file| {
+            if !file.action.is_handled() {
+                return 0;
+            }
+            fs::metadata(&file.source).map_or_else(
+                |e| {
+                    warn!("Failed to get size of {:?}: {}", file.source, e);
+                    0
+                },
+                |metadata| metadata.len(),
+            )
+        })
+        .collect();
+    if dry_run || args.check_space {
+        check_space(&plan, &sizes, destination_path, args, |folder| {
+            fs2::available_space(folder)
+        })?;
+    }
+    let planned = plan
+        .files
+        .iter()
+        .filter(|file| file.action.is_handled())
+        .count();
+    // When the user declines, the planned files are skipped and the other files keep their action.
+    let aborted = || {
+        info!("Aborted by user");
+        let unchanged = plan
+            .files
+            .iter()
+            .filter(|file| file.action == Action::Unchanged)
+            .count();
+        Summary {
+            unchanged,
+            skipped: plan.files.len() - unchanged,
+            ..Summary::default()
+        }
+    };
+    let mut renamed = Vec::new();
+    let results = if dry_run {
+        progress.on_start(planned);
+        plan.files
+            .iter()
+            .map(|file| {
+                if file.action == Action::Overwrite && args.trash {
+                    let trash_text = format!("[dry-run] trash {:?}", file.destination);
+                    if verbose && args.format == OutputFormat::Text {
+                        println!("{}", trash_text);
+                    } else {
+                        debug!("{}", trash_text);
+                    }
+                }
+                let file_operation = file.file_operation(operation);
+                if let Some(file_operation) = &file_operation {
+                    progress.on_file_done(file_operation);
+                    let op_text = file.describe(operation, "[dry-run] ");
+                    if verbose && args.format == OutputFormat::Text {
+                        print_ok(&op_text);
+                    } else {
+                        debug!("{}", op_text);
+                    }
+                }
+                Ok(file_operation)
+            })
+            .collect()
+    } else {
+        let over_limit = args
+            .confirm_above
+            .filter(|&limit| planned > limit && !args.yes);
+        if let Some(limit) = over_limit
+            && !prompt.is_interactive()
+        {
+            return Err(ImgmvError::ConfirmationRequired {
+                count: planned,
+                limit,
+            });
+        }
+        if args.interactive {
+            if prompt.is_interactive() {
+                for file in &plan.files {
+                    if file.action.is_handled() {
+                        eprintln!("{} {:?} -> {:?}", name, file.source, file.destination);
+                    }
+                }
+                let question = format!("{} {} files?", capitalize(name), planned);
+                if !prompt.confirm(&question)? {
+                    return Ok(aborted());
+                }
+            } else {
+                info!("Not asking for confirmation since stdin is not a terminal");
+            }
+        } else if let Some(limit) = over_limit {
+            let question = format!(
+                "{} {} files, more than {}?",
+                capitalize(name),
+                planned,
+                limit
+            );
+            if !prompt.confirm(&question)? {
+                return Ok(aborted());
+            }
+        }
+
+        progress.on_start(planned);
+        if let Some(zip_path) = &args.zip {
+            write_zip(&plan, zip_path, args, progress)?
+        } else {
+            undo_log_start =
+                undo_log
+                    .open()
+                    .map_err(|source| ImgmvError::DestinationNotWritable {
+                        path: undo_log_path.clone(),
+                        source,
+                    })?;
+            let results;
+            (results, renamed) = execute_plan(&plan, undo_log, args, file_operation, progress)?;
+            info!("Undo log written to {:?}", undo_log_path);
+            results
+        }
+    };
+
+    let mut summary = Summary::default();
+    let mut file_operations = Vec::new();
+    let mut failures = Vec::new();
+    for ((result, size), file) in results.iter().zip(&sizes).zip(&plan.files) {
+        match result {
+            Ok(Some(file_operation)) => {
+                match file_operation.operation {
+                    Operation::Copy => summary.copied += 1,
+                    Operation::Move => summary.moved += 1,
+                }
+                summary.bytes += size;
+                file_operations.push(file_operation);
+            }
+            Ok(None) if file.action == Action::Unchanged => summary.unchanged += 1,
+            Ok(None) => summary.skipped += 1,
+            Err(failure) => {
+                summary.failed += 1;
+                failures.push(failure);
+            }
+        }
+    }
+    progress.on_finish(&summary);
+
+    let rolled_back = args.atomic && !dry_run && !failures.is_empty();
+    let mut rollback_error = None;
+    if rolled_back {
+        for failure in &failures {
+            error!("{}", failure);
+        }
+        // Staged files that had to be renamed to free names are renamed back as well.
+        let completed: Vec<FileOperation> = file_operations
+            .iter()
+            .map(|&file_operation| file_operation.clone())
+            .chain(renamed)
+            .collect();
+        info!("Rolling back {} file operations", completed.len());
+        let failed_reverts = revert_operations(&completed, args)?;
+        if failed_reverts == 0 {
+            undo_log.discard_since(undo_log_start)?;
+        } else {
+            warn!(
+                "Undo log {:?} kept, but some of its operations were already reverted",
+                undo_log_path
+            );
+        }
+        rollback_error = Some(ImgmvError::RolledBack {
+            failed: failures.len(),
+            reverted: completed.len() - failed_reverts,
+            completed: completed.len(),
+        });
+    }
+
+    if let Some(report_path) = &args.report {
+        write_report(report_path, &plan, &results, dry_run, rolled_back).map_err(|source| {
+            ImgmvError::ReportNotWritable {
+                path: report_path.clone(),
+                source,
+            }
+        })?;
+        info!("Report written to {:?}", report_path);
+    }
+    if let Some(error) = rollback_error {
+        return Err(error);
+    }
+
+    match args.format {
+        OutputFormat::Text => {}
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&file_operations)?),
+        OutputFormat::Table => print!("{}", render_table(&file_operations)),
+    }
+
+    for failure in &failures {
+        error!("{}", failure);
+    }
+
+    Ok(summary)
+}
+
+/// Render the destination files of a plan that already exist, marking those that are skipped.
+///
+/// # Arguments
+/// * `plan` - The planned file actions.
+///
+/// # Returns
+/// A heading and one line per existing destination file, or nothing if there are none.
+fn render_collisions(plan: &Plan) -> String {
+    let collisions: Vec<&PlannedFile> = plan
+        .files
+        .iter()
+        .filter(|file| matches!(file.action, Action::Overwrite | Action::Skip))
+        .collect();
+    if collisions.is_empty() {
+        return String::new();
+    }
+
+    let mut output = String::from("Already exist:\n");
+    for file in collisions {
+        output.push_str(&format!(
+            "  {:?}{}\n",
+            file.destination,
+            if file.action == Action::Skip {
+                " (skipped)"
+            } else {
+                ""
+            }
+        ));
+    }
+    output
+}
+
+/// Check that the destination has enough free space for the files of a plan.
+///
+/// Files moved within the same device don't need any space. In a dry run, a lack of space is
+/// only reported.
+///
+/// # Arguments
+/// * `plan` - The planned file actions.
+/// * `sizes` - The sizes of the source files of the plan, in bytes.
+/// * `destination_path` - The path to the destination directory.
+/// * `args` - A reference to the command-line arguments.
+/// * `available_space` - The function that gets the free space in a folder, in bytes.
+///
+/// # Returns
+/// `Ok` if there is enough space, in a dry run, or if the free space cannot be determined, and
+/// `ImgmvError::InsufficientSpace` otherwise.
+fn check_space(
+    plan: &Plan,
+    sizes: &[u64],
+    destination_path: &Path,
+    args: &Args,
+    available_space: impl Fn(&Path) -> std::io::Result<u64>,
+) -> Result<(), ImgmvError> {
+    let folder = match args.zip.as_deref().and_then(Path::parent) {
+        Some(parent) if parent.as_os_str().is_empty() => Path::new("."),
+        Some(parent) => parent,
+        None => destination_path,
+    };
+    let required: u64 = plan
+        .files
+        .iter()
+        .zip(sizes)
+        .filter(|(file, _)| {
+            file.action.is_handled()
+                && (plan.operation == Operation::Copy
+                    || args.zip.is_some()
+                    || !is_same_device(&file.source, folder))
+        })
+        .map(|(_, size)| size)
+        .sum();
+    if required == 0 {
+        return Ok(());
+    }
+    let available = match available_space(folder) {
+        Ok(available) => available,
+        Err(e) => {
+            warn!("Failed to get the free space in {:?}: {}", folder, e);
+            return Ok(());
+        }
+    };
+    debug!(
+        "{} needed, {} available in {:?}",
+        format_size(required),
+        format_size(available),
+        folder
+    );
+    if required <= available {
+        return Ok(());
+    }
+
+    let error = ImgmvError::InsufficientSpace {
+        path: folder.to_path_buf(),
+        required,
+        available,
+    };
+    if !args.dry_run {
+        return Err(error);
+    }
+    if !args.quiet && args.format == OutputFormat::Text {
+        println!("{}", error);
+    } else {
+        warn!("{}", error);
+    }
+    Ok(())
+}
+
+/// The outcome of a planned file: the completed operation, `None` if the file was not handled, or
+/// the failure message.
+type FileResult = Result<Option<FileOperation>, String>;
+
+/// Execute a plan, moving or copying the files in parallel.
+///
+/// With `--strict` or `--atomic`, the files that have not been started when a file fails are
+/// skipped.
+///
+/// # Arguments
+/// * `plan` - The plan to execute.
+/// * `undo_log` - The undo log to record the completed file operations in.
+/// * `args` - A reference to the command-line arguments controlling the operation.
+/// * `perform` - The function that moves or copies each file.
+/// * `progress` - The sink to report each handled file to.
+///
+/// # Returns
+/// A Result containing the result of each planned file, in the same order as the plan, and the
+/// moves of files renamed in place that were not handled to free names, since their original
+/// names were taken, or an error if the execution could not start.
+fn execute_plan(
+    plan: &Plan,
+    undo_log: &UndoLog,
+    args: &Args,
+    perform: &FileOperationFn,
+    progress: &dyn ProgressSink,
+) -> Result<(Vec<FileResult>, Vec<FileOperation>), ImgmvError> {
+    let operation = plan.operation;
+    let name = operation.name();
+    let verify = args.verify && operation == Operation::Copy;
+
+    let pool = thread_pool(args)?;
+    let staged = stage_files(
+        &plan
+            .files
+            .iter()
+            .filter(|file| file.stage)
+            .map(|file| &file.source)
+            .collect(),
+    )?;
+
+    let aborted = std::sync::atomic::AtomicBool::new(false);
+    let handle_file = |file: &PlannedFile| -> FileResult {
+        if aborted.load(std::sync::atomic::Ordering::Relaxed) {
+            return Ok(None);
+        }
+        let Some(file_operation) = file.file_operation(operation) else {
+            return Ok(None);
+        };
+        let (source_file, destination_file) = (&file.source, &file.destination);
+
+        if file.action == Action::Overwrite && args.trash {
+            trash::delete(destination_file)
+                .map_err(|e| format!("Failed to trash {:?}: {}", destination_file, e))?;
+            let trash_text = format!("trash {:?}", destination_file);
+            if args.verbose && args.format == OutputFormat::Text {
+                println!("{}", trash_text);
+            } else {
+                debug!("{}", trash_text);
+            }
+        }
+
+        let staged_file = staged.get(source_file).unwrap_or(source_file);
+        create_parent_dir(destination_file)
+            .and_then(|_| with_retries(args, || perform(operation, staged_file, destination_file)))
+            .and_then(|_| {
+                if verify {
+                    verify_copy(source_file, destination_file)
+                } else {
+                    Ok(())
+                }
+            })
+            .map_err(|e| {
+                if (args.strict || args.atomic)
+                    && !aborted.swap(true, std::sync::atomic::Ordering::Relaxed)
+                {
+                    info!("Skipping the remaining files after a failure");
+                }
+                format!(
+                    "Failed to {} {:?} -> {:?}: {}",
+                    name, source_file, destination_file, e
+                )
+            })?;
+
+        let op_text = file.describe(operation, "");
+        if args.verbose && args.format == OutputFormat::Text {
+            print_ok(&op_text);
+        } else {
+            debug!("{}", op_text);
+        }
+
+        if let Err(e) = undo_log.record(&file_operation) {
+            warn!(
+                "Failed to record {:?} in the undo log: {}",
+                file_operation, e
+            );
+        }
+        Ok(Some(file_operation))
+    };
+    let results: Vec<_> = pool.install(|| {
+        plan.files
+            .par_iter()
+            .map(|file| report_progress(progress, file, handle_file(file)))
+            .collect()
+    });
+
+    // Files that failed or were skipped after a failure are still under their temporary names.
+    let mut renamed = Vec::new();
+    for (file, result) in plan.files.iter().zip(&results) {
+        if let Some(staged_file) = staged.get(&file.source)
+            && !matches!(result, Ok(Some(_)))
+            && let Some(free_name) = unstage_file(staged_file, &file.source)
+        {
+            let file_operation = FileOperation {
+                source: file.source.clone(),
+                destination: free_name,
+                operation: Operation::Move,
+            };
+            if let Err(e) = undo_log.record(&file_operation) {
+                warn!(
+                    "Failed to record {:?} in the undo log: {}",
+                    file_operation, e
+                );
+            }
+            renamed.push(file_operation);
+        }
+    }
+
+    Ok((results, renamed))
+}
+
+/// Execute a plan into a zip archive, adding each file under its destination path in the archive.
+///
+/// Moved files are removed once the archive is complete. With `--strict`, the files after a
+/// failed file are skipped.
+///
+/// # Arguments
+/// * `plan` - The plan to execute, with destinations inside the archive path.
+/// * `zip_path` - The path of the zip archive to create.
+/// * `args` - A reference to the command-line arguments controlling the operation.
+/// * `progress` - The sink to report each added file to.
+///
+/// # Returns
+/// A Result containing the result of each planned file, in the same order as the plan, or an
+/// error if the archive cannot be written.
+fn write_zip(
+    plan: &Plan,
+    zip_path: &Path,
+    args: &Args,
+    progress: &dyn ProgressSink,
+) -> Result<Vec<FileResult>, ImgmvError> {
+    let operation = plan.operation;
+    let not_writable = |source| ImgmvError::ZipNotWritable {
+        path: zip_path.to_path_buf(),
+        source,
+    };
+    let file = if args.collision_strategy() == CollisionStrategy::Overwrite {
+        fs::File::create(zip_path)
+    } else {
+        fs::File::create_new(zip_path)
+    }
+    .map_err(|e| not_writable(e.into()))?;
+    let mut zip = zip::ZipWriter::new(file);
+
+    let mut aborted = false;
+    let mut add_file = |file: &PlannedFile| -> FileResult {
+        if aborted {
+            return Ok(None);
+        }
+        let Some(file_operation) = file.file_operation(operation) else {
+            return Ok(None);
+        };
+        let entry = file
+            .destination
+            .strip_prefix(zip_path)
+            .unwrap_or(&file.destination)
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        add_to_zip(&mut zip, &file.source, &entry).map_err(|e| {
+            if args.strict && !aborted {
+                aborted = true;
+                info!("Skipping the remaining files after a failure");
+            }
+            format!(
+                "Failed to {} {:?} -> {:?}: {}",
+                operation.name(),
+                file.source,
+                file.destination,
+                e
+            )
+        })?;
+
+        let op_text = file.describe(operation, "");
+        if args.verbose && args.format == OutputFormat::Text {
+            print_ok(&op_text);
+        } else {
+            debug!("{}", op_text);
+        }
+        Ok(Some(file_operation))
+    };
+    let results: Vec<_> = plan
+        .files
+        .iter()
+        .map(|file| report_progress(progress, file, add_file(file)))
+        .collect();
+    zip.finish().map_err(not_writable)?;
+
+    if operation == Operation::Move {
+        for file_operation in results.iter().flatten().flatten() {
+            if let Err(e) = fs::remove_file(&file_operation.source) {
+                warn!(
+                    "Failed to remove {:?} after adding it to the archive: {}",
+                    file_operation.source, e
+                );
+            }
+        }
+    }
+    Ok(results)
+}
+
+/// Add a file to a zip archive, keeping its modification time.
+///
+/// Images are already compressed, so the file is stored without compression.
+///
+/// # Arguments
+/// * `zip` - The zip archive to add the file to.
+/// * `source` - The path to the file.
+/// * `entry` - The path of the file in the archive, with `/` separators.
+///
+/// # Returns
+/// A Result indicating success or failure.
+fn add_to_zip(
+    zip: &mut zip::ZipWriter<fs::File>,
+    source: &Path,
+    entry: &str,
+) -> std::io::Result<()> {
+    let mut reader = fs::File::open(source)?;
+    let mut options = zip::write::SimpleFileOptions::default()
+        .compression_method(zip::CompressionMethod::Stored)
+        .large_file(reader.metadata()?.len() >= u32::MAX as u64);
+    if let Ok(modified) = reader.metadata().and_then(|metadata| metadata.modified()) {
+        let modified = chrono::DateTime::<chrono::Local>::from(modified).naive_local();
+        if let Ok(time) = zip::DateTime::from_date_and_time(
+            modified.year() as u16,
+            modified.month() as u8,
+            modified.day() as u8,
+            modified.hour() as u8,
+            modified.minute() as u8,
+            modified.second() as u8,
+        ) {
+            options = options.last_modified_time(time);
+        }
+    }
+    zip.start_file(entry, options)
+        .map_err(std::io::Error::other)?;
+    std::io::copy(&mut reader, zip)?;
+    Ok(())
+}
+
+/// Run a file operation, retrying it with backoff after transient errors.
+///
+/// Errors such as a missing source file or missing permissions are permanent and are not retried.
+///
+/// # Arguments
+/// * `args` - A reference to the command-line arguments with the number of retries and the delay.
+/// * `operation` - The file operation to run.
+///
+/// # Returns
+/// The result of the last attempt.
+fn with_retries(args: &Args, operation: impl Fn() -> std::io::Result<()>) -> std::io::Result<()> {
+    let mut delay = std::time::Duration::from_millis(args.retry_delay);
+    let mut attempt = 0;
+    loop {
+        match operation() {
+            Err(e) if attempt < args.retries && is_transient(&e) => {
+                attempt += 1;
+                warn!(
+                    "Retrying in {:?} after error: {} (attempt {} of {})",
+                    delay, e, attempt, args.retries
+                );
+                std::thread::sleep(delay);
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Check whether an I/O error may go away when the operation is retried.
+///
+/// # Arguments
+/// * `e` - The error.
+///
+/// # Returns
+/// `false` for errors that will happen again, such as a missing file, and `true` otherwise.
+fn is_transient(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+    !matches!(
+        e.kind(),
+        ErrorKind::NotFound
+            | ErrorKind::PermissionDenied
+            | ErrorKind::AlreadyExists
+            | ErrorKind::InvalidInput
+            | ErrorKind::InvalidData
+            | ErrorKind::Unsupported
+            | ErrorKind::IsADirectory
+            | ErrorKind::NotADirectory
+            | ErrorKind::ReadOnlyFilesystem
+            | ErrorKind::StorageFull
+    )
+}
+
+/// Write a CSV report with the source, destination, operation and status of each file action.
+///
+/// In a dry run, handled files have the status `would-move` or `would-copy`, and after an
+/// `--atomic` rollback, `rolled-back`.
+///
+/// # Arguments
+/// * `path` - The path of the report file.
+/// * `plan` - The planned file actions.
+/// * `results` - The result of each file action, in the same order as the plan.
+/// * `dry_run` - Whether the file actions were only planned.
+/// * `rolled_back` - Whether the completed file actions were rolled back.
+///
+/// # Returns
+/// A `Result` indicating success or failure.
+fn write_report(
+    path: &Path,
+    plan: &Plan,
+    results: &[FileResult],
+    dry_run: bool,
+    rolled_back: bool,
+) -> Result<(), csv::Error> {
+    let operation = plan.operation;
+    let mut writer = csv::Writer::from_path(path)?;
+    writer.write_record(["source", "destination", "operation", "status"])?;
+    for (file, result) in plan.files.iter().zip(results) {
+        let status = match result {
+            Ok(Some(_)) if rolled_back => "rolled-back",
+            Ok(Some(_)) => match (operation, dry_run) {
+                (Operation::Copy, false) => "copied",
+                (Operation::Move, false) => "moved",
+                (Operation::Copy, true) => "would-copy",
+                (Operation::Move, true) => "would-move",
+            },
+            Ok(None) if file.action == Action::Unchanged => "unchanged",
+            Ok(None) => "skipped",
+            Err(_) => "failed",
+        };
+        writer.write_record([
+            file.source.to_string_lossy().as_ref(),
+            file.destination.to_string_lossy().as_ref(),
+            operation.name(),
+            status,
+        ])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// A folder in the planned destination layout.
+#[derive(Default)]
+struct TreeNode<'a> {
+    folders: std::collections::BTreeMap<String, TreeNode<'a>>,
+    files: Vec<(String, &'a Path)>,
+}
+
+impl TreeNode<'_> {
+    /// Render the contents of this folder, with each line preceded by `indent`.
+    fn render(&self, indent: &str, output: &mut String) {
+        let count = self.folders.len() + self.files.len();
+        let folders = self
+            .folders
+            .iter()
+            .map(|(name, folder)| (format!("{}/", name), Some(folder)));
+        let files = self
+            .files
+            .iter()
+            .map(|(name, source_file)| (format!("{} <- {:?}", name, source_file), None));
+        for (i, (line, folder)) in folders.chain(files).enumerate() {
+            let last = i + 1 == count;
+            output.push_str(indent);
+            output.push_str(if last { "└── " } else { "├── " });
+            output.push_str(&line);
+            output.push('\n');
+            if let Some(folder) = folder {
+                folder.render(
+                    &format!("{}{}", indent, if last { "    " } else { "│   " }),
+                    output,
+                );
+            }
+        }
+    }
+}
+
+/// Render the file names of file operations in two aligned columns, as in `a.jpg → trip_0.jpg`.
+///
+/// # Arguments
+/// * `file_operations` - The file operations.
+///
+/// # Returns
+/// The table, one line per file operation.
+fn render_table(file_operations: &[&FileOperation]) -> String {
+    let file_name = |path: &Path| {
+        path.file_name()
+            .map_or(String::new(), |name| name.to_string_lossy().into_owned())
+    };
+    let rows: Vec<_> = file_operations
+        .iter()
+        .map(|file_operation| {
+            (
+                file_name(&file_operation.source),
+                file_name(&file_operation.destination),
+            )
+        })
+        .collect();
+    let width = rows
+        .iter()
+        .map(|(source, _)| source.chars().count())
+        .max()
+        .unwrap_or(0);
+    rows.iter()
+        .map(|(source, destination)| {
+            format!(
+                "{}{} → {}\n",
+                source,
+                " ".repeat(width - source.chars().count()),
+                destination
+            )
+        })
+        .collect()
+}
+
+/// Render the destination layout of the file actions as a tree, with subfolders before files.
+/// Skipped files are left out, since they are not placed in the destination.
+///
+/// # Arguments
+/// * `destination_path` - The path to the destination directory, at the root of the tree.
+/// * `files` - The planned file actions.
+///
+/// # Returns
+/// The tree, one line per folder or file.
+fn render_tree(destination_path: &Path, files: &[PlannedFile]) -> String {
+    let mut root = TreeNode::default();
+    for PlannedFile {
+        source: source_file,
+        destination: destination_file,
+        ..
+    } in files.iter().filter(|file| file.action != Action::Skip)
+    {
+        let relative = destination_file
+            .strip_prefix(destination_path)
+            .unwrap_or(destination_file);
+        let mut node = &mut root;
+        if let Some(parent) = relative.parent() {
+            for component in parent.components() {
+                node = node
+                    .folders
+                    .entry(component.as_os_str().to_string_lossy().to_string())
+                    .or_default();
+            }
+        }
+        let name = relative
+            .file_name()
+            .map_or(String::new(), |name| name.to_string_lossy().to_string());
+        node.files.push((name, source_file));
+    }
+
+    let mut output = format!("{}\n", destination_path.display());
+    root.render("", &mut output);
+    output
+}
+
+/// Rename files to temporary names in the same folder.
+///
+/// If a file cannot be renamed, the files renamed so far are renamed back.
+///
+/// # Arguments
+/// * `files` - The files to rename.
+///
+/// # Returns
+/// A `Result` containing the temporary name of each file.
+fn stage_files<'a>(files: &HashSet<&'a PathBuf>) -> std::io::Result<HashMap<&'a PathBuf, PathBuf>> {
+    let mut staged: HashMap<&PathBuf, PathBuf> = HashMap::new();
+    for (i, file) in files.iter().enumerate() {
+        let staged_file = file.with_file_name(format!(".imgmv-{}-{}", std::process::id(), i));
+        if let Err(e) = fs::rename(file, &staged_file) {
+            for (file, staged_file) in &staged {
+                unstage_file(staged_file, file);
+            }
+            return Err(e);
+        }
+        debug!("Renamed {:?} -> {:?} temporarily", file, staged_file);
+        staged.insert(*file, staged_file);
+    }
+    Ok(staged)
+}
+
+/// Rename a file back from its temporary name, or to a free name next to it if its original name
+/// has been taken in the meantime.
+///
+/// # Arguments
+/// * `staged_file` - The temporary name of the file.
+/// * `file` - The original name of the file.
+///
+/// # Returns
+/// The free name the file was renamed to, or `None` if it got its original name back or could
+/// not be renamed.
+fn unstage_file(staged_file: &Path, file: &Path) -> Option<PathBuf> {
+    let destination = if file.exists() {
+        counted_name(file, Path::exists)
+    } else {
+        file.to_path_buf()
+    };
+    if let Err(e) = fs::rename(staged_file, &destination) {
+        warn!(
+            "Failed to rename {:?} back to {:?}: {}",
+            staged_file, destination, e
+        );
+        return None;
+    }
+    if destination == file {
+        return None;
+    }
+    warn!(
+        "Renamed {:?} to {:?}, since its name is taken",
+        file, destination
+    );
+    Some(destination)
+}
+
+/// Ask the user a yes/no question on stderr and read the answer.
+///
+/// # Arguments
+/// * `question` - The question to ask.
+/// * `input` - The input to read the answer from.
+///
+/// # Returns
+/// `true` if the answer is yes, `false` otherwise.
+fn confirm(question: &str, input: &mut impl BufRead) -> std::io::Result<bool> {
+    eprint!("{} [y/N] ", question);
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Capitalize the first letter of a word.
+///
+/// # Arguments
+/// * `word` - The word to capitalize.
+///
+/// # Returns
+/// The word with its first letter in uppercase.
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    chars.next().map_or(String::new(), |first| {
+        first.to_uppercase().chain(chars).collect()
+    })
+}
+
+/// Revert the operations recorded in an undo log.
+///
+/// The undo log is removed if every operation was reverted.
+///
+/// # Arguments
+/// * `undo_log_path` - The path to the undo log.
+/// * `args` - A reference to the command-line arguments.
+///
+/// # Returns
+/// A Result indicating success or failure.
+pub fn undo_operations(undo_log_path: &Path, args: &Args) -> Result<(), ImgmvError> {
+    let mut entries = Vec::new();
+    for line in std::io::BufReader::new(fs::File::open(undo_log_path)?).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        entries.push(
+            serde_json::from_str::<FileOperation>(&line).map_err(|source| {
+                ImgmvError::InvalidUndoLog {
+                    path: undo_log_path.to_path_buf(),
+                    source,
+                }
+            })?,
+        );
+    }
+
+    let failed = revert_operations(&entries, args)?;
+    if failed > 0 {
+        return Err(ImgmvError::FilesFailed {
+            failed,
+            total: entries.len(),
+        });
+    }
+    if !args.dry_run {
+        fs::remove_file(undo_log_path)?;
+    }
+
+    Ok(())
+}
+
+/// Revert file operations.
+///
+/// Operations are reverted in reverse order. Moved files are moved back to their source, and
+/// copied files are deleted.
+///
+/// # Arguments
+/// * `entries` - The file operations to revert.
+/// * `args` - A reference to the command-line arguments.
+///
+/// # Returns
+/// A Result containing the number of operations that could not be reverted, or an error.
+fn revert_operations(entries: &[FileOperation], args: &Args) -> Result<usize, ImgmvError> {
+    let dry_run_prefix = if args.dry_run { "[dry-run] " } else { "" };
+    let mut failed = 0;
+
+    // Moving files back in place may overwrite files that haven't been moved back yet, so rename
+    // those out of the way first, as when they were moved.
+    let moves = || {
+        entries
+            .iter()
+            .filter(|entry| entry.operation == Operation::Move)
+    };
+    let sources: HashSet<&PathBuf> = moves().map(|entry| &entry.source).collect();
+    let in_place: HashSet<&PathBuf> = moves()
+        .map(|entry| &entry.destination)
+        .filter(|destination| sources.contains(destination))
+        .collect();
+    let staged = if args.dry_run {
+        HashMap::new()
+    } else {
+        stage_files(&in_place)?
+    };
+
+    for entry in entries.iter().rev() {
+        let (op_text, result) = match entry.operation {
+            Operation::Copy => (
+                format!("{}delete {:?}", dry_run_prefix, entry.destination),
+                if args.dry_run {
+                    Ok(())
+                } else {
+                    fs::remove_file(&entry.destination)
+                },
+            ),
+            Operation::Move => (
+                format!(
+                    "{}move {:?} -> {:?}",
+                    dry_run_prefix, entry.destination, entry.source
+                ),
+                if args.dry_run {
+                    Ok(())
+                } else {
+                    let staged_file = staged.get(&entry.destination).unwrap_or(&entry.destination);
+                    move_file(staged_file, &entry.source).inspect_err(|_| {
+                        if staged_file != &entry.destination {
+                            unstage_file(staged_file, &entry.destination);
+                        }
+                    })
+                },
+            ),
+        };
+
+        match result {
+            Ok(_) if args.verbose => print_ok(&op_text),
+            Ok(_) => debug!("{}", op_text),
+            Err(e) => {
+                failed += 1;
+                error!("Failed to undo {}: {}", op_text, e);
+            }
+        }
+    }
+
+    Ok(failed)
+}
+
+/// Create a progress bar for the file operations.
+///
+/// # Arguments
+/// * `len` - The number of file operations.
+/// * `args` - A reference to the command-line arguments.
+///
+/// # Returns
+/// A progress bar, which is hidden unless it should be shown.
+fn progress_bar(len: usize, args: &Args) -> ProgressBar {
+    if !show_progress(args, std::io::stdout().is_terminal()) {
+        return ProgressBar::hidden();
+    }
+
+    ProgressBar::with_draw_target(Some(len as u64), ProgressDrawTarget::stdout()).with_style(
+        ProgressStyle::with_template("{bar:40} {pos}/{len} (ETA {eta})")
+            .expect("progress bar template is valid"),
+    )
+}
+
+/// Check whether to show a progress bar.
+///
+/// The progress bar is hidden unless forced on with `--progress`, or if not disabled with
+/// `--no-progress` and file actions are not logged, output is not quieted, this is not a dry run,
+/// the output format is text, and stdout is a terminal.
+///
+/// # Arguments
+/// * `args` - A reference to the command-line arguments.
+/// * `is_terminal` - Whether stdout is a terminal.
+///
+/// # Returns
+/// `true` if the progress bar should be shown.
+fn show_progress(args: &Args, is_terminal: bool) -> bool {
+    if args.progress {
+        true
+    } else if args.no_progress {
+        false
+    } else {
+        !args.verbose
+            && !args.quiet
+            && !args.dry_run
+            && args.format == OutputFormat::Text
+            && is_terminal
+    }
+}
+
+/// Create the parent folder of a destination file if it does not exist.
+///
+/// # Arguments
+/// * `destination` - The path to the destination file.
+///
+/// # Returns
+/// A Result indicating success or failure.
+fn create_parent_dir(destination: &Path) -> std::io::Result<()> {
+    match destination.parent() {
+        Some(folder) if !folder.exists() => {
+            debug!("Creating folder {:?}", folder);
+            fs::create_dir_all(folder)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Copy a file, preserving its access and modification times.
+///
+/// # Arguments
+/// * `source` - The path to the file to copy.
+/// * `destination` - The path to copy the file to.
+/// * `throttle` - The rate limiter to copy the file in chunks with, if any.
+///
+/// # Returns
+/// A Result indicating success or failure.
+fn copy_preserving_times(
+    source: &Path,
+    destination: &Path,
+    throttle: Option<&Throttle>,
+) -> std::io::Result<()> {
+    let metadata = fs::metadata(source)?;
+    match throttle {
+        Some(throttle) => {
+            copy_throttled(source, destination, throttle)?;
+            fs::set_permissions(destination, metadata.permissions())?;
+        }
+        None => {
+            fs::copy(source, destination)?;
+        }
+    }
+    filetime::set_file_times(
+        destination,
+        filetime::FileTime::from_last_access_time(&metadata),
+        filetime::FileTime::from_last_modification_time(&metadata),
+    )
+}
+
+/// Copy the contents of a file in chunks, taking each chunk from a rate limiter.
+///
+/// # Arguments
+/// * `source` - The path to the file to copy.
+/// * `destination` - The path to copy the file to.
+/// * `throttle` - The rate limiter.
+///
+/// # Returns
+/// A Result indicating success or failure.
+fn copy_throttled(source: &Path, destination: &Path, throttle: &Throttle) -> std::io::Result<()> {
+    const CHUNK_SIZE: usize = 64 * 1024;
+    let mut reader = fs::File::open(source)?;
+    let mut writer = std::io::BufWriter::new(fs::File::create(destination)?);
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        let read = std::io::Read::read(&mut reader, &mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        throttle.take(read);
+        writer.write_all(&buffer[..read])?;
+    }
+    writer.flush()
+}
+
+/// Copy a file, or hard-link it to an earlier copy of a file with the same content.
+///
+/// If hard-linking fails, for example because the copies are on different devices, the file is
+/// copied instead.
+///
+/// # Arguments
+/// * `source` - The path to the source file.
+/// * `destination` - The path to the destination file.
+/// * `first_copies` - The first copy of each content hash, which new copies are added to.
+/// * `throttle` - The rate limiter to copy the file in chunks with, if any.
+///
+/// # Returns
+/// A Result indicating success or failure.
+fn copy_or_link(
+    source: &Path,
+    destination: &Path,
+    first_copies: &std::sync::Mutex<HashMap<blake3::Hash, PathBuf>>,
+    throttle: Option<&Throttle>,
+) -> std::io::Result<()> {
+    let hash = hash_file(source)?;
+    let first_copy = first_copies
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(&hash)
+        .cloned();
+    if let Some(first_copy) = first_copy {
+        match fs::hard_link(&first_copy, destination) {
+            Ok(()) => {
+                debug!("Linked {:?} -> {:?}", destination, first_copy);
+                return Ok(());
+            }
+            Err(e) => debug!(
+                "Failed to link {:?} -> {:?}, copying instead: {}",
+                destination, first_copy, e
+            ),
+        }
+    }
+
+    copy_preserving_times(source, destination, throttle)?;
+    first_copies
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .entry(hash)
+        .or_insert_with(|| destination.to_path_buf());
+    Ok(())
+}
+
+/// Verify that a copied file has the same content as its source, removing the copy if not.
+///
+/// # Arguments
+/// * `source` - The path to the source file.
+/// * `destination` - The path to the copied file.
+///
+/// # Returns
+/// A Result indicating success, or an error if the contents differ or cannot be read.
+fn verify_copy(source: &Path, destination: &Path) -> std::io::Result<()> {
+    if hash_file(source)? == hash_file(destination)? {
+        debug!("Verified {:?} -> {:?}", source, destination);
+        return Ok(());
+    }
+
+    fs::remove_file(destination)?;
+    Err(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "copy does not match the source, removed the copy",
+    ))
+}
+
+/// Move a file, falling back to copying and deleting it if it cannot be renamed across devices.
+///
+/// The source file is only removed once it has been copied successfully.
+///
+/// # Arguments
+/// * `source` - The path to the file to move.
+/// * `destination` - The path to move the file to.
+///
+/// # Returns
+/// A Result indicating success or failure.
+fn move_file(source: &Path, destination: &Path) -> std::io::Result<()> {
+    move_file_with(source, destination, |source, destination| {
+        fs::rename(source, destination)
+    })
+}
+
+/// Move a file as `move_file` does, renaming it with a given function.
+///
+/// # Arguments
+/// * `source` - The path to the file to move.
+/// * `destination` - The path to move the file to.
+/// * `rename` - The function that renames a file.
+///
+/// # Returns
+/// A Result indicating success or failure.
+fn move_file_with(
+    source: &Path,
+    destination: &Path,
+    rename: impl Fn(&Path, &Path) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    match rename(source, destination) {
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+            debug!(
+                "Cannot rename {:?} -> {:?} across devices, copying and deleting instead",
+                source, destination
+            );
+            copy_preserving_times(source, destination, None)?;
+            fs::remove_file(source)
+        }
+        Ok(()) => {
+            debug!("Renamed {:?} -> {:?}", source, destination);
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Retrieve all source files from the specified source paths, in the order the paths are given.
+///
+/// Source paths containing glob metacharacters are expanded to the files they match. With
+/// `--stdin`, the files listed on stdin come first.
+///
+/// Subdirectories are only descended into when recursion is enabled, and then no deeper than the
+/// maximum depth, where a depth of 1 is the source directory itself. Hidden files and folders are
+/// skipped unless requested, as are files matching a pattern in the `.imgmvignore` file of their
+/// source folder.
+///
+/// Unless all files are requested, only files with an image extension, or one of the requested
+/// extensions, are returned. Files must match an include pattern if any are given, and must not
+/// match an exclude pattern. Empty files, unless allowed, and files outside the size range, the
+/// date range or the minimum age are skipped.
+///
+/// # Arguments
+/// * `source_paths` - The paths to the source directories.
+/// * `args` - A reference to the command-line arguments.
+///
+/// # Returns
+/// A vector of file paths contained in the source directories.
+pub fn get_source_files(
+    source_paths: &[PathBuf],
+    args: &Args,
+) -> Result<Vec<std::path::PathBuf>, ImgmvError> {
+    let max_depth = if args.recursive {
+        args.max_depth
+    } else {
+        Some(1)
+    };
+
+    let include = build_glob_set(&args.include)?;
+    let exclude = build_glob_set(&args.exclude)?;
+    let since = match &args.since_file {
+        Some(path) => read_since_file(path)?,
+        None => None,
+    };
+    let newest = args
+        .min_age
+        .and_then(|min_age| std::time::SystemTime::now().checked_sub(min_age));
+    let mut all_files = Vec::new();
+    if args.stdin {
+        all_files.extend(read_source_list(std::io::stdin().lock(), args.null)?);
+    }
+    for source_path in source_paths {
+        if is_glob(source_path) {
+            all_files.extend(expand_glob(source_path, args.follow_symlinks, args.hidden)?);
+            continue;
+        }
+
+        let ignored = read_ignore_file(source_path)?;
+        let prefix_file = args
+            .prefix_file
+            .as_ref()
+            .map(|prefix_file| source_path.join(prefix_file));
+        all_files.extend(
+            read_source_dir(source_path, 1, max_depth, args.follow_symlinks, args.hidden)?
+                .into_iter()
+                .filter(|source_file| {
+                    if source_file
+                        .file_name()
+                        .is_some_and(|name| name == IGNORE_FILE_NAME)
+                        || prefix_file.as_ref() == Some(source_file)
+                    {
+                        false
+                    } else if ignored.as_ref().is_some_and(|ignored| {
+                        file_name_matches(ignored, source_file)
+                            || source_file
+                                .strip_prefix(source_path)
+                                .is_ok_and(|relative| ignored.is_match(relative))
+                    }) {
+                        debug!(
+                            "Ignoring file listed in {}: {:?}",
+                            IGNORE_FILE_NAME, source_file
+                        );
+                        false
+                    } else {
+                        true
+                    }
+                }),
+        );
+    }
+
+    let source_files = all_files
+        .into_iter()
+        .filter(|source_file| {
+            let sniffed;
+            let named_file = match sniffed_extension(source_file, args.sniff_ext) {
+                Some(ext) => {
+                    sniffed = source_file.with_extension(ext);
+                    &sniffed
+                }
+                None => source_file,
+            };
+            if args.all
+                || (args.ext.is_empty() && is_image(named_file))
+                || has_extension(named_file, &args.ext)
+            {
+                true
+            } else {
+                debug!("Ignoring file with unselected extension: {:?}", source_file);
+                false
+            }
+        })
+        .filter(|source_file| {
+            if args.include.is_empty() || file_name_matches(&include, source_file) {
+                true
+            } else {
+                debug!("Ignoring file not included: {:?}", source_file);
+                false
+            }
+        })
+        .filter(|source_file| {
+            if file_name_matches(&exclude, source_file) {
+                debug!("Ignoring excluded file: {:?}", source_file);
+                false
+            } else {
+                true
+            }
+        })
+        .filter(|source_file| {
+            if args.allow_empty && args.min_size.is_none() && args.max_size.is_none() {
+                return true;
+            }
+            match fs::metadata(source_file) {
+                Ok(metadata) if metadata.len() == 0 && !args.allow_empty => {
+                    info!("Ignoring empty file: {:?}", source_file);
+                    false
+                }
+                Ok(metadata)
+                    if args.min_size.is_some_and(|min| metadata.len() < min)
+                        || args.max_size.is_some_and(|max| metadata.len() > max) =>
+                {
+                    debug!(
+                        "Ignoring file outside the size range: {:?} ({} bytes)",
+                        source_file,
+                        metadata.len()
+                    );
+                    false
+                }
+                Ok(_) => true,
+                Err(err) => {
+                    warn!("Failed to get size of {:?}: {}", source_file, err);
+                    false
+                }
+            }
+        })
+        .filter(|source_file| {
+            if args.after.is_none() && args.before.is_none() {
+                return true;
+            }
+            match file_date(source_file).map(|date| date.date()) {
+                Some(date)
+                    if args.after.is_some_and(|after| date < after)
+                        || args.before.is_some_and(|before| date > before) =>
+                {
+                    debug!(
+                        "Ignoring file outside the date range: {:?} ({})",
+                        source_file, date
+                    );
+                    false
+                }
+                Some(_) => true,
+                None => {
+                    warn!("Ignoring file without a date: {:?}", source_file);
+                    false
+                }
+            }
+        })
+        .filter(|source_file| {
+            let Some(since) = since else {
+                return true;
+            };
+            match fs::metadata(source_file).and_then(|metadata| metadata.modified()) {
+                Ok(modified) if modified > since => true,
+                Ok(_) => {
+                    debug!(
+                        "Ignoring file not modified since the last run: {:?}",
+                        source_file
+                    );
+                    false
+                }
+                Err(err) => {
+                    warn!(
+                        "Failed to get modification time for {:?}: {}",
+                        source_file, err
+                    );
+                    false
+                }
+            }
+        })
+        .filter(|source_file| {
+            let Some(newest) = newest else {
+                return true;
+            };
+            match fs::metadata(source_file).and_then(|metadata| metadata.modified()) {
+                Ok(modified) if modified <= newest => true,
+                Ok(_) => {
+                    debug!("Ignoring recently modified file: {:?}", source_file);
+                    false
+                }
+                Err(err) => {
+                    warn!(
+                        "Failed to get modification time for {:?}: {}",
+                        source_file, err
+                    );
+                    false
+                }
+            }
+        })
+        .collect();
+
+    Ok(source_files)
+}
+
+/// Read the time of the last run from a marker file.
+///
+/// # Arguments
+/// * `path` - The path to the marker file.
+///
+/// # Returns
+/// The time stored in the marker file, `None` if it does not exist, or an error if it cannot be
+/// read or parsed.
+fn read_since_file(path: &Path) -> Result<Option<std::time::SystemTime>, ImgmvError> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            info!("No marker file {:?}, handling all files", path);
+            return Ok(None);
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let since = chrono::DateTime::parse_from_rfc3339(contents.trim()).map_err(|source| {
+        ImgmvError::InvalidSinceFile {
+            path: path.to_path_buf(),
+            source,
+        }
+    })?;
+    debug!("Handling files modified after {}", since);
+    Ok(Some(since.into()))
+}
+
+/// Store the time of a run in a marker file, for the next run with `--since-file`.
+///
+/// # Arguments
+/// * `path` - The path to the marker file.
+/// * `time` - The time the run started.
+///
+/// # Returns
+/// A `Result` indicating success, or an error if the marker file cannot be written.
+fn write_since_file(path: &Path, time: std::time::SystemTime) -> Result<(), ImgmvError> {
+    let time = chrono::DateTime::<chrono::Local>::from(time);
+    fs::write(path, format!("{}\n", time.to_rfc3339())).map_err(|source| {
+        ImgmvError::SinceFileNotWritable {
+            path: path.to_path_buf(),
+            source,
+        }
+    })
+}
+
+/// Read a list of source files, such as the output of `find`.
+///
+/// Paths that do not exist or are not files are skipped with a warning.
+///
+/// # Arguments
+/// * `input` - The input to read the paths from.
+/// * `null` - Whether the paths are separated by NUL characters instead of newlines.
+///
+/// # Returns
+/// A `Result` containing the source file paths.
+fn read_source_list(input: impl BufRead, null: bool) -> std::io::Result<Vec<PathBuf>> {
+    let separator = if null { b'\0' } else { b'\n' };
+    let mut source_files = Vec::new();
+    for entry in input.split(separator) {
+        let entry = entry?;
+        let entry = String::from_utf8_lossy(&entry);
+        let entry = if null {
+            entry.as_ref()
+        } else {
+            entry.trim_end_matches('\r')
+        };
+        if entry.is_empty() {
+            continue;
+        }
+
+        let path = PathBuf::from(entry);
+        if path.is_file() {
+            source_files.push(path);
+        } else {
+            warn!("Skipping {:?}: not an existing file", path);
+        }
+    }
+    Ok(source_files)
+}
+
+/// Check whether a directory entry is hidden.
+///
+/// Entries whose name begins with a dot are hidden. On Windows, so are entries with the hidden
+/// file attribute.
+///
+/// # Arguments
+/// * `entry` - The directory entry.
+///
+/// # Returns
+/// `true` if the entry is hidden.
+fn is_hidden(entry: &fs::DirEntry) -> bool {
+    if entry.file_name().to_string_lossy().starts_with('.') {
+        return true;
+    }
+
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::MetadataExt;
+        const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+        if entry
+            .metadata()
+            .is_ok_and(|metadata| metadata.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0)
+        {
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Check whether a path contains glob metacharacters, such as `trip/*.jpg`.
+///
+/// # Arguments
+/// * `path` - The path to check.
+///
+/// # Returns
+/// `true` if the path is a glob pattern.
+fn is_glob(path: &Path) -> bool {
+    path.to_string_lossy().contains(['*', '?', '['])
+}
+
+/// Get the folder a glob pattern starts matching in, which is the path up to the first component
+/// containing glob metacharacters.
+///
+/// # Arguments
+/// * `pattern` - The glob pattern.
+///
+/// # Returns
+/// The base folder of the pattern, which is empty for a pattern in the current folder.
+fn glob_base(pattern: &Path) -> PathBuf {
+    pattern
+        .components()
+        .take_while(|component| !is_glob(Path::new(component.as_os_str())))
+        .collect()
+}
+
+/// Expand a glob pattern to the files it matches.
+///
+/// # Arguments
+/// * `pattern` - The glob pattern.
+/// * `follow_symlinks` - Whether to include the targets of symlinks to files.
+/// * `include_hidden` - Whether wildcards match the names of hidden files and folders.
+///
+/// # Returns
+/// A `Result` containing the matching files, or an error if the pattern is invalid.
+fn expand_glob(
+    pattern: &Path,
+    follow_symlinks: bool,
+    include_hidden: bool,
+) -> Result<Vec<PathBuf>, glob::PatternError> {
+    let options = glob::MatchOptions {
+        require_literal_leading_dot: !include_hidden,
+        ..glob::MatchOptions::default()
+    };
+    let mut source_files = Vec::new();
+    for entry in glob::glob_with(&pattern.to_string_lossy(), options)? {
+        let path = match entry {
+            Ok(path) => path,
+            Err(err) => {
+                warn!("Failed to read {:?}: {}", err.path(), err.error());
+                continue;
+            }
+        };
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.is_file() => source_files.push(path),
+            Ok(metadata) if metadata.is_symlink() && follow_symlinks => {
+                match resolve_symlink(&path) {
+                    Ok(Some(target)) => source_files.push(target),
+                    Ok(None) => debug!("Ignoring symlink to non-file entry: {:?}", path),
+                    Err(err) => warn!("Failed to resolve symlink {:?}: {}", path, err),
+                }
+            }
+            Ok(metadata) if metadata.is_symlink() => debug!(
+                "Ignoring symlink: {:?}. Use --follow-symlinks to include its target.",
+                path
+            ),
+            Ok(_) => debug!("Ignoring non-file entry: {:?}", path),
+            Err(err) => warn!("Failed to get file type for {:?}: {}", path, err),
+        }
+    }
+    Ok(source_files)
+}
+
+/// Read the glob patterns in the ignore file of a source folder.
+///
+/// The file has one pattern per line, matched against the file name and the path relative to the
+/// source folder. Empty lines and lines starting with `#` are ignored.
+///
+/// # Arguments
+/// * `source_path` - The path to the source directory.
+///
+/// # Returns
+/// A `Result` containing the patterns, or `None` if the folder has no ignore file.
+fn read_ignore_file(source_path: &Path) -> Result<Option<globset::GlobSet>, ImgmvError> {
+    let path = source_path.join(IGNORE_FILE_NAME);
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut builder = globset::GlobSetBuilder::new();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        builder.add(
+            globset::Glob::new(line).map_err(|source| ImgmvError::InvalidIgnoreFile {
+                path: path.clone(),
+                source,
+            })?,
+        );
+    }
+    debug!("Read ignore file {:?}", path);
+    Ok(Some(builder.build()?))
+}
+
+/// Resolve a symlink to the file it points to.
+///
+/// # Arguments
+/// * `path` - The path to the symlink.
+///
+/// # Returns
+/// The canonical path of the target if it is a file, `None` if it is not a file, or an error if
+/// the symlink cannot be resolved.
+fn resolve_symlink(path: &Path) -> std::io::Result<Option<PathBuf>> {
+    let target = fs::canonicalize(path)?;
+    Ok(fs::metadata(&target)?.is_file().then_some(target))
+}
+
+/// Build a set of glob patterns that matches if any of the patterns match.
+///
+/// # Arguments
+/// * `globs` - The glob patterns.
+///
+/// # Returns
+/// The glob set, or an error if it cannot be built.
+fn build_glob_set(globs: &[globset::Glob]) -> Result<globset::GlobSet, globset::Error> {
+    globs
+        .iter()
+        .fold(globset::GlobSetBuilder::new(), |mut builder, glob| {
+            builder.add(glob.clone());
+            builder
+        })
+        .build()
+}
+
+/// Check whether the file name of a path matches a glob set.
+///
+/// # Arguments
+/// * `glob_set` - The glob set to match against.
+/// * `path` - The path whose file name is matched.
+///
+/// # Returns
+/// `true` if the file name matches any pattern in the set.
+fn file_name_matches(glob_set: &globset::GlobSet, path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|file_name| glob_set.is_match(file_name))
+}
+
+/// Compute the BLAKE3 hash of a file's contents.
+///
+/// # Arguments
+/// * `path` - The path to the file.
+///
+/// # Returns
+/// The hash of the file, or an error if it cannot be read.
+fn hash_file(path: &Path) -> std::io::Result<blake3::Hash> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(fs::File::open(path)?)?;
+    Ok(hasher.finalize())
+}
+
+/// Compute the hash of a file's contents as lowercase hexadecimal digits.
+///
+/// # Arguments
+/// * `path` - The path to the file.
+/// * `algo` - The hash algorithm.
+///
+/// # Returns
+/// The hash of the file, or an error if it cannot be read.
+fn hex_hash_file(path: &Path, algo: HashAlgo) -> std::io::Result<String> {
+    match algo {
+        HashAlgo::Blake3 => Ok(hash_file(path)?.to_hex().to_string()),
+        HashAlgo::Sha256 => {
+            use sha2::Digest;
+            let mut hasher = sha2::Sha256::new();
+            std::io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+            Ok(format!("{:x}", hasher.finalize()))
+        }
+    }
+}
+
+/// Check whether a file has one of the recognized image extensions, ignoring case.
+///
+/// # Arguments
+/// * `source_file` - The path to the file.
+///
+/// # Returns
+/// `true` if the file extension is an image extension.
+fn is_image(source_file: &Path) -> bool {
+    has_extension(source_file, IMAGE_EXTENSIONS)
+}
+
+/// Check whether a file has one of the given extensions, ignoring case.
+///
+/// # Arguments
+/// * `source_file` - The path to the file.
+/// * `extensions` - The extensions to match, without leading dots.
+///
+/// # Returns
+/// `true` if the file extension is one of `extensions`.
+fn has_extension<S: AsRef<str>>(source_file: &Path, extensions: &[S]) -> bool {
+    source_file.extension().is_some_and(|ext| {
+        extensions
+            .iter()
+            .any(|extension| ext.eq_ignore_ascii_case(extension.as_ref()))
+    })
+}
+
+/// Read the files in a directory, descending into subdirectories down to the maximum depth.
+///
+/// # Arguments
+/// * `dir` - The directory to read.
+/// * `depth` - The depth of `dir`, starting at 1 for the source directory.
+/// * `max_depth` - The maximum depth to read, or `None` for no limit.
+/// * `follow_symlinks` - Whether to include the targets of symlinks to files.
+/// * `include_hidden` - Whether to include hidden files and folders.
+///
+/// # Returns
+/// A vector of file paths contained in the directory and its subdirectories.
+fn read_source_dir(
+    dir: &Path,
+    depth: usize,
+    max_depth: Option<usize>,
+    follow_symlinks: bool,
+    include_hidden: bool,
+) -> std::io::Result<Vec<std::path::PathBuf>> {
+    let mut source_files = Vec::new();
+
+    for f in fs::read_dir(dir)? {
+        match f {
+            Ok(entry) if !include_hidden && is_hidden(&entry) => {
+                debug!("Ignoring hidden entry: {:?}", entry.path());
+            }
+            Ok(entry) => match entry.file_type() {
+                Ok(file_type) if file_type.is_file() => source_files.push(entry.path()),
+                Ok(file_type) if file_type.is_dir() && max_depth.is_none_or(|max| depth < max) => {
+                    match read_source_dir(
+                        &entry.path(),
+                        depth + 1,
+                        max_depth,
+                        follow_symlinks,
+                        include_hidden,
+                    ) {
+                        Ok(files) => source_files.extend(files),
+                        Err(err) => warn!("Failed to read directory {:?}: {}", entry.path(), err),
+                    }
+                }
+                Ok(file_type) if file_type.is_symlink() => {
+                    if follow_symlinks {
+                        match resolve_symlink(&entry.path()) {
+                            Ok(Some(target)) => source_files.push(target),
+                            Ok(None) => {
+                                debug!("Ignoring symlink to non-file entry: {:?}", entry.path())
+                            }
+                            Err(err) => {
+                                warn!("Failed to resolve symlink {:?}: {}", entry.path(), err)
+                            }
+                        }
+                    } else {
+                        debug!(
+                            "Ignoring symlink: {:?}. Use --follow-symlinks to include its target.",
+                            entry.path()
+                        );
+                    }
+                }
+                Ok(_) => debug!("Ignoring non-file entry: {:?}", entry.path()),
+                Err(err) => {
+                    warn!(
+                        "Failed to get file type for entry {:?}: {}",
+                        entry.path(),
+                        err
+                    );
+                }
+            },
+            Err(err) => {
+                warn!("Error reading source directory entry: {}", err);
+            }
+        }
+    }
+
+    Ok(source_files)
+}
+
+/// Remove duplicate source files.
+///
+/// With `--dedup`, files with the same content as an earlier file are removed, and with
+/// `--dedup-perceptual`, so are images that look nearly the same as an earlier image. The files
+/// are hashed in parallel, but compared in order, so the first of a set of duplicates is kept.
+///
+/// # Arguments
+/// * `source_files` - The source file paths, in sort order.
+/// * `args` - A reference to the command-line arguments.
+///
+/// # Returns
+/// The source files without duplicates, or an error if the thread pool cannot be built.
+fn remove_duplicates(source_files: Vec<PathBuf>, args: &Args) -> Result<Vec<PathBuf>, ImgmvError> {
+    if !args.dedup && !args.dedup_perceptual {
+        return Ok(source_files);
+    }
+
+    let pool = thread_pool(args)?;
+    let hasher = image_hasher::HasherConfig::new().to_hasher();
+    let hashes: Vec<_> = pool.install(|| {
+        source_files
+            .par_iter()
+            .map(|source_file| {
+                let content = args.dedup.then(|| hash_file(source_file));
+                let image = (args.dedup_perceptual && is_image(source_file))
+                    .then(|| image::open(source_file).map(|image| hasher.hash_image(&image)));
+                (content, image)
+            })
+            .collect()
+    });
+
+    let mut seen_hashes = HashSet::new();
+    let mut seen_images: Vec<image_hasher::ImageHash> = Vec::new();
+    Ok(source_files
+        .into_iter()
+        .zip(hashes)
+        .filter(|(source_file, (content, _))| match content {
+            None => true,
+            Some(Ok(hash)) if seen_hashes.insert(*hash) => true,
+            Some(Ok(_)) => {
+                info!("Skipping duplicate file: {:?}", source_file);
+                false
+            }
+            Some(Err(err)) => {
+                warn!("Failed to hash {:?}: {}", source_file, err);
+                true
+            }
+        })
+        .filter(|(source_file, (_, image))| match image {
+            None => true,
+            Some(Ok(hash)) => {
+                if let Some(distance) = seen_images
+                    .iter()
+                    .map(|seen| seen.dist(hash))
+                    .find(|distance| *distance <= args.dedup_threshold)
+                {
+                    info!(
+                        "Skipping near-duplicate image: {:?} ({} bits differ)",
+                        source_file, distance
+                    );
+                    false
+                } else {
+                    seen_images.push(hash.clone());
+                    true
+                }
+            }
+            Some(Err(err)) => {
+                warn!("Failed to read image {:?}: {}", source_file, err);
+                true
+            }
+        })
+        .map(|(source_file, _)| source_file)
+        .collect())
+}
+
+/// Build the thread pool used for hashing and file operations.
+///
+/// # Arguments
+/// * `args` - A reference to the command-line arguments.
+///
+/// # Returns
+/// The thread pool, with as many threads as requested by `--jobs`, or one per CPU by default.
+fn thread_pool(args: &Args) -> Result<rayon::ThreadPool, ImgmvError> {
+    Ok(rayon::ThreadPoolBuilder::new()
+        .num_threads(args.jobs.unwrap_or(0))
+        .build()?)
+}
+
+/// Sort source files in the requested order, reversed with `--reverse`.
+///
+/// # Arguments
+/// * `source_files` - The source file paths to sort.
+/// * `args` - A reference to the command-line arguments.
+fn sort_source_files(source_files: &mut [PathBuf], args: &Args) {
+    sort_by_order(source_files, args.sort);
+    if args.reverse {
+        source_files.reverse();
+    }
+}
+
+/// Sort source files in the specified order.
+///
+/// Files with equal sort keys are ordered by name, and files with equal names by path, so the
+/// order doesn't depend on the order in which the files were read.
+///
+/// # Arguments
+/// * `source_files` - The source file paths to sort.
+/// * `sort` - The order to sort the files in.
+fn sort_by_order(source_files: &mut [PathBuf], sort: SortOrder) {
+    if sort == SortOrder::None {
+        return;
+    }
+    source_files.sort_by(|a, b| {
+        natural_cmp(
+            &a.file_name().unwrap_or_default().to_string_lossy(),
+            &b.file_name().unwrap_or_default().to_string_lossy(),
+        )
+        .then_with(|| a.cmp(b))
+    });
+    // The cached key sort is stable, so files with equal keys stay in name order.
+    match sort {
+        SortOrder::None | SortOrder::Name => {}
+        SortOrder::Mtime => source_files.sort_by_cached_key(|source_file| {
+            fs::metadata(source_file)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+        }),
+        SortOrder::Size => source_files.sort_by_cached_key(|source_file| {
+            fs::metadata(source_file)
+                .map(|metadata| metadata.len())
+                .ok()
+        }),
+        SortOrder::ExifDate => {
+            source_files.sort_by_cached_key(|source_file| file_date(source_file))
+        }
+    }
+}
+
+/// Compare two strings in natural order, ignoring case and comparing runs of digits by value.
+///
+/// For example, `img2` sorts before `img10`.
+///
+/// # Arguments
+/// * `a` - The first string.
+/// * `b` - The second string.
+///
+/// # Returns
+/// The ordering of `a` relative to `b`.
+fn natural_cmp(a: &str, b: &str) -> std::cmp::Ordering {
+    let mut a_chars = a.chars().peekable();
+    let mut b_chars = b.chars().peekable();
+
+    loop {
+        match (a_chars.peek(), b_chars.peek()) {
+            (None, None) => return std::cmp::Ordering::Equal,
+            (None, Some(_)) => return std::cmp::Ordering::Less,
+            (Some(_), None) => return std::cmp::Ordering::Greater,
+            (Some(a_char), Some(b_char)) if a_char.is_ascii_digit() && b_char.is_ascii_digit() => {
+                let a_digits = take_digits(&mut a_chars);
+                let b_digits = take_digits(&mut b_chars);
+                let a_value = a_digits.trim_start_matches('0');
+                let b_value = b_digits.trim_start_matches('0');
+                let ordering = a_value
+                    .len()
+                    .cmp(&b_value.len())
+                    .then_with(|| a_value.cmp(b_value))
+                    .then_with(|| a_digits.len().cmp(&b_digits.len()));
+                if ordering.is_ne() {
+                    return ordering;
+                }
+            }
+            (Some(_), Some(_)) => {
+                let a_char = a_chars.next().into_iter().flat_map(char::to_lowercase);
+                let b_char = b_chars.next().into_iter().flat_map(char::to_lowercase);
+                let ordering = a_char.cmp(b_char);
+                if ordering.is_ne() {
+                    return ordering;
+                }
+            }
+        }
+    }
+}
+
+/// Consume a run of ASCII digits from a character iterator.
+///
+/// # Arguments
+/// * `chars` - The character iterator, positioned at the first digit.
+///
+/// # Returns
+/// The digits consumed.
+fn take_digits(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut digits = String::new();
+    while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+        digits.push(digit);
+    }
+    digits
+}
+
+/// Get the date a file was captured, falling back to its modification time if it has no EXIF date.
+///
+/// # Arguments
+/// * `source_file` - The path to the file.
+///
+/// # Returns
+/// The capture or modification date of the file, or `None` if neither can be read.
+fn file_date(source_file: &Path) -> Option<NaiveDateTime> {
+    exif_date(source_file).or_else(|| {
+        warn!(
+            "No EXIF capture date in {:?}, using modification time",
+            source_file
+        );
+        modified_date(source_file)
+    })
+}
+
+/// Get the local modification time of a file.
+///
+/// # Arguments
+/// * `source_file` - The path to the file.
+///
+/// # Returns
+/// The modification date of the file, or `None` if it cannot be read.
+fn modified_date(source_file: &Path) -> Option<NaiveDateTime> {
+    match fs::metadata(source_file).and_then(|metadata| metadata.modified()) {
+        Ok(modified) => Some(chrono::DateTime::<chrono::Local>::from(modified).naive_local()),
+        Err(err) => {
+            warn!(
+                "Failed to get modification time for {:?}: {}",
+                source_file, err
+            );
+            None
+        }
+    }
+}
+
+/// Read the EXIF `DateTimeOriginal` tag of a file.
+///
+/// # Arguments
+/// * `source_file` - The path to the file.
+///
+/// # Returns
+/// The capture date of the file, or `None` if the file has no readable EXIF capture date.
+fn exif_date(source_file: &Path) -> Option<NaiveDateTime> {
+    let file = fs::File::open(source_file).ok()?;
+    let exif = exif::Reader::new()
+        .read_from_container(&mut std::io::BufReader::new(file))
+        .ok()?;
+    let field = exif.get_field(exif::Tag::DateTimeOriginal, exif::In::PRIMARY)?;
+    match &field.value {
+        exif::Value::Ascii(values) => NaiveDateTime::parse_from_str(
+            std::str::from_utf8(values.first()?).ok()?,
+            "%Y:%m:%d %H:%M:%S",
+        )
+        .ok(),
+        _ => None,
+    }
+}
+
+/// Read the EXIF `Model` tag of a file.
+///
+/// # Arguments
+/// * `source_file` - The path to the file.
+///
+/// # Returns
+/// The camera model, or `None` if the file has no readable EXIF camera model.
+fn exif_camera(source_file: &Path) -> Option<String> {
+    let file = fs::File::open(source_file).ok()?;
+    let exif = exif::Reader::new()
+        .read_from_container(&mut std::io::BufReader::new(file))
+        .ok()?;
+    let field = exif.get_field(exif::Tag::Model, exif::In::PRIMARY)?;
+    match &field.value {
+        exif::Value::Ascii(values) => Some(
+            String::from_utf8_lossy(values.first()?)
+                .trim_end_matches('\0')
+                .trim()
+                .to_string(),
+        ),
+        _ => None,
+    }
+}
+
+/// Generate source and destination file path pairs.
+///
+/// The destination file names are rendered from the file name template with the prefix, or with
+/// `--date-prefix`, the capture date, the separator, an index, and the original name and
+/// extension. With `--keep-name`, the original name is kept after the index, and with
+/// `--no-rename`, it is used unchanged. With `--hash-name`, files are named by the hash of their
+/// contents. With `--lowercase-name`, the generated name is lowercased.
+///
+/// Indices are counted from the start index, or with `--continue`, from after the highest index
+/// already in the destination folder. Files are put in subfolders with `--preserve-structure`,
+/// `--by-camera` and `--by-date`, and split into `batch_000`, `batch_001` and so on with
+/// `--batch-size`. The numbering restarts in each folder, or with `--global-index`, in each
+/// folder above the batches.
+/// Duplicate destination file names get a counter appended, as in `name-1.jpg`.
+///
+/// The index is zero-padded so that the generated names sort correctly. Unless an
+/// explicit width is given, the width is that of the largest index in the folder,
+/// `start + count - 1`.
+///
+/// # Arguments
+/// * `source_files` - A vector of source file paths.
+/// * `source_paths` - The source directory paths that the source files were found in.
+/// * `destination_path` - The destination directory path.
+/// * `prefix` - The prefix to be added to the destination file names as a string slice.
+/// * `case_insensitive` - Whether destination names that only differ in case are the same file.
+/// * `args` - A reference to the command-line arguments controlling the file names.
+///
+/// # Returns
+/// A vector of tuples, each containing a source file path and its destination file path.
+pub fn generate_source_destination_pairs(
+    source_files: Vec<PathBuf>,
+    source_paths: &[PathBuf],
+    destination_path: &Path,
+    prefix: &str,
+    case_insensitive: bool,
+    args: &Args,
+) -> Vec<(PathBuf, PathBuf)> {
+    let keep_name_template;
+    let template = if args.keep_name {
+        keep_name_template = Template::keep_name();
+        &keep_name_template
+    } else {
+        &args.template
+    };
+    let suffix = args.suffix.as_ref().map(|s| {
+        let suffix = sanitize_prefix(s, args.space_replacement);
+        if suffix != *s {
+            warn!("Using sanitized suffix {:?} instead of {:?}", suffix, s);
+        }
+        if !template.uses_suffix() {
+            warn!(
+                "Ignoring suffix {:?}: the template has no {{suffix}}",
+                suffix
+            );
+        }
+        suffix
+    });
+    let ext_cases = if args.ext_case() == ExtCase::Consistent {
+        first_ext_cases(&source_files)
+    } else {
+        HashMap::new()
+    };
+    let base_folders: Vec<PathBuf> = source_files
+        .iter()
+        .map(|source_file| destination_folder(source_file, source_paths, destination_path, args))
+        .collect();
+    let folders: Vec<PathBuf> = match args.batch_size {
+        Some(batch_size) => {
+            let mut positions: HashMap<&Path, usize> = HashMap::new();
+            base_folders
+                .iter()
+                .map(|folder| {
+                    let position = positions.entry(folder).or_default();
+                    let batch = *position / batch_size;
+                    *position += 1;
+                    folder.join(format!("batch_{:03}", batch))
+                })
+                .collect()
+        }
+        None => base_folders.clone(),
+    };
+    // The folders that numbering restarts in, and the prefixes that are numbered separately.
+    let numbering_folders = if args.global_index {
+        &base_folders
+    } else {
+        &folders
+    };
+    let numbering_prefixes: Vec<String> = source_files
+        .iter()
+        .map(|source_file| ext_prefix(source_file, args).unwrap_or_else(|| prefix.to_string()))
+        .collect();
+    let numberings: Vec<(&Path, &str)> = numbering_folders
+        .iter()
+        .map(PathBuf::as_path)
+        .zip(numbering_prefixes.iter().map(String::as_str))
+        .collect();
+    let mut counts: HashMap<(&Path, &str), usize> = HashMap::new();
+    for numbering in &numberings {
+        *counts.entry(*numbering).or_default() += 1;
+    }
+    let starts: HashMap<(&Path, &str), usize> = counts
+        .keys()
+        .map(|&(folder, prefix)| {
+            let start = if args.continue_numbering {
+                next_free_index(folder, prefix, args).map_or(args.start, |index| {
+                    debug!(
+                        "Continuing numbering of {:?} in {:?} at {}",
+                        prefix, folder, index
+                    );
+                    index.max(args.start)
+                })
+            } else {
+                args.start
+            };
+            ((folder, prefix), start)
+        })
+        .collect();
+    let mut next_indices: HashMap<(&Path, &str), usize> = HashMap::new();
+    let mut used_destinations = UsedDestinations::new(case_insensitive);
+    let mut hash_names: HashMap<PathBuf, String> = HashMap::new();
+
+    source_files
+        .into_iter()
+        .zip(folders.iter().zip(&numberings))
+        .map(|(source_file, (folder, &numbering))| {
+            if args.no_rename {
+                let destination_file = folder.join(source_file.file_name().unwrap_or_default());
+                let destination_file = disambiguate(destination_file, &mut used_destinations);
+                return (source_file, destination_file);
+            }
+            if args.hash_name {
+                let ext = destination_extension(
+                    &source_file,
+                    args.ext_case(),
+                    &ext_cases,
+                    &args.ext_rewrite,
+                    args.sniff_ext,
+                );
+                let destination_file = match hex_hash_file(&source_file, args.hash_algo) {
+                    Ok(hash) => hash_destination(folder, &hash, &ext, args, &mut hash_names),
+                    Err(e) => {
+                        warn!("Failed to hash {:?}, keeping its name: {}", source_file, e);
+                        disambiguate(
+                            folder.join(source_file.file_name().unwrap_or_default()),
+                            &mut used_destinations,
+                        )
+                    }
+                };
+                used_destinations.insert(&destination_file);
+                return (source_file, destination_file);
+            }
+
+            let start = starts[&numbering];
+            let next_index = next_indices.entry(numbering).or_default();
+            let index = format_index(
+                start + *next_index,
+                start,
+                counts[&numbering],
+                args.index_format.or(args.pad_width),
+                args.index_style,
+            );
+            *next_index += 1;
+            let dimensions = if template.uses_dimensions() {
+                image_dimensions(&source_file)
+            } else {
+                None
+            };
+            let file_prefix = file_prefix(&source_file, prefix, args);
+            let mut name = template.render(&TemplateValues {
+                prefix: &file_prefix,
+                separator: &args.separator,
+                index: &index,
+                ext: &destination_extension(
+                    &source_file,
+                    args.ext_case(),
+                    &ext_cases,
+                    &args.ext_rewrite,
+                    args.sniff_ext,
+                ),
+                name: &source_file
+                    .file_stem()
+                    .map_or(String::new(), |stem| sanitize_name(&stem.to_string_lossy())),
+                dimensions,
+                suffix: suffix.as_deref(),
+            });
+            if args.lowercase_name {
+                name = name.to_lowercase();
+            }
+            let destination_file = disambiguate(folder.join(name), &mut used_destinations);
+            (source_file, destination_file)
+        })
+        .collect()
+}
+
+/// Name a destination file by the start of the hash of its contents.
+///
+/// The hash is lengthened until the name isn't taken by a file with different contents, either
+/// earlier in this run or in the destination folder.
+///
+/// # Arguments
+/// * `folder` - The destination folder of the file.
+/// * `hash` - The full hash of the file contents in hexadecimal digits.
+/// * `ext` - The extension of the destination file, including the leading dot.
+/// * `args` - A reference to the command-line arguments.
+/// * `hash_names` - The full hashes of the destination files named so far.
+///
+/// # Returns
+/// The destination file path, which is shared by files with the same contents.
+fn hash_destination(
+    folder: &Path,
+    hash: &str,
+    ext: &str,
+    args: &Args,
+    hash_names: &mut HashMap<PathBuf, String>,
+) -> PathBuf {
+    let mut len = args.hash_length.min(hash.len());
+    loop {
+        let destination_file = folder.join(format!("{}{}", &hash[..len], ext));
+        if !hash_names.contains_key(&destination_file)
+            && destination_file.is_file()
+            && let Ok(existing) = hex_hash_file(&destination_file, args.hash_algo)
+        {
+            hash_names.insert(destination_file.clone(), existing);
+        }
+        match hash_names.get(&destination_file) {
+            Some(other) if other != hash && len < hash.len() => {
+                debug!(
+                    "Lengthening the hash name {:?}, which is taken by different contents",
+                    destination_file
+                );
+                len += 1;
+            }
+            Some(_) => return destination_file,
+            None => {
+                hash_names.insert(destination_file.clone(), hash.to_string());
+                return destination_file;
+            }
+        }
+    }
+}
+
+/// Get the prefix for the destination of a source file.
+///
+/// This is the prefix for the extension of the file given with `--ext-prefix`, or else the
+/// prefix for all files. With `--date-prefix`, it is preceded by the capture date of the file and
+/// the separator, or replaced by the date if the prefix wasn't given explicitly. `--mtime-prefix`
+/// does the same with the modification time of the file.
+///
+/// # Arguments
+/// * `source_file` - The source file path.
+/// * `prefix` - The prefix for all files.
+/// * `args` - A reference to the command-line arguments.
+///
+/// # Returns
+/// The prefix for the file.
+fn file_prefix(source_file: &Path, prefix: &str, args: &Args) -> String {
+    let mapped = ext_prefix(source_file, args);
+    let prefix = mapped.as_deref().unwrap_or(prefix);
+    let date = match (&args.date_prefix, &args.mtime_prefix) {
+        (Some(format), _) => file_date(source_file).map(|date| (date, format)),
+        (None, Some(format)) => modified_date(source_file).map(|date| (date, format)),
+        (None, None) => return prefix.to_string(),
+    };
+
+    let date = sanitize_prefix(
+        &date.map_or("unknown".to_string(), |(date, format)| {
+            date.format(format).to_string()
+        }),
+        args.space_replacement,
+    );
+    if args.prefix.is_some() || mapped.is_some() {
+        format!("{}{}{}", date, args.separator, prefix)
+    } else {
+        date
+    }
+}
+
+/// Get the prefix given with `--ext-prefix` for the extension of a file.
+///
+/// # Arguments
+/// * `source_file` - The source file path.
+/// * `args` - A reference to the command-line arguments.
+///
+/// # Returns
+/// The sanitized prefix for the extension, or `None` if the extension has no prefix of its own.
+fn ext_prefix(source_file: &Path, args: &Args) -> Option<String> {
+    let ext = source_file.extension()?.to_string_lossy().to_lowercase();
+    args.ext_prefix
+        .iter()
+        .find(|(mapped_ext, _)| *mapped_ext == ext)
+        .map(|(_, prefix)| sanitize_prefix(prefix, args.space_replacement))
+}
+
+/// Find the index after the highest one used by the files in a folder.
+///
+/// Only files named by the template of the run are considered, as in [`numbered_index`].
+///
+/// # Arguments
+/// * `folder` - The folder to scan.
+/// * `prefix` - The prefix of the numbered files.
+/// * `args` - A reference to the command-line arguments.
+///
+/// # Returns
+/// The index after the highest one found, or `None` if the folder contains no numbered files.
+fn next_free_index(folder: &Path, prefix: &str, args: &Args) -> Option<usize> {
+    let entries = fs::read_dir(folder).ok()?;
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| numbered_index(&entry.path(), prefix, args))
+        .max()
+        .map(|index| index + 1)
+}
+
+/// A piece of a destination file name, for finding the index in it.
+enum NamePiece {
+    /// Text that is the same in every file name of the run.
+    Fixed(String),
+    /// The index, in the index style of the run.
+    Index,
+    /// The original name, which may be anything.
+    Name,
+    /// A width or height, which is empty if the dimensions are unknown.
+    Digits,
+    /// The extension, which is empty or a dot followed by a name without dots.
+    Ext,
+}
+
+/// Get the index of a file named by the template of the run, with the given prefix.
+///
+/// The separator, the suffix, the index style and `--lowercase-name` are those of the run. The
+/// original name, the dimensions and the extension may be anything.
+///
+/// # Arguments
+/// * `path` - The path to the file.
+/// * `prefix` - The prefix of the numbered files.
+/// * `args` - A reference to the command-line arguments.
+///
+/// # Returns
+/// The index in the file name, or `None` if the file is not named that way.
+fn numbered_index(path: &Path, prefix: &str, args: &Args) -> Option<usize> {
+    let file_name = path.file_name()?.to_str()?;
+    let keep_name_template;
+    let template = if args.keep_name {
+        keep_name_template = Template::keep_name();
+        &keep_name_template
+    } else {
+        &args.template
+    };
+    let case = |text: &str| {
+        if args.lowercase_name {
+            text.to_lowercase()
+        } else {
+            text.to_string()
+        }
+    };
+    let suffix = args.suffix.as_ref().map_or(String::new(), |suffix| {
+        case(&format!(
+            "{}{}",
+            args.separator,
+            sanitize_prefix(suffix, args.space_replacement)
+        ))
+    });
+
+    let mut previous = None;
+    let mut pieces = Vec::new();
+    for part in &template.0 {
+        pieces.push(match part {
+            TemplatePart::Literal(text) => NamePiece::Fixed(case(text)),
+            TemplatePart::Prefix => NamePiece::Fixed(case(prefix)),
+            TemplatePart::Separator
+                if previous == Some(&TemplatePart::Prefix) && prefix.is_empty() =>
+            {
+                NamePiece::Fixed(String::new())
+            }
+            TemplatePart::Separator => NamePiece::Fixed(case(&args.separator)),
+            TemplatePart::Suffix => NamePiece::Fixed(suffix.clone()),
+            TemplatePart::Index => NamePiece::Index,
+            TemplatePart::Name => NamePiece::Name,
+            TemplatePart::Width | TemplatePart::Height => NamePiece::Digits,
+            TemplatePart::Ext => NamePiece::Ext,
+        });
+        previous = Some(part);
+    }
+
+    let index = match_name(&pieces, file_name, None, args.index_style)?;
+    match args.index_style {
+        IndexStyle::Decimal => index.parse().ok(),
+        // The inverse of `alpha_index`, where `a` is 1 more than nothing.
+        IndexStyle::Alpha => index
+            .bytes()
+            .try_fold(0usize, |number, letter| {
+                number
+                    .checked_mul(26)?
+                    .checked_add(usize::from(letter - b'a') + 1)
+            })
+            .map(|number| number - 1),
+    }
+}
+
+/// Match the rest of a file name against the remaining pieces of a name.
+///
+/// The index is tried longest first, and the other pieces shortest first.
+///
+/// # Arguments
+/// * `pieces` - The remaining pieces of the name.
+/// * `rest` - The rest of the file name.
+/// * `index` - The text of the index, if it has been matched already.
+/// * `style` - The index style of the run.
+///
+/// # Returns
+/// The text of the index, or `None` if the file name does not match or has no index.
+fn match_name<'a>(
+    pieces: &[NamePiece],
+    rest: &'a str,
+    index: Option<&'a str>,
+    style: IndexStyle,
+) -> Option<&'a str> {
+    let Some((piece, pieces)) = pieces.split_first() else {
+        return if rest.is_empty() { index } else { None };
+    };
+    let ends = (0..=rest.len()).filter(|&end| rest.is_char_boundary(end));
+    let ends: Vec<usize> = if matches!(piece, NamePiece::Index) {
+        ends.rev().collect()
+    } else {
+        ends.collect()
+    };
+    ends.into_iter().find_map(|end| {
+        let (head, tail) = rest.split_at(end);
+        let matches = match piece {
+            NamePiece::Fixed(text) => head == text,
+            NamePiece::Index => {
+                !head.is_empty()
+                    && match style {
+                        IndexStyle::Decimal => head.bytes().all(|b| b.is_ascii_digit()),
+                        IndexStyle::Alpha => head.bytes().all(|b| b.is_ascii_lowercase()),
+                    }
+            }
+            NamePiece::Name => true,
+            NamePiece::Digits => head.bytes().all(|b| b.is_ascii_digit()),
+            NamePiece::Ext => {
+                head.is_empty()
+                    || head
+                        .strip_prefix('.')
+                        .is_some_and(|ext| !ext.is_empty() && !ext.contains('.'))
+            }
+        };
+        let index = if matches!(piece, NamePiece::Index) {
+            Some(head)
+        } else {
+            index
+        };
+        if matches {
+            match_name(pieces, tail, index, style)
+        } else {
+            None
+        }
+    })
+}
+
+/// Read the width and height of an image without decoding it.
+///
+/// # Arguments
+/// * `source_file` - The path to the image.
+///
+/// # Returns
+/// The width and height in pixels, or `None` if they cannot be read.
+fn image_dimensions(source_file: &Path) -> Option<(u32, u32)> {
+    image::image_dimensions(source_file)
+        .inspect_err(|err| warn!("Failed to read dimensions of {:?}: {}", source_file, err))
+        .ok()
+}
+
+/// Make a destination file path unique among the paths already used in this run.
+///
+/// If the path is already used, a counter is appended to the file stem, as in `name-1.jpg`,
+/// `name-2.jpg`, and so on.
+///
+/// # Arguments
+/// * `destination_file` - The generated destination file path.
+/// * `used_destinations` - The destination file paths used so far, which the result is added to.
+///
+/// # Returns
+/// A destination file path that was not used before.
+fn disambiguate(destination_file: PathBuf, used_destinations: &mut UsedDestinations) -> PathBuf {
+    if used_destinations.insert(&destination_file) {
+        return destination_file;
+    }
+
+    let unique_file = counted_name(&destination_file, |candidate| {
+        used_destinations.contains(candidate)
+    });
+    debug!(
+        "Renamed duplicate destination {:?} to {:?}",
+        destination_file, unique_file
+    );
+    used_destinations.insert(&unique_file);
+    unique_file
+}
+
+/// The destination file paths used in a run, compared ignoring case on case-insensitive file
+/// systems, where `name.JPG` and `name.jpg` are the same file.
+struct UsedDestinations {
+    paths: HashSet<PathBuf>,
+    case_insensitive: bool,
+}
+
+impl UsedDestinations {
+    /// Create an empty set, comparing paths ignoring case if requested.
+    fn new(case_insensitive: bool) -> Self {
+        UsedDestinations {
+            paths: HashSet::new(),
+            case_insensitive,
+        }
+    }
+
+    /// Get the path as it is compared.
+    fn key(&self, path: &Path) -> PathBuf {
+        if self.case_insensitive {
+            PathBuf::from(path.to_string_lossy().to_lowercase())
+        } else {
+            path.to_path_buf()
+        }
+    }
+
+    /// Add a path, returning whether it was not used before.
+    fn insert(&mut self, path: &Path) -> bool {
+        self.paths.insert(self.key(path))
+    }
+
+    /// Check whether a path is used.
+    fn contains(&self, path: &Path) -> bool {
+        self.paths.contains(&self.key(path))
+    }
+}
+
+/// Check whether a folder is on a case-insensitive file system, by writing a probe file to it.
+///
+/// If the folder does not exist or cannot be written to, the usual file system of the operating
+/// system is assumed: case-insensitive on Windows and macOS.
+///
+/// # Arguments
+/// * `folder` - The folder to check.
+///
+/// # Returns
+/// `true` if file names in the folder are compared ignoring case.
+fn is_case_insensitive(folder: &Path) -> bool {
+    let probe = folder.join(format!(".imgmv-case-probe-{}", std::process::id()));
+    if fs::File::create_new(&probe).is_err() {
+        return cfg!(any(windows, target_os = "macos"));
+    }
+    let upper = folder.join(format!(".IMGMV-CASE-PROBE-{}", std::process::id()));
+    let case_insensitive = upper.exists();
+    if let Err(e) = fs::remove_file(&probe) {
+        warn!("Failed to remove probe file {:?}: {}", probe, e);
+    }
+    case_insensitive
+}
+
+/// Check whether two paths are the same file, such as the same path or hard links to one file.
+///
+/// # Arguments
+/// * `a` - The first path.
+/// * `b` - The second path.
+///
+/// # Returns
+/// `true` if both paths exist and are the same file, `false` otherwise.
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    if a == b {
+        return a.exists();
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        matches!(
+            (fs::metadata(a), fs::metadata(b)),
+            (Ok(a), Ok(b)) if a.dev() == b.dev() && a.ino() == b.ino()
+        )
+    }
+    #[cfg(not(unix))]
+    {
+        matches!((a.canonicalize(), b.canonicalize()), (Ok(a), Ok(b)) if a == b)
+    }
+}
+
+/// Check whether a path is too long to be created on this platform.
+///
+/// On Windows, canonical paths have the `\\?\` extended-length prefix, which lifts the
+/// `MAX_PATH` limit of 260 characters, so the shorter limit only applies to other paths.
+///
+/// # Arguments
+/// * `path` - The path to check.
+///
+/// # Returns
+/// A description of the limit that the path exceeds, or `None` if it is short enough.
+fn path_length_error(path: &Path) -> Option<String> {
+    #[cfg(windows)]
+    let (len, max_len) = {
+        use std::os::windows::ffi::OsStrExt;
+        let verbatim = matches!(
+            path.components().next(),
+            Some(std::path::Component::Prefix(prefix)) if prefix.kind().is_verbatim()
+        );
+        (
+            path.as_os_str().encode_wide().count(),
+            if verbatim { 32_767 } else { 259 },
+        )
+    };
+    #[cfg(not(windows))]
+    let (len, max_len) = (path.as_os_str().len(), 4095);
+    if len > max_len {
+        return Some(format!(
+            "path is {} characters long, over the limit of {}",
+            len, max_len
+        ));
+    }
+
+    let name_len = path.file_name().map_or(0, |name| {
+        #[cfg(windows)]
+        {
+            use std::os::windows::ffi::OsStrExt;
+            name.encode_wide().count()
+        }
+        #[cfg(not(windows))]
+        name.len()
+    });
+    (name_len > MAX_NAME_LEN).then(|| {
+        format!(
+            "file name is {} characters long, over the limit of {}",
+            name_len, MAX_NAME_LEN
+        )
+    })
+}
+
+/// Check whether two paths are on the same device, so that moving between them needs no space.
+///
+/// # Arguments
+/// * `a` - The first path.
+/// * `b` - The second path.
+///
+/// # Returns
+/// `true` if both paths exist on the same device, `false` otherwise.
+fn is_same_device(a: &Path, b: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        matches!(
+            (fs::metadata(a), fs::metadata(b)),
+            (Ok(a), Ok(b)) if a.dev() == b.dev()
+        )
+    }
+    #[cfg(not(unix))]
+    {
+        matches!(
+            (a.canonicalize(), b.canonicalize()),
+            (Ok(a), Ok(b)) if a.components().next() == b.components().next()
+        )
+    }
+}
+
+/// Check whether a destination is in the same folder as its source, with a name that only differs
+/// in case, such as an extension change from `.JPG` to `.jpg`.
+///
+/// # Arguments
+/// * `source_file` - The source file path.
+/// * `destination_file` - The destination file path.
+///
+/// # Returns
+/// `true` if the names differ in case only.
+fn is_case_change(source_file: &Path, destination_file: &Path) -> bool {
+    let name = |path: &Path| {
+        path.file_name()
+            .map(|name| name.to_string_lossy().to_lowercase())
+    };
+    source_file.parent() == destination_file.parent()
+        && name(source_file).is_some()
+        && name(source_file) == name(destination_file)
+}
+
+/// Append the first free counter to the stem of a file path, as in `name-1.jpg`.
+///
+/// # Arguments
+/// * `path` - The file path.
+/// * `is_taken` - Whether a candidate path is already taken.
+///
+/// # Returns
+/// The first path with a counter that is not taken.
+fn counted_name(path: &Path, is_taken: impl Fn(&Path) -> bool) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map_or(String::new(), |stem| stem.to_string_lossy().to_string());
+    let ext = path
+        .extension()
+        .map_or(String::new(), |ext| format!(".{}", ext.to_string_lossy()));
+    (1..)
+        .map(|counter| path.with_file_name(format!("{}-{}{}", stem, counter, ext)))
+        .find(|candidate| !is_taken(candidate))
+        .expect("there is always an unused counter")
+}
+
+/// Get the folder to put the destination of a source file in.
+///
+/// With `--preserve-structure`, this is the subfolder of the destination folder with the same path
+/// as the folder of the file relative to its source folder. With `--by-date`, this is a subfolder
+/// named after the capture date of the file, or `unknown` if the date cannot be determined.
+///
+/// # Arguments
+/// * `source_file` - The source file path.
+/// * `source_paths` - The source directory paths.
+/// * `destination_path` - The destination directory path.
+/// * `args` - A reference to the command-line arguments.
+///
+/// # Returns
+/// The folder for the destination file.
+fn destination_folder(
+    source_file: &Path,
+    source_paths: &[PathBuf],
+    destination_path: &Path,
+    args: &Args,
+) -> PathBuf {
+    let mut folder = destination_path.to_path_buf();
+    if args.preserve_structure
+        && let Some(relative) = source_file.parent().and_then(|parent| {
+            source_paths
+                .iter()
+                .find_map(|source_path| parent.strip_prefix(source_path).ok())
+        })
+    {
+        folder.push(relative);
+    }
+    if args.by_camera {
+        folder.push(
+            exif_camera(source_file)
+                .map(|model| sanitize_prefix(&model, args.space_replacement))
+                .filter(|model| !model.is_empty())
+                .unwrap_or_else(|| "unknown".to_string()),
+        );
+    }
+    if let Some(format) = &args.by_date {
+        let subfolder = file_date(source_file).map_or("unknown".to_string(), |date| {
+            date.format(format).to_string()
+        });
+        // The subfolder must stay in the destination, whatever the format renders.
+        folder.extend(
+            Path::new(&subfolder)
+                .components()
+                .filter(|component| matches!(component, std::path::Component::Normal(_))),
+        );
+    }
+    folder
+}
+
+/// Sanitize an original file name for use in a destination file name.
+///
+/// Path separators are replaced by underscores, and runs of whitespace are collapsed into a
+/// single underscore.
+///
+/// # Arguments
+/// * `name` - The original file name.
+///
+/// # Returns
+/// The sanitized file name.
+fn sanitize_name(name: &str) -> String {
+    name.replace(std::path::is_separator, "_")
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+/// Sanitize a prefix for use in destination file names.
+///
+/// Control characters and characters that are unsafe in file names on common file systems are
+/// replaced by underscores, and whitespace by the space replacement, if any. Runs of underscores
+/// or replaced whitespace are collapsed into one.
+///
+/// # Arguments
+/// * `prefix` - The prefix.
+/// * `space_replacement` - The character to replace whitespace with, or `None` to keep spaces.
+///
+/// # Returns
+/// The sanitized prefix.
+fn sanitize_prefix(prefix: &str, space_replacement: Option<char>) -> String {
+    let mut sanitized = String::with_capacity(prefix.len());
+    for c in prefix.chars() {
+        let c = match space_replacement {
+            Some(replacement) if c.is_whitespace() => replacement,
+            _ if c.is_control() || UNSAFE_NAME_CHARS.contains(&c) => '_',
+            _ => c,
+        };
+        if (c == '_' || Some(c) == space_replacement) && sanitized.ends_with(c) {
+            continue;
+        }
+        sanitized.push(c);
+    }
+    sanitized
+}
+
+/// Get the extension to use for the destination of a source file.
+///
+/// # Arguments
+/// * `source_file` - The source file path.
+/// * `ext_case` - How to write the extension.
+/// * `ext_cases` - The first-seen spelling of each lowercased extension, used with
+///   `ExtCase::Consistent`.
+/// * `ext_rewrites` - The lowercased extensions to change and their new extensions.
+/// * `sniff` - Whether to detect the extension of a file without one from its contents.
+///
+/// # Returns
+/// The extension including the leading dot, rewritten or in the requested case, or an empty
+/// string if the source file has no extension and none is detected.
+fn destination_extension(
+    source_file: &Path,
+    ext_case: ExtCase,
+    ext_cases: &HashMap<String, String>,
+    ext_rewrites: &[(String, String)],
+    sniff: bool,
+) -> String {
+    let rewrite = |ext: &str| {
+        ext_rewrites
+            .iter()
+            .find(|(from, _)| *from == ext.to_lowercase())
+            .map(|(_, to)| match ext_case {
+                ExtCase::Lower => to.to_lowercase(),
+                ExtCase::Keep | ExtCase::Consistent => to.clone(),
+            })
+    };
+    if sniff && source_file.extension().is_none() {
+        return match sniffed_extension(source_file, sniff) {
+            Some(ext) => {
+                debug!("Detected extension {:?} of {:?}", ext, source_file);
+                format!(".{}", rewrite(ext).as_deref().unwrap_or(ext))
+            }
+            None => {
+                warn!(
+                    "Cannot detect the type of {:?}, leaving it without an extension",
+                    source_file
+                );
+                String::new()
+            }
+        };
+    }
+    source_file.extension().map_or(String::new(), |ext| {
+        let ext = ext.to_string_lossy();
+        if let Some(new_ext) = rewrite(&ext) {
+            return format!(".{}", new_ext);
+        }
+        match ext_case {
+            ExtCase::Keep => format!(".{}", ext),
+            ExtCase::Lower => format!(".{}", ext.to_lowercase()),
+            ExtCase::Consistent => format!(
+                ".{}",
+                ext_cases
+                    .get(&ext.to_lowercase())
+                    .map_or(ext.as_ref(), String::as_str)
+            ),
+        }
+    })
+}
+
+/// Detect the extension of a file without one from the magic bytes at the start of its contents.
+///
+/// # Arguments
+/// * `source_file` - The source file path.
+/// * `sniff` - Whether to detect extensions at all.
+///
+/// # Returns
+/// The lowercase extension without a leading dot, or `None` if detection is off, the file has
+/// an extension, or its type is unknown.
+fn sniffed_extension(source_file: &Path, sniff: bool) -> Option<&'static str> {
+    if !sniff || source_file.extension().is_some() {
+        return None;
+    }
+    match infer::get_from_path(source_file) {
+        Ok(kind) => kind.map(|kind| kind.extension()),
+        Err(e) => {
+            warn!("Failed to read {:?}: {}", source_file, e);
+            None
+        }
+    }
+}
+
+/// Record the spelling of the first file with each extension, ignoring case.
+///
+/// # Arguments
+/// * `source_files` - The source file paths, in numbering order.
+///
+/// # Returns
+/// The first-seen spelling of each extension, by the lowercased extension.
+fn first_ext_cases(source_files: &[PathBuf]) -> HashMap<String, String> {
+    let mut ext_cases = HashMap::new();
+    for ext in source_files
+        .iter()
+        .filter_map(|source_file| source_file.extension())
+    {
+        let ext = ext.to_string_lossy();
+        ext_cases
+            .entry(ext.to_lowercase())
+            .or_insert_with(|| ext.to_string());
+    }
+    ext_cases
+}
+
+/// Parse an extension and its prefix, such as `jpg=photo`.
+///
+/// # Arguments
+/// * `mapping` - The mapping to parse.
+///
+/// # Returns
+/// The lowercased extension without a leading dot and the prefix, or an error message if the
+/// mapping is invalid.
+fn parse_ext_prefix(mapping: &str) -> Result<(String, String), String> {
+    match mapping.split_once('=') {
+        Some((ext, prefix)) if !ext.trim_start_matches('.').is_empty() => Ok((
+            ext.trim_start_matches('.').to_lowercase(),
+            prefix.to_string(),
+        )),
+        _ => Err(format!(
+            "Invalid extension prefix {:?}. Use an extension and a prefix such as jpg=photo.",
+            mapping
+        )),
+    }
+}
+
+/// Parse an extension and its replacement, such as `jpeg=jpg`.
+///
+/// # Arguments
+/// * `mapping` - The mapping to parse.
+///
+/// # Returns
+/// The lowercased extension and its replacement, both without a leading dot, or an error message
+/// if the mapping is invalid.
+fn parse_ext_rewrite(mapping: &str) -> Result<(String, String), String> {
+    match mapping.split_once('=') {
+        Some((ext, new_ext))
+            if !ext.trim_start_matches('.').is_empty()
+                && !new_ext.trim_start_matches('.').is_empty()
+                && !new_ext.chars().any(std::path::is_separator) =>
+        {
+            Ok((
+                ext.trim_start_matches('.').to_lowercase(),
+                new_ext.trim_start_matches('.').to_string(),
+            ))
+        }
+        _ => Err(format!(
+            "Invalid extension rewrite {:?}. Use an extension and its replacement such as \
+             jpeg=jpg.",
+            mapping
+        )),
+    }
+}
+
+/// Parse a separator, rejecting separators that contain path separators.
+///
+/// # Arguments
+/// * `separator` - The separator to parse.
+///
+/// # Returns
+/// The separator, or an error message if it contains a path separator.
+fn parse_separator(separator: &str) -> Result<String, String> {
+    if separator.chars().any(std::path::is_separator) {
+        Err(format!(
+            "Separator {:?} must not contain path separators",
+            separator
+        ))
+    } else {
+        Ok(separator.to_string())
+    }
+}
+
+/// Parse the character to replace whitespace in the prefix with, rejecting characters that are
+/// unsafe in file names.
+///
+/// # Arguments
+/// * `replacement` - The replacement to parse.
+///
+/// # Returns
+/// The replacement character, or an error message if it is invalid.
+fn parse_space_replacement(replacement: &str) -> Result<char, String> {
+    let mut chars = replacement.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if c.is_control() || UNSAFE_NAME_CHARS.contains(&c) => Err(format!(
+            "Space replacement {:?} is not allowed in file names",
+            c
+        )),
+        (Some(c), None) => Ok(c),
+        _ => Err(format!(
+            "Space replacement {:?} must be a single character",
+            replacement
+        )),
+    }
+}
+
+/// Parse a strftime-style date format, rejecting formats that chrono cannot render, and formats
+/// of paths that are absolute or lead out of the destination folder.
+///
+/// # Arguments
+/// * `format` - The date format to parse, such as `%Y/%m`.
+///
+/// # Returns
+/// The date format, or an error message if it is invalid.
+fn parse_date_format(format: &str) -> Result<String, String> {
+    if chrono::format::StrftimeItems::new(format).any(|item| item == chrono::format::Item::Error) {
+        return Err(format!("Invalid date format {:?}", format));
+    }
+    let sample = NaiveDate::from_ymd_opt(2000, 1, 1)
+        .expect("the sample date is valid")
+        .and_hms_opt(0, 0, 0)
+        .expect("the sample time is valid")
+        .format(format)
+        .to_string();
+    if sample.is_empty()
+        || !Path::new(&sample)
+            .components()
+            .all(|component| matches!(component, std::path::Component::Normal(_)))
+    {
+        return Err(format!(
+            "Date format {:?} must be a relative path in the destination",
+            format
+        ));
+    }
+    Ok(format.to_string())
+}
+
+/// Parse a printf-style index format, `%d` or `%0Nd`.
+///
+/// # Arguments
+/// * `format` - The format to parse.
+///
+/// # Returns
+/// The width to zero-pad the index to, which is 0 for `%d`, or an error message if the format is
+/// invalid.
+fn parse_index_format(format: &str) -> Result<usize, String> {
+    let invalid = |reason: &str| {
+        format!(
+            "Invalid index format {:?}: {}. Use %d, or %05d to zero-pad to 5 digits.",
+            format, reason
+        )
+    };
+    let spec = format
+        .strip_prefix('%')
+        .ok_or_else(|| invalid("it must start with %"))?;
+    let spec = spec
+        .strip_suffix('d')
+        .ok_or_else(|| invalid("only the conversion d is supported"))?;
+    if spec.is_empty() {
+        return Ok(0);
+    }
+    let width = spec
+        .strip_prefix('0')
+        .ok_or_else(|| invalid("only zero padding is supported"))?;
+    width
+        .parse()
+        .map_err(|_| invalid("the width must be a number"))
+}
+
+/// Parse an extension, removing the optional leading dot.
+///
+/// # Arguments
+/// * `ext` - The extension to parse, such as `jpg` or `.jpg`.
+///
+/// # Returns
+/// The extension without the leading dot, or an error message if it is empty.
+fn parse_extension(ext: &str) -> Result<String, String> {
+    match ext.strip_prefix('.').unwrap_or(ext) {
+        "" => Err("Extension must not be empty".to_string()),
+        ext => Ok(ext.to_string()),
+    }
+}
+
+/// Parse an ISO date, such as `2024-05-01`.
+///
+/// # Arguments
+/// * `date` - The date to parse.
+///
+/// # Returns
+/// The date, or an error message if the date is invalid.
+fn parse_date(date: &str) -> Result<NaiveDate, String> {
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map_err(|e| format!("invalid date {:?}, expected YYYY-MM-DD: {}", date, e))
+}
+
+/// Parse a human-readable size, such as `10k` or `5M`, into bytes.
+///
+/// The suffixes `k`, `M` and `G` are powers of 1024 and are case-insensitive. An optional `B` or
+/// `iB` may follow them.
+///
+/// # Arguments
+/// * `size` - The size to parse.
+///
+/// # Returns
+/// The size in bytes, or an error message if the size is invalid.
+fn parse_size(size: &str) -> Result<u64, String> {
+    let lower = size.trim().to_lowercase();
+    // Only one `b` may follow the number, and `ib` only a unit.
+    let number = match lower.strip_suffix("ib") {
+        Some(number) if number.ends_with(['k', 'm', 'g']) => number,
+        _ => lower.strip_suffix('b').unwrap_or(&lower),
+    }
+    .trim_end();
+    let (digits, multiplier) = match number.char_indices().last() {
+        Some((i, 'k')) => (&number[..i], 1 << 10),
+        Some((i, 'm')) => (&number[..i], 1 << 20),
+        Some((i, 'g')) => (&number[..i], 1 << 30),
+        _ => (number, 1),
+    };
+    digits
+        .trim_end()
+        .parse::<u64>()
+        .ok()
+        .and_then(|value| value.checked_mul(multiplier))
+        .ok_or_else(|| {
+            format!(
+                "Invalid size {:?}. Use a number of bytes such as 10k or 5M.",
+                size
+            )
+        })
+}
+
+/// Parse a duration such as `30m` or `1h30m`.
+///
+/// Each number must be followed by one of the units `s`, `m`, `h` and `d`.
+///
+/// # Arguments
+/// * `duration` - The duration to parse.
+///
+/// # Returns
+/// The duration, or an error message if the duration is invalid.
+fn parse_duration(duration: &str) -> Result<std::time::Duration, String> {
+    let invalid = || {
+        format!(
+            "Invalid duration {:?}. Use a number with a unit such as 30m or 1h30m.",
+            duration
+        )
+    };
+    let mut seconds: u64 = 0;
+    let mut chars = duration.trim().chars().peekable();
+    if chars.peek().is_none() {
+        return Err(invalid());
+    }
+    while chars.peek().is_some() {
+        let number: u64 = take_digits(&mut chars).parse().map_err(|_| invalid())?;
+        let unit = match chars.next() {
+            Some('s') => 1,
+            Some('m') => 60,
+            Some('h') => 60 * 60,
+            Some('d') => 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        seconds = number
+            .checked_mul(unit)
+            .and_then(|unit_seconds| seconds.checked_add(unit_seconds))
+            .ok_or_else(invalid)?;
+    }
+    Ok(std::time::Duration::from_secs(seconds))
+}
+
+/// Parse a rate in bytes per second, such as `5M`, which must not be zero.
+///
+/// # Arguments
+/// * `rate` - The rate to parse.
+///
+/// # Returns
+/// The rate in bytes per second, or an error message if the rate is invalid or zero.
+fn parse_rate(rate: &str) -> Result<u64, String> {
+    match parse_size(rate)? {
+        0 => Err("The rate must be more than 0 bytes per second.".to_string()),
+        rate => Ok(rate),
+    }
+}
+
+/// Format a size in bytes for humans, such as `1.2 GiB`.
+///
+/// # Arguments
+/// * `bytes` - The size in bytes.
+///
+/// # Returns
+/// The size in bytes below 1 KiB, or else with one decimal in the largest binary unit that fits.
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes < 1 << 10 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Format an index for a destination file name.
+///
+/// A decimal index is zero-padded to the explicit width if one is given, or else to the width of
+/// the largest index that will be used, `start + count - 1`. An alphabetic index is not padded.
+///
+/// # Arguments
+/// * `index` - The index to format.
+/// * `start` - The first index used.
+/// * `count` - The number of indices used.
+/// * `pad_width` - The explicit width to pad to.
+/// * `style` - How to write the index.
+///
+/// # Returns
+/// The formatted index.
+fn format_index(
+    index: usize,
+    start: usize,
+    count: usize,
+    pad_width: Option<usize>,
+    style: IndexStyle,
+) -> String {
+    match style {
+        IndexStyle::Decimal => {
+            let width = pad_width.unwrap_or_else(|| index_width((start + count).saturating_sub(1)));
+            format!("{:0width$}", index)
+        }
+        IndexStyle::Alpha => alpha_index(index),
+    }
+}
+
+/// Write an index in lowercase letters, counting a, b, ... z, aa, ab, and so on.
+///
+/// # Arguments
+/// * `index` - The index to write, where 0 is `a`.
+///
+/// # Returns
+/// The index in letters.
+fn alpha_index(index: usize) -> String {
+    let mut letters = Vec::new();
+    let mut rest = index + 1;
+    while rest > 0 {
+        rest -= 1;
+        letters.push(b'a' + (rest % 26) as u8);
+        rest /= 26;
+    }
+    letters.iter().rev().map(|&letter| letter as char).collect()
+}
+
+/// Get the number of decimal digits needed to print an index.
+///
+/// # Arguments
+/// * `index` - The largest index that will be printed.
+///
+/// # Returns
+/// The number of digits in `index`.
+fn index_width(index: usize) -> usize {
+    index
+        .checked_ilog10()
+        .map_or(1, |digits| digits as usize + 1)
+}
+
+/// Get the canonical destination path, creating the destination folder if requested.
+///
+/// In a dry run, a missing destination folder is reported but not created.
+///
+/// # Arguments
+/// * `args` - A reference to the command-line arguments.
+///
+/// # Returns
+/// A `Result` containing the destination path or an error.
+pub fn get_destination_path(args: &Args) -> Result<PathBuf, ImgmvError> {
+    let destination = &args.destination();
+    if args.make_dest && !destination.exists() {
+        let op_text = format!(
+            "{}create directory {:?}",
+            if args.dry_run { "[dry-run] " } else { "" },
+            destination
+        );
+        if args.dry_run {
+            if args.verbose && args.format == OutputFormat::Text {
+                println!("{}", op_text);
+            } else {
+                info!("{}", op_text);
+            }
+            return Ok(std::path::absolute(destination)?);
+        }
+
+        fs::create_dir_all(destination).map_err(|e| ImgmvError::DestinationNotWritable {
+            path: destination.clone(),
+            source: e,
+        })?;
+        if args.verbose && args.format == OutputFormat::Text {
+            print_ok(&op_text);
+        } else {
+            debug!("{}", op_text);
+        }
+    }
+
+    destination
+        .canonicalize()
+        .map_err(|e| ImgmvError::DestinationNotFound {
+            path: destination.clone(),
+            source: e,
+        })
+}
+
+/// Get the canonical paths of the source folders, or of the source glob patterns.
+///
+/// # Arguments
+/// * `args` - A reference to the command-line arguments.
+///
+/// # Returns
+/// A `Result` containing the canonical source paths, or `ImgmvError::SourceNotFound`.
+pub fn canonicalize_sources(args: &Args) -> Result<Vec<PathBuf>, ImgmvError> {
+    args.sources()
+        .iter()
+        .map(|source| {
+            // Only the folder that a glob pattern starts matching in has to exist.
+            let base = if is_glob(source) {
+                glob_base(source)
+            } else {
+                source.clone()
+            };
+            let canonical = if base.as_os_str().is_empty() {
+                std::env::current_dir()
+            } else {
+                base.canonicalize()
+            };
+            canonical
+                .map(|canonical| {
+                    canonical.join(
+                        source
+                            .strip_prefix(&base)
+                            .expect("base is a prefix of source"),
+                    )
+                })
+                .map_err(|e| ImgmvError::SourceNotFound {
+                    path: source.clone(),
+                    source: e,
+                })
+        })
+        .collect()
+}
+
+/// Get the source folder name from the provided source paths or use the provided prefix.
+///
+/// If a prefix is provided in the arguments, it is returned. Otherwise, it is the folder name of
+/// the source paths, which must all have the same name. For a glob pattern, this is the folder it
+/// starts matching in.
+///
+/// With `--prefix-file`, the first line of that file in a source folder is used instead of the
+/// folder name, if the file exists. With `--prefix-from-ancestors`, the names of the parent
+/// folders are joined in front of the folder name by the separator. Either way, the prefix is
+/// sanitized for use in file names.
+///
+/// # Arguments
+/// * `args` - A reference to the command-line arguments.
+///
+/// # Returns
+/// A `Result` containing the source folder name, `ImgmvError::NoPrefix`,
+/// `ImgmvError::TooFewAncestors` or `ImgmvError::AmbiguousPrefix`.
+pub fn get_prefix(args: &Args) -> Result<String, ImgmvError> {
+    if let Some(p) = &args.prefix {
+        let prefix = sanitize_prefix(p, args.space_replacement);
+        if prefix != *p {
+            warn!("Using sanitized prefix {:?} instead of {:?}", prefix, p);
+        }
+        return Ok(prefix);
+    }
+
+    let mut names = args
+        .sources()
+        .iter()
+        .map(|source| {
+            let folder = if is_glob(source) {
+                glob_base(source)
+            } else {
+                source.clone()
+            };
+            if let Some(prefix_file) = &args.prefix_file
+                && let Some(title) = read_prefix_file(&folder.join(prefix_file))
+            {
+                return Ok(title);
+            }
+            if args.prefix_from_ancestors == 1 {
+                return folder
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .ok_or(ImgmvError::NoPrefix);
+            }
+            ancestor_names(&folder, args.prefix_from_ancestors)
+                .map(|names| names.join(&args.separator))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    names.dedup();
+    match names.len() {
+        0 => Err(ImgmvError::NoPrefix),
+        1 => Ok(sanitize_prefix(&names[0], args.space_replacement)),
+        _ => Err(ImgmvError::AmbiguousPrefix(names)),
+    }
+}
+
+/// Get the names of the last folders of a path, which is made absolute first.
+///
+/// # Arguments
+/// * `folder` - The path of the last folder.
+/// * `count` - The number of folder names to get.
+///
+/// # Returns
+/// The folder names, outermost first, `ImgmvError::SourceNotFound` if the folder does not exist,
+/// or `ImgmvError::TooFewAncestors` if the path has fewer folders.
+fn ancestor_names(folder: &Path, count: usize) -> Result<Vec<String>, ImgmvError> {
+    let canonical = folder
+        .canonicalize()
+        .map_err(|source| ImgmvError::SourceNotFound {
+            path: folder.to_path_buf(),
+            source,
+        })?;
+    let mut names: Vec<String> = canonical
+        .components()
+        .rev()
+        .filter_map(|component| match component {
+            std::path::Component::Normal(name) => Some(name.to_string_lossy().to_string()),
+            _ => None,
+        })
+        .take(count)
+        .collect();
+    if names.len() < count {
+        return Err(ImgmvError::TooFewAncestors {
+            path: canonical,
+            count,
+        });
+    }
+    names.reverse();
+    Ok(names)
+}
+
+/// Read a prefix from the first line of a file, such as a `title.txt` describing an event.
+///
+/// # Arguments
+/// * `path` - The path to the file.
+///
+/// # Returns
+/// The trimmed first line of the file, or `None` if the file does not exist, cannot be read or
+/// starts with an empty line.
+fn read_prefix_file(path: &Path) -> Option<String> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            debug!("No prefix file {:?}, using the folder name", path);
+            return None;
+        }
+        Err(e) => {
+            warn!("Failed to read prefix file {:?}: {}", path, e);
+            return None;
+        }
+    };
+    let title = contents.lines().next().unwrap_or_default().trim();
+    if title.is_empty() {
+        warn!(
+            "Ignoring prefix file {:?} starting with an empty line",
+            path
+        );
+        return None;
+    }
+    Some(title.to_string())
+}
+
+#[cfg(test)]
+mod tests;
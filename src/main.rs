@@ -1,220 +1,200 @@
-#![allow(unused)]
-
-use clap::Parser;
-use log::{debug, error, info, warn};
+use clap::{CommandFactory, FromArgMatches};
+use imgmv_rs::{
+    Args, CollisionStrategy, Command, Config, FAILED_STYLE, ImgmvError, OK_STYLE, OutputFormat,
+    Summary, TerminalProgress, TerminalPrompt, canonicalize_sources, disk_file_operation,
+    get_destination_path, get_prefix, list_source_files, move_images, undo_operations,
+    watch_images,
+};
 use std::fs;
-use std::path::{Path, PathBuf};
-
-#[derive(Parser, Debug)]
-#[command(version, about="Rename images based on their folder.", long_about = None)]
-struct Args {
-    /// The source folder containing the images.
-    source: PathBuf,
-
-    /// The target folder to put the renamed images. Defaults to ".".
-    #[arg(default_value = ".")]
-    destination: PathBuf,
+use std::io::Write;
+use std::path::Path;
+use std::process::ExitCode;
+
+/// A logger that writes to stderr as configured by `RUST_LOG`, and debug and higher messages of
+/// `imgmv` to a log file.
+struct TeeLogger {
+    stderr: env_logger::Logger,
+    file: std::sync::Mutex<fs::File>,
+}
 
-    /// Copy instead of moving.
-    #[arg(short, long)]
-    copy: bool,
+impl TeeLogger {
+    /// Check whether a message goes to the log file, which leaves out other crates.
+    fn logs_to_file(metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Debug && metadata.target().starts_with("imgmv")
+    }
+}
 
-    /// The image prefix to use. Defaults to the source folder name.
-    #[arg(short, long)]
-    prefix: Option<String>,
+impl log::Log for TeeLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.stderr.enabled(metadata) || Self::logs_to_file(metadata)
+    }
 
-    /// Log file actions.
-    #[arg(short, long)]
-    verbose: bool,
+    fn log(&self, record: &log::Record) {
+        self.stderr.log(record);
+        if Self::logs_to_file(record.metadata()) {
+            let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+            // There is nowhere to report a failure to write the log.
+            let _ = writeln!(
+                file,
+                "[{} {:<5} {}] {}",
+                chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ"),
+                record.level(),
+                record.target(),
+                record.args()
+            );
+        }
+    }
 
-    /// Do nothing.
-    #[arg(short, long)]
-    dry_run: bool,
+    fn flush(&self) {
+        self.stderr.flush();
+        let _ = self.file.lock().unwrap_or_else(|e| e.into_inner()).flush();
+    }
 }
 
-/// Move or copy images from the source path to the destination path with a specified prefix.
+/// Set up logging to stderr, and to a log file if one is given.
 ///
 /// # Arguments
-/// * `source_path` - The path to the source directory.
-/// * `destination_path` - The path to the destination directory.
-/// * `copy_file` - A boolean indicating whether to copy (true) or move (false) the files.
-/// * `prefix` - The prefix to be added to the destination file names.
-/// * `verbose` - A boolean indicating whether to log file actions.
-/// * `dry_run` - A boolean indicating whether to perform a dry run (no actual file operations).
+/// * `log_file` - The path to the log file, which is truncated.
 ///
 /// # Returns
-/// A Result indicating success or failure.
-fn move_images(
-    source_path: PathBuf,
-    destination_path: PathBuf,
-    copy_file: bool,
-    prefix: &str,
-    verbose: bool,
-    dry_run: bool,
-) -> Result<(), Box<dyn std::error::Error>> {
-    debug!("Source path: {:?}", source_path);
-    debug!("Destination path: {:?}", destination_path);
-    debug!("Copy file: {}", copy_file);
-    debug!("Prefix: {}", prefix);
-    debug!("Verbose: {}", verbose);
-    debug!("Dry run: {}", dry_run);
-
-    let source_files: Vec<_> = get_source_files(source_path)?;
-
-    let op = if dry_run {
-        // Dry run: no operation
-        |_: &PathBuf, _: &PathBuf| -> std::io::Result<()> { Ok(()) }
-    } else if copy_file {
-        |source: &PathBuf, destination: &PathBuf| -> std::io::Result<()> {
-            fs::copy(source, destination).and(Ok(()))
-        }
-    } else {
-        |source: &PathBuf, destination: &PathBuf| -> std::io::Result<()> {
-            fs::rename(source, destination)
-        }
+/// A `Result` indicating success, or an error if the log file cannot be created.
+fn init_logging(log_file: Option<&Path>) -> Result<(), ImgmvError> {
+    let Some(path) = log_file else {
+        env_logger::init();
+        return Ok(());
     };
 
-    let dry_run_prefix = if dry_run { "[dry-run] " } else { "" };
-    let name = if copy_file { "copy" } else { "move" };
-
-    for (source_file, destination_file) in
-        generate_source_destination_pairs(source_files, destination_path, prefix)
-    {
-        let op_text = format!(
-            "{}{} {:?} -> {:?}",
-            dry_run_prefix, name, source_file, destination_file
-        );
-        if verbose {
-        } else {
-            debug!("{}", op_text);
-        }
+    let stderr = env_logger::Builder::from_default_env().build();
+    let max_level = stderr.filter().max(log::LevelFilter::Debug);
+    let file = fs::File::create(path).map_err(|source| ImgmvError::LogFileNotWritable {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    log::set_boxed_logger(Box::new(TeeLogger {
+        stderr,
+        file: std::sync::Mutex::new(file),
+    }))
+    .expect("the logger is only set once");
+    log::set_max_level(max_level);
+    Ok(())
+}
 
-        match op(&source_file, &destination_file) {
-            Ok(_) if verbose => println!("{}", op_text),
-            Ok(_) => debug!("{}", op_text),
-            Err(e) => error!(
-                "Failed to {} {:?} -> {:?}: {}",
-                name, source_file, destination_file, e
-            ),
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            e.exit_code()
         }
     }
-
-    Ok(())
 }
 
-/// Retrieve all source files from the specified source path.
+/// Print the summary of the handled files, unless the output format or options rule it out.
 ///
 /// # Arguments
-/// * `source_path` - The path to the source directory.
-///
-/// # Returns
-/// A vector of file paths contained in the source directory.
-fn get_source_files(
-    source_path: PathBuf,
-) -> Result<Vec<std::path::PathBuf>, Box<dyn std::error::Error>> {
-    let source_files: Vec<_> = fs::read_dir(source_path)?
-        .filter_map(|f| match f {
-            Ok(entry) => match entry.file_type() {
-                Ok(file_type) => {
-                    if file_type.is_file() {
-                        Some(entry.path())
-                    } else {
-                        debug!("Ignoring non-file entry: {:?}", entry.path());
-                        None
-                    }
-                }
-                Err(err) => {
-                    warn!(
-                        "Failed to get file type for entry {:?}: {}",
-                        entry.path(),
-                        err
-                    );
-                    None
-                }
-            },
-            Err(err) => {
-                warn!("Error reading source directory entry: {}", err);
-                None
-            }
-        })
-        .collect();
-
-    Ok(source_files)
+/// * `summary` - The summary of the handled files.
+/// * `args` - A reference to the command-line arguments.
+fn print_summary(summary: &Summary, args: &Args) {
+    if !args.quiet && !args.plan && !args.check && args.format != OutputFormat::Json {
+        let style = if summary.failed > 0 {
+            FAILED_STYLE
+        } else {
+            OK_STYLE
+        };
+        anstream::println!(
+            "{style}{}{}{style:#}",
+            if args.dry_run { "[dry-run] " } else { "" },
+            summary
+        );
+    }
 }
 
-/// Generate source and destination file path pairs.
-///
-/// This function takes a list of source file paths, a destination directory path,
-/// and a prefix string. It generates destination file paths by appending the prefix
-/// and an index to the original file name, preserving the original file extension.
-///
-/// # Arguments
-/// * `source_files` - A vector of source file paths.
-/// * `destination_path` - The destination directory path.
-/// * `prefix` - The prefix to be added to the destination file names as a string slice.
+/// Run `imgmv` with the command-line arguments.
 ///
 /// # Returns
-/// A vector of tuples, each containing a source file path and the corresponding destination file path.
-fn generate_source_destination_pairs(
-    source_files: Vec<PathBuf>,
-    destination_path: PathBuf,
-    prefix: &str,
-) -> Vec<(PathBuf, PathBuf)> {
-    source_files
-        .into_iter()
-        .enumerate()
-        .map(|(index, source_file)| {
-            let destination_file = destination_path.join(format!(
-                "{}_{}{}",
-                prefix,
-                index,
-                source_file
-                    .extension()
-                    .map_or(String::new(), |ext| format!(".{}", ext.to_string_lossy()))
-            ));
-            (source_file, destination_file)
-        })
-        .collect()
-}
+/// A `Result` indicating success or failure.
+fn run() -> Result<(), ImgmvError> {
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if let Some(Command::Completions { shell }) = args.command {
+        clap_complete::generate(shell, &mut Args::command(), "imgmv", &mut std::io::stdout());
+        return Ok(());
+    }
+    init_logging(args.log_file.as_deref())?;
+    if let Some((path, config)) = Config::load()? {
+        config.apply(&path, &mut args, &matches)?;
+    }
+    args.color.apply();
 
-/// Get the source folder name from the provided source path or use the provided prefix.
-///
-/// If a prefix is provided in the arguments, it is returned. Otherwise, the function extracts the folder name from
-/// the source path.
-///
-/// # Arguments
-/// * `args` - A reference to the command-line arguments.
-///
-/// # Returns
-/// A `Result` containing the source folder name or an error message.
-fn get_prefix(args: &Args) -> Result<String, String> {
-    match &args.prefix {
-        Some(p) => Ok(p.clone()),
-        None => Ok((&args.source)
-        .file_name()
-        .ok_or(
-            "Cannot determine prefix from source path. Supply a prefix using the --prefix option.",
-        )?
-        .to_string_lossy()
-        .to_string()),
+    if let Some(undo_log_path) = &args.undo {
+        return undo_operations(undo_log_path, &args);
+    }
+    if args.plan || args.check {
+        args.dry_run = true;
+    }
+    if args.trash && args.collision_strategy() != CollisionStrategy::Overwrite {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "--trash requires --force or --collision-strategy overwrite",
+            )
+            .exit();
+    }
+    if args.stdin && args.paths.len() > 1 {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "only the destination folder can be given with --stdin",
+            )
+            .exit();
     }
-}
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    env_logger::init();
-    let args = Args::parse();
-    move_images(
-        args.source
-            .canonicalize()
-            .map_err(|e| format!("Failed to canonicalize source path: {}", e))?,
-        args.destination
-            .canonicalize()
-            .map_err(|e| format!("Failed to canonicalize destination path: {}", e))?,
-        args.copy,
-        &get_prefix(&args)?,
-        args.verbose,
-        args.dry_run,
-    )
-    .map_err(|e| format!("Error moving images: {}", e))?;
+    let source_paths = canonicalize_sources(&args)?;
+    if args.list_only {
+        for source_file in list_source_files(&source_paths, &args)? {
+            println!("{}", source_file.display());
+        }
+        return Ok(());
+    }
+    let prefix = if args.no_rename || args.hash_name || args.map.is_some() {
+        String::new()
+    } else {
+        get_prefix(&args)?
+    };
+    let destination_path = get_destination_path(&args)?;
+    let file_operation = disk_file_operation(&args);
+    let progress = TerminalProgress::new(&args);
+    let summary = move_images(
+        source_paths.clone(),
+        destination_path.clone(),
+        &prefix,
+        &args,
+        &file_operation,
+        &progress,
+        &TerminalPrompt,
+    )?;
+
+    print_summary(&summary, &args);
+    if args.watch {
+        return watch_images(
+            &source_paths,
+            &destination_path,
+            &prefix,
+            &args,
+            &file_operation,
+            &progress,
+            &TerminalPrompt,
+            |summary| {
+                print_summary(summary, &args);
+                std::ops::ControlFlow::Continue(())
+            },
+        );
+    }
+    if summary.failed > 0 {
+        return Err(ImgmvError::FilesFailed {
+            failed: summary.failed,
+            total: summary.total(),
+        });
+    }
     Ok(())
 }
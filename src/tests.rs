@@ -0,0 +1,3546 @@
+use super::*;
+
+/// Parse command-line arguments, as given after `imgmv`.
+fn parse_args(args: &[&str]) -> Args {
+    Args::try_parse_from(std::iter::once("imgmv").chain(args.iter().copied()))
+        .expect("the test arguments are valid")
+}
+
+/// Get a path as a command-line argument.
+fn arg(path: &Path) -> &str {
+    path.to_str().unwrap()
+}
+
+/// Write a file with the given contents, creating its parent folders.
+fn write_file(path: &Path, contents: &str) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, contents).unwrap();
+}
+
+/// The sorted names of the entries in a folder, including hidden ones.
+fn names(folder: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(folder)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    names.sort();
+    names
+}
+
+/// A prompt that answers every question the same way, and records the questions.
+struct MockPrompt {
+    /// The answer, or `None` if the user cannot be asked.
+    answer: Option<bool>,
+    questions: std::sync::Mutex<Vec<String>>,
+}
+
+impl MockPrompt {
+    fn new(answer: Option<bool>) -> Self {
+        MockPrompt {
+            answer,
+            questions: std::sync::Mutex::new(Vec::new()),
+        }
+    }
+
+    fn questions(&self) -> Vec<String> {
+        self.questions.lock().unwrap().clone()
+    }
+}
+
+impl Prompt for MockPrompt {
+    fn is_interactive(&self) -> bool {
+        self.answer.is_some()
+    }
+
+    fn confirm(&self, question: &str) -> std::io::Result<bool> {
+        self.questions.lock().unwrap().push(question.to_string());
+        Ok(self.answer.expect("only interactive prompts are asked"))
+    }
+}
+
+/// Move or copy the images on disk as `imgmv` would with the arguments, answering any
+/// questions with a prompt.
+fn run_with(args: &Args, prompt: &dyn Prompt) -> Result<Summary, ImgmvError> {
+    let prefix = if args.no_rename || args.hash_name || args.map.is_some() {
+        String::new()
+    } else {
+        get_prefix(args)?
+    };
+    move_images(
+        canonicalize_sources(args)?,
+        get_destination_path(args)?,
+        &prefix,
+        args,
+        &disk_file_operation(args),
+        &(),
+        prompt,
+    )
+}
+
+/// Move or copy the images on disk as `imgmv` would with the arguments, as from a script.
+fn run(args: &Args) -> Result<Summary, ImgmvError> {
+    run_with(args, &MockPrompt::new(None))
+}
+
+/// Plan the moves or copies of the images as `imgmv` would with the arguments.
+fn plan(args: &Args) -> Plan {
+    let source_paths = canonicalize_sources(args).unwrap();
+    let prefix = if args.no_rename || args.hash_name || args.map.is_some() {
+        String::new()
+    } else {
+        get_prefix(args).unwrap()
+    };
+    build_plan(
+        get_source_files(&source_paths, args).unwrap(),
+        &source_paths,
+        &args.destination(),
+        &prefix,
+        false,
+        args,
+    )
+    .unwrap()
+}
+
+/// The destination file names of a plan, in order.
+fn destination_names(plan: &Plan) -> Vec<String> {
+    plan.files
+        .iter()
+        .map(|file| {
+            file.destination
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .to_string()
+        })
+        .collect()
+}
+
+/// A source folder `src` with two images, and an empty destination folder `dst`.
+fn two_images() -> (tempfile::TempDir, PathBuf, PathBuf) {
+    let dir = tempfile::tempdir().unwrap();
+    let (source, destination) = (dir.path().join("src"), dir.path().join("dst"));
+    write_file(&source.join("a.jpg"), "A");
+    write_file(&source.join("b.jpg"), "B");
+    fs::create_dir(&destination).unwrap();
+    (dir, source, destination)
+}
+
+#[test]
+fn index_is_padded_to_the_largest_index() {
+    let format =
+        |index, count, pad_width| format_index(index, 0, count, pad_width, IndexStyle::Decimal);
+
+    assert_eq!(format(0, 1, None), "0");
+    assert_eq!(format(8, 9, None), "8");
+    assert_eq!(format(0, 10, None), "0");
+    assert_eq!(format(0, 11, None), "00");
+    assert_eq!(format(5, 100, None), "05");
+    assert_eq!(format(5, 101, None), "005");
+    assert_eq!(format(5, 100, Some(4)), "0005");
+    // An explicit width is a minimum, not a limit.
+    assert_eq!(format(123, 200, Some(2)), "123");
+    assert_eq!([0, 9, 10, 99, 100].map(index_width), [1, 1, 2, 2, 3]);
+}
+
+#[test]
+fn pad_width_pads_the_destination_names() {
+    let (_dir, source, destination) = two_images();
+
+    let plan = plan(&parse_args(&[
+        "-p",
+        "x",
+        "--sort=name",
+        "--pad-width=3",
+        arg(&source),
+        arg(&destination),
+    ]));
+
+    assert_eq!(destination_names(&plan), ["x_000.jpg", "x_001.jpg"]);
+}
+
+#[test]
+fn start_offsets_the_index_and_its_padding() {
+    let (_dir, source, destination) = two_images();
+    let names = |start: &str| {
+        destination_names(&plan(&parse_args(&[
+            "-p",
+            "x",
+            "--sort=name",
+            "-s",
+            start,
+            arg(&source),
+            arg(&destination),
+        ])))
+    };
+
+    assert_eq!(names("0"), ["x_0.jpg", "x_1.jpg"]);
+    assert_eq!(names("1"), ["x_1.jpg", "x_2.jpg"]);
+    assert_eq!(names("9"), ["x_09.jpg", "x_10.jpg"]);
+    assert_eq!(names("99"), ["x_099.jpg", "x_100.jpg"]);
+}
+
+/// The source files in a folder, relative to it and sorted.
+fn relative_files(folder: &Path, files: Vec<PathBuf>) -> Vec<String> {
+    let mut files: Vec<String> = files
+        .iter()
+        .map(|file| {
+            file.strip_prefix(folder)
+                .unwrap()
+                .to_string_lossy()
+                .replace('\\', "/")
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+/// The source files that `imgmv` would select with the arguments, relative to the source folder.
+fn selected_files(folder: &Path, options: &[&str]) -> Vec<String> {
+    let args = parse_args(&[options, &[arg(folder)]].concat());
+    relative_files(
+        folder,
+        get_source_files(&[folder.to_path_buf()], &args).unwrap(),
+    )
+}
+
+#[test]
+fn source_folders_are_read_to_the_maximum_depth() {
+    let dir = tempfile::tempdir().unwrap();
+    for name in ["a.jpg", "2020/b.jpg", "2020/01/c.jpg"] {
+        write_file(&dir.path().join(name), name);
+    }
+    let read = |max_depth| {
+        relative_files(
+            dir.path(),
+            read_source_dir(dir.path(), 1, max_depth, false, false).unwrap(),
+        )
+    };
+
+    assert_eq!(read(Some(1)), ["a.jpg"]);
+    assert_eq!(read(Some(2)), ["2020/b.jpg", "a.jpg"]);
+    assert_eq!(read(None), ["2020/01/c.jpg", "2020/b.jpg", "a.jpg"]);
+    assert_eq!(selected_files(dir.path(), &[]), ["a.jpg"]);
+    assert_eq!(
+        selected_files(dir.path(), &["-r", "--max-depth=2"]),
+        ["2020/b.jpg", "a.jpg"]
+    );
+}
+
+#[test]
+fn recursive_files_are_placed_in_the_flat_destination() {
+    let dir = tempfile::tempdir().unwrap();
+    let (source, destination) = (dir.path().join("src"), dir.path().join("dst"));
+    write_file(&source.join("a.jpg"), "A");
+    write_file(&source.join("2020/01/b.jpg"), "B");
+    fs::create_dir(&destination).unwrap();
+
+    let plan = plan(&parse_args(&[
+        "-r",
+        "-p",
+        "x",
+        "--sort=name",
+        arg(&source),
+        arg(&destination),
+    ]));
+
+    assert!(
+        plan.files
+            .iter()
+            .all(|file| file.destination.parent() == Some(&destination))
+    );
+    assert_eq!(destination_names(&plan), ["x_0.jpg", "x_1.jpg"]);
+}
+
+#[test]
+fn only_images_are_selected_unless_all_files_are() {
+    let dir = tempfile::tempdir().unwrap();
+    for name in [
+        "a.jpg",
+        "b.PNG",
+        "c.heic",
+        "notes.txt",
+        "Thumbs.db",
+        "README",
+    ] {
+        write_file(&dir.path().join(name), name);
+    }
+
+    assert_eq!(
+        selected_files(dir.path(), &[]),
+        ["a.jpg", "b.PNG", "c.heic"]
+    );
+    assert_eq!(
+        selected_files(dir.path(), &["-a"]),
+        [
+            "README",
+            "Thumbs.db",
+            "a.jpg",
+            "b.PNG",
+            "c.heic",
+            "notes.txt"
+        ]
+    );
+}
+
+#[test]
+fn moves_across_devices_copy_and_delete() {
+    let dir = tempfile::tempdir().unwrap();
+    let (source, destination) = (dir.path().join("a.jpg"), dir.path().join("b.jpg"));
+    write_file(&source, "A");
+    set_mtime(&source, 2020, 6, 1);
+    let cross_device = |_: &Path, _: &Path| Err(std::io::ErrorKind::CrossesDevices.into());
+
+    move_file_with(&source, &destination, cross_device).unwrap();
+
+    assert!(!source.exists());
+    assert_eq!(fs::read_to_string(&destination).unwrap(), "A");
+    assert_eq!(
+        modified_date(&destination).unwrap().date().to_string(),
+        "2020-06-01"
+    );
+
+    // A failed copy keeps the source.
+    let missing = dir.path().join("missing/c.jpg");
+    assert!(move_file_with(&destination, &missing, cross_device).is_err());
+    assert_eq!(fs::read_to_string(&destination).unwrap(), "A");
+}
+
+/// Render a template with fixed placeholder values.
+fn render_template(template: &str) -> String {
+    template
+        .parse::<Template>()
+        .unwrap()
+        .render(&TemplateValues {
+            prefix: "trip",
+            separator: "_",
+            index: "07",
+            ext: ".jpg",
+            name: "IMG",
+            dimensions: Some((640, 480)),
+            suffix: Some("edit"),
+        })
+}
+
+#[test]
+fn template_renders_each_placeholder() {
+    assert_eq!(render_template("{prefix}"), "trip");
+    assert_eq!(render_template("a{separator}b"), "a_b");
+    assert_eq!(render_template("{index}"), "07");
+    assert_eq!(render_template("x{ext}"), "x.jpg");
+    assert_eq!(render_template("{name}"), "IMG");
+    assert_eq!(render_template("{width}x{height}"), "640x480");
+    assert_eq!(render_template("x{suffix}"), "x_edit");
+    assert_eq!(
+        render_template("{prefix}{separator}{index}{ext}"),
+        "trip_07.jpg"
+    );
+    assert_eq!(render_template("{index} - {name}{ext}"), "07 - IMG.jpg");
+}
+
+#[test]
+fn malformed_templates_are_rejected() {
+    assert!(
+        "{prefix}_{idx}{ext}"
+            .parse::<Template>()
+            .unwrap_err()
+            .contains("{idx}")
+    );
+    assert!("{prefix_{index}".parse::<Template>().is_err());
+    assert!("{prefix}_{index".parse::<Template>().is_err());
+    assert!("../{prefix}{index}{ext}".parse::<Template>().is_err());
+    assert!("{prefix}/{index}{ext}".parse::<Template>().is_err());
+}
+
+#[test]
+fn existing_destinations_are_only_replaced_with_force() {
+    let (_dir, source, destination) = two_images();
+    write_file(&destination.join("x_0.jpg"), "old");
+    let options = ["-p", "x", "--sort=name", arg(&source), arg(&destination)];
+
+    let summary = run(&parse_args(&options)).unwrap();
+
+    assert_eq!((summary.moved, summary.skipped), (1, 1));
+    assert_eq!(
+        fs::read_to_string(destination.join("x_0.jpg")).unwrap(),
+        "old"
+    );
+    assert_eq!(names(&source), ["a.jpg"]);
+
+    let summary = run(&parse_args(&[&["-f"], &options[..]].concat())).unwrap();
+
+    assert_eq!(summary.moved, 1);
+    assert_eq!(
+        fs::read_to_string(destination.join("x_0.jpg")).unwrap(),
+        "A"
+    );
+    assert!(names(&source).is_empty());
+}
+
+/// Set the modification time of a file to noon on a date.
+fn set_mtime(path: &Path, year: i32, month: u32, day: u32) {
+    let time = NaiveDate::from_ymd_opt(year, month, day)
+        .unwrap()
+        .and_hms_opt(12, 0, 0)
+        .unwrap()
+        .and_local_timezone(chrono::Local)
+        .unwrap();
+    filetime::set_file_mtime(
+        path,
+        filetime::FileTime::from_unix_time(time.timestamp(), 0),
+    )
+    .unwrap();
+}
+
+/// Write a minimal JPEG file with an EXIF `DateTimeOriginal` and `Model`, if given.
+///
+/// Both strings must be longer than 3 bytes, so they are stored outside the IFD entries.
+fn write_exif_jpeg(path: &Path, date: Option<&str>, model: Option<&str>) {
+    fn entry(tiff: &mut Vec<u8>, tag: u16, kind: u16, count: usize, value: usize) {
+        tiff.extend(tag.to_le_bytes());
+        tiff.extend(kind.to_le_bytes());
+        tiff.extend((count as u32).to_le_bytes());
+        tiff.extend((value as u32).to_le_bytes());
+    }
+    const ASCII: u16 = 2;
+    const LONG: u16 = 4;
+    let date = date.map(|date| format!("{}\0", date));
+    let model = model.map(|model| format!("{}\0", model));
+    let ifd0_len = 2 + 12 * (1 + model.iter().len()) + 4;
+    let exif_offset = 8 + ifd0_len;
+    let data_offset = exif_offset + 2 + 12 * date.iter().len() + 4;
+    let date_len = date.as_ref().map_or(0, String::len);
+
+    let mut tiff = b"II*\0".to_vec();
+    tiff.extend(8u32.to_le_bytes());
+    tiff.extend((1 + model.iter().len() as u16).to_le_bytes());
+    if let Some(model) = &model {
+        entry(
+            &mut tiff,
+            0x0110,
+            ASCII,
+            model.len(),
+            data_offset + date_len,
+        );
+    }
+    entry(&mut tiff, 0x8769, LONG, 1, exif_offset);
+    tiff.extend(0u32.to_le_bytes());
+    tiff.extend((date.iter().len() as u16).to_le_bytes());
+    if let Some(date) = &date {
+        entry(&mut tiff, 0x9003, ASCII, date.len(), data_offset);
+    }
+    tiff.extend(0u32.to_le_bytes());
+    tiff.extend(date.iter().chain(&model).flat_map(|value| value.bytes()));
+
+    let mut jpeg = vec![0xff, 0xd8, 0xff, 0xe1];
+    jpeg.extend((2 + 6 + tiff.len() as u16).to_be_bytes());
+    jpeg.extend(b"Exif\0\0");
+    jpeg.extend(tiff);
+    jpeg.extend([0xff, 0xd9]);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, jpeg).unwrap();
+}
+
+#[test]
+fn exif_date_sort_orders_by_capture_date() {
+    let (_dir, source, destination) = two_images();
+    write_exif_jpeg(&source.join("a.jpg"), Some("2021:03:01 10:00:00"), None);
+    write_exif_jpeg(&source.join("b.jpg"), Some("2021:01:01 10:00:00"), None);
+    write_exif_jpeg(&source.join("c.jpg"), None, Some("Camera"));
+    set_mtime(&source.join("c.jpg"), 2021, 2, 1);
+
+    let plan = plan(&parse_args(&[
+        "--sort=exif-date",
+        arg(&source),
+        arg(&destination),
+    ]));
+
+    assert_eq!(
+        exif_date(&source.join("a.jpg")).unwrap().to_string(),
+        "2021-03-01 10:00:00"
+    );
+    assert_eq!(exif_date(&source.join("c.jpg")), None);
+    let sources: Vec<&Path> = plan
+        .files
+        .iter()
+        .map(|file| file.source.strip_prefix(&source).unwrap())
+        .collect();
+    assert_eq!(
+        sources,
+        [Path::new("b.jpg"), Path::new("c.jpg"), Path::new("a.jpg")]
+    );
+}
+
+/// The source file names of a plan, in order.
+fn source_names(plan: &Plan) -> Vec<String> {
+    plan.files
+        .iter()
+        .map(|file| {
+            file.source
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .to_string()
+        })
+        .collect()
+}
+
+#[test]
+fn each_sort_order_is_deterministic() {
+    let dir = tempfile::tempdir().unwrap();
+    let (source, destination) = (dir.path().join("src"), dir.path().join("dst"));
+    for (name, contents, month) in [
+        ("img10.jpg", "1", 3),
+        ("IMG2.jpg", "333", 1),
+        ("img1.jpg", "22", 2),
+    ] {
+        write_file(&source.join(name), contents);
+        set_mtime(&source.join(name), 2020, month, 1);
+    }
+    let sorted = |sort: &str| {
+        source_names(&plan(&parse_args(&[
+            &format!("--sort={}", sort),
+            arg(&source),
+            arg(&destination),
+        ])))
+    };
+
+    assert_eq!(sorted("name"), ["img1.jpg", "IMG2.jpg", "img10.jpg"]);
+    assert_eq!(sorted("mtime"), ["IMG2.jpg", "img1.jpg", "img10.jpg"]);
+    assert_eq!(sorted("size"), ["img10.jpg", "img1.jpg", "IMG2.jpg"]);
+    let mut unsorted = sorted("none");
+    unsorted.sort();
+    assert_eq!(unsorted, ["IMG2.jpg", "img1.jpg", "img10.jpg"]);
+}
+
+#[test]
+fn parallel_copies_create_every_destination() {
+    let dir = tempfile::tempdir().unwrap();
+    let (source, destination) = (dir.path().join("src"), dir.path().join("dst"));
+    for i in 0..300 {
+        write_file(&source.join(format!("{}.jpg", i)), &i.to_string());
+    }
+    fs::create_dir(&destination).unwrap();
+
+    let summary = run(&parse_args(&[
+        "-c",
+        "-j4",
+        "-p",
+        "x",
+        "--sort=name",
+        arg(&source),
+        arg(&destination),
+    ]))
+    .unwrap();
+
+    assert_eq!((summary.copied, summary.failed), (300, 0));
+    for i in 0..300 {
+        let contents = fs::read_to_string(destination.join(format!("x_{:03}.jpg", i))).unwrap();
+        assert_eq!(contents, i.to_string());
+    }
+    assert_eq!(names(&source).len(), 300);
+}
+
+#[test]
+fn progress_is_only_shown_on_a_terminal() {
+    let show = |options: &[&str], is_terminal| {
+        show_progress(&parse_args(&[options, &["src"]].concat()), is_terminal)
+    };
+
+    assert!(show(&[], true));
+    assert!(!show(&[], false));
+    assert!(show(&["--progress"], false));
+    assert!(!show(&["--no-progress"], true));
+    for options in [["-v"], ["-q"], ["-d"], ["--format=json"]] {
+        assert!(!show(&options, true), "{:?}", options);
+    }
+}
+
+#[test]
+fn undo_reverts_moves_and_deletes_copies() {
+    let (_dir, source, destination) = two_images();
+    let undo_log = destination.join(UNDO_LOG_NAME);
+    let undo = |options: &[&str]| {
+        undo_operations(
+            &undo_log,
+            &parse_args(&[options, &[arg(&destination)]].concat()),
+        )
+    };
+
+    run(&parse_args(&["-p", "x", arg(&source), arg(&destination)])).unwrap();
+    assert!(names(&source).is_empty());
+    assert_eq!(fs::read_to_string(&undo_log).unwrap().lines().count(), 2);
+
+    undo(&["-d"]).unwrap();
+    assert!(names(&source).is_empty());
+    undo(&[]).unwrap();
+    assert_eq!(names(&source), ["a.jpg", "b.jpg"]);
+    assert_eq!(fs::read_to_string(source.join("b.jpg")).unwrap(), "B");
+    assert!(names(&destination).is_empty());
+
+    run(&parse_args(&[
+        "-c",
+        "-p",
+        "x",
+        arg(&source),
+        arg(&destination),
+    ]))
+    .unwrap();
+    undo(&[]).unwrap();
+    assert_eq!(names(&source), ["a.jpg", "b.jpg"]);
+    assert!(names(&destination).is_empty());
+}
+
+#[test]
+fn summary_counts_each_outcome() {
+    let (_dir, source, destination) = two_images();
+    write_file(&source.join("c.jpg"), "CC");
+    write_file(&destination.join("x_0.jpg"), "old");
+    let args = parse_args(&["-c", "--sort=name", arg(&source), arg(&destination)]);
+    let fail_b = |operation, source: &Path, destination: &Path| {
+        if source.ends_with("b.jpg") {
+            Err(std::io::Error::other("injected failure"))
+        } else {
+            disk_file_operation(&args)(operation, source, destination)
+        }
+    };
+
+    let summary = move_images(
+        canonicalize_sources(&args).unwrap(),
+        destination.clone(),
+        "x",
+        &args,
+        &fail_b,
+        &(),
+        &MockPrompt::new(None),
+    )
+    .unwrap();
+
+    assert_eq!(
+        summary,
+        Summary {
+            copied: 1,
+            skipped: 1,
+            failed: 1,
+            bytes: 2,
+            ..Summary::default()
+        }
+    );
+    assert_eq!(summary.total(), 3);
+    assert_eq!(
+        fs::read_to_string(destination.join("x_0.jpg")).unwrap(),
+        "old"
+    );
+    assert_eq!(
+        fs::read_to_string(destination.join("x_2.jpg")).unwrap(),
+        "CC"
+    );
+}
+
+#[test]
+fn separators_may_be_empty_but_not_contain_a_path_separator() {
+    for separator in ["_", "-", ""] {
+        assert_eq!(parse_separator(separator).unwrap(), separator);
+    }
+    assert!(parse_separator("/").is_err());
+    assert!(parse_separator("a/b").is_err());
+
+    let (_dir, source, destination) = two_images();
+    let plan = plan(&parse_args(&[
+        "-p",
+        "x",
+        "--sort=name",
+        "--separator=",
+        arg(&source),
+        arg(&destination),
+    ]));
+    assert_eq!(destination_names(&plan), ["x0.jpg", "x1.jpg"]);
+}
+
+#[test]
+fn extensions_are_lowercased_on_request() {
+    let extension = |name: &str, ext_case| {
+        destination_extension(Path::new(name), ext_case, &HashMap::new(), &[], false)
+    };
+
+    assert_eq!(extension("IMG.JPG", ExtCase::Lower), ".jpg");
+    assert_eq!(extension("IMG.Jpeg", ExtCase::Lower), ".jpeg");
+    assert_eq!(extension("IMG", ExtCase::Lower), "");
+    assert_eq!(extension("IMG.JPG", ExtCase::Keep), ".JPG");
+    assert_eq!(extension("IMG.Jpeg", ExtCase::Keep), ".Jpeg");
+
+    let (_dir, source, destination) = two_images();
+    fs::rename(source.join("b.jpg"), source.join("B.JPG")).unwrap();
+    let plan = plan(&parse_args(&[
+        "-p",
+        "X",
+        "--sort=name",
+        "--lowercase-ext",
+        arg(&source),
+        arg(&destination),
+    ]));
+    assert_eq!(destination_names(&plan), ["X_0.jpg", "X_1.jpg"]);
+}
+
+#[test]
+fn keep_name_adds_the_sanitized_stem() {
+    assert_eq!(sanitize_name("IMG 1234"), "IMG_1234");
+    assert_eq!(sanitize_name(" a \t b  "), "a_b");
+    assert_eq!(sanitize_name("a/b"), "a_b");
+
+    let (_dir, source, destination) = two_images();
+    fs::rename(source.join("b.jpg"), source.join("IMG  1234.jpg")).unwrap();
+    let plan = plan(&parse_args(&[
+        "-k",
+        "-p",
+        "vacation",
+        "--sort=name",
+        arg(&source),
+        arg(&destination),
+    ]));
+    assert_eq!(
+        destination_names(&plan),
+        ["vacation_0_a.jpg", "vacation_1_IMG_1234.jpg"]
+    );
+}
+
+#[test]
+fn dedup_keeps_the_first_of_identical_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let files: Vec<PathBuf> = [("a.jpg", "same"), ("b.jpg", "other"), ("c.jpg", "same")]
+        .iter()
+        .map(|(name, contents)| {
+            write_file(&dir.path().join(name), contents);
+            dir.path().join(name)
+        })
+        .collect();
+
+    let kept = remove_duplicates(files.clone(), &parse_args(&["--dedup", "src"])).unwrap();
+    assert_eq!(kept, files[..2]);
+    let all = remove_duplicates(files.clone(), &parse_args(&["src"])).unwrap();
+    assert_eq!(all, files);
+
+    let destination = dir.path().join("dst");
+    let plan = plan(&parse_args(&[
+        "--dedup",
+        "-p",
+        "x",
+        "--sort=name",
+        arg(dir.path()),
+        arg(&destination),
+    ]));
+    assert_eq!(source_names(&plan), ["a.jpg", "b.jpg"]);
+    assert_eq!(destination_names(&plan), ["x_0.jpg", "x_1.jpg"]);
+}
+
+/// A folder with the images `a.jpg`, `b.jpg`, `c_raw.jpg` and `d.png`.
+fn four_images() -> tempfile::TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    for name in ["a.jpg", "b.jpg", "c_raw.jpg", "d.png"] {
+        write_file(&dir.path().join(name), name);
+    }
+    dir
+}
+
+#[test]
+fn exclude_skips_matching_file_names() {
+    let dir = four_images();
+    let selected = |options: &[&str]| selected_files(dir.path(), options);
+
+    assert_eq!(selected(&["-x", "*_raw.*"]), ["a.jpg", "b.jpg", "d.png"]);
+    assert_eq!(
+        selected(&["-x", "*_raw.*", "-x", "*.png"]),
+        ["a.jpg", "b.jpg"]
+    );
+    assert_eq!(
+        selected(&["-x", "*.gif"]),
+        ["a.jpg", "b.jpg", "c_raw.jpg", "d.png"]
+    );
+}
+
+#[test]
+fn include_selects_names_before_exclude_removes_them() {
+    let dir = four_images();
+    let selected = |options: &[&str]| selected_files(dir.path(), options);
+
+    assert_eq!(
+        selected(&["--include", "*.jpg"]),
+        ["a.jpg", "b.jpg", "c_raw.jpg"]
+    );
+    assert_eq!(
+        selected(&["--include", "*.jpg", "-x", "*_raw.*"]),
+        ["a.jpg", "b.jpg"]
+    );
+    assert_eq!(
+        selected(&["--include", "*.jpg", "--include", "d.*", "-x", "b.*"]),
+        ["a.jpg", "c_raw.jpg", "d.png"]
+    );
+}
+
+#[test]
+fn interactive_no_aborts_without_touching_files() {
+    let (_dir, source, destination) = two_images();
+    let args = parse_args(&["-i", arg(&source), arg(&destination)]);
+    let prompt = MockPrompt::new(Some(false));
+
+    let summary = run_with(&args, &prompt).unwrap();
+
+    assert_eq!(prompt.questions(), ["Move 2 files?"]);
+    assert_eq!((summary.moved, summary.skipped), (0, 2));
+    assert_eq!(names(&source), ["a.jpg", "b.jpg"]);
+    assert!(names(&destination).is_empty());
+}
+
+#[test]
+fn interactive_yes_moves_the_files() {
+    let (_dir, source, destination) = two_images();
+    let args = parse_args(&["-i", arg(&source), arg(&destination)]);
+    let prompt = MockPrompt::new(Some(true));
+
+    let summary = run_with(&args, &prompt).unwrap();
+
+    assert_eq!(prompt.questions().len(), 1);
+    assert_eq!(summary.moved, 2);
+    assert!(names(&source).is_empty());
+}
+
+#[test]
+fn interactive_is_skipped_without_a_terminal() {
+    let (_dir, source, destination) = two_images();
+    let args = parse_args(&["-i", arg(&source), arg(&destination)]);
+
+    assert_eq!(run(&args).unwrap().moved, 2);
+}
+
+/// Parse command-line arguments with defaults from the text of a config file.
+fn configured_args(config: &str, args: &[&str]) -> Result<Args, ImgmvError> {
+    use clap::{CommandFactory, FromArgMatches};
+    let matches = Args::command()
+        .try_get_matches_from(std::iter::once("imgmv").chain(args.iter().copied()))
+        .unwrap();
+    let mut parsed = Args::from_arg_matches(&matches).unwrap();
+    let config: Config = toml::from_str(config).map_err(|e| ImgmvError::InvalidConfig {
+        path: PathBuf::from(CONFIG_FILE_NAME),
+        message: e.to_string(),
+    })?;
+    config.apply(Path::new(CONFIG_FILE_NAME), &mut parsed, &matches)?;
+    Ok(parsed)
+}
+
+#[test]
+fn config_values_are_defaults_for_the_command_line() {
+    let config = "copy = true\nprefix = \"cfg\"\nseparator = \"-\"\nsort = \"name\"\n";
+
+    let args = configured_args(config, &["src"]).unwrap();
+    assert!(args.copy);
+    assert_eq!(args.prefix.as_deref(), Some("cfg"));
+    assert_eq!(args.separator, "-");
+    assert_eq!(args.sort, SortOrder::Name);
+
+    let args = configured_args(config, &["-p", "cli", "--separator=_", "src"]).unwrap();
+    assert!(args.copy);
+    assert_eq!(args.prefix.as_deref(), Some("cli"));
+    assert_eq!(args.separator, "_");
+
+    for malformed in [
+        "copy = ",
+        "copy = \"yes\"",
+        "colour = \"never\"",
+        "separator = \"a/b\"",
+        "prefix-from-ancestors = 0",
+    ] {
+        assert!(
+            matches!(
+                configured_args(malformed, &["src"]),
+                Err(ImgmvError::InvalidConfig { .. })
+            ),
+            "{:?}",
+            malformed
+        );
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn symlinks_are_only_followed_on_request() {
+    let dir = tempfile::tempdir().unwrap();
+    let (source, target) = (dir.path().join("src"), dir.path().join("elsewhere/a.jpg"));
+    write_file(&target, "A");
+    write_file(&source.join("b.jpg"), "B");
+    std::os::unix::fs::symlink(&target, source.join("link.jpg")).unwrap();
+    std::os::unix::fs::symlink(dir.path().join("elsewhere"), source.join("folder.jpg")).unwrap();
+    let files = |options: &[&str]| {
+        let args = parse_args(&[options, &[arg(&source)]].concat());
+        let mut files = get_source_files(std::slice::from_ref(&source), &args).unwrap();
+        files.sort();
+        files
+    };
+
+    assert_eq!(files(&[]), [source.join("b.jpg")]);
+    assert_eq!(
+        files(&["--follow-symlinks"]),
+        [target.canonicalize().unwrap(), source.join("b.jpg")]
+    );
+}
+
+#[test]
+fn missing_destinations_are_created_with_make_dest() {
+    let dir = tempfile::tempdir().unwrap();
+    let destination = |options: &[&str], path: &Path| {
+        get_destination_path(&parse_args(&[options, &["src", arg(path)]].concat()))
+    };
+
+    for missing in [dir.path().join("out"), dir.path().join("a/b/out")] {
+        assert!(matches!(
+            destination(&[], &missing),
+            Err(ImgmvError::DestinationNotFound { .. })
+        ));
+        assert_eq!(destination(&["-m", "-d"], &missing).unwrap(), missing);
+        assert!(!missing.exists());
+        assert_eq!(destination(&["-m"], &missing).unwrap(), missing);
+        assert!(missing.is_dir());
+    }
+}
+
+#[test]
+fn natural_sort_compares_numbers_by_value() {
+    let mut names = [
+        "IMG_10.jpg",
+        "img_2.jpg",
+        "IMG_1.jpg",
+        "IMG_002.jpg",
+        "IMG_100.jpg",
+    ];
+    let mut lexical = names;
+    lexical.sort();
+    names.sort_by(|a, b| natural_cmp(a, b));
+
+    assert_eq!(
+        lexical,
+        [
+            "IMG_002.jpg",
+            "IMG_1.jpg",
+            "IMG_10.jpg",
+            "IMG_100.jpg",
+            "img_2.jpg"
+        ]
+    );
+    assert_eq!(
+        names,
+        [
+            "IMG_1.jpg",
+            "img_2.jpg",
+            "IMG_002.jpg",
+            "IMG_10.jpg",
+            "IMG_100.jpg"
+        ]
+    );
+    assert_eq!(parse_args(&["--sort=natural", "src"]).sort, SortOrder::Name);
+}
+
+#[test]
+fn reverse_numbers_the_last_file_first() {
+    let (_dir, source, destination) = two_images();
+    write_file(&source.join("c.jpg"), "");
+    write_file(&source.join("img10.jpg"), "10");
+    let plan = |options: &[&str]| {
+        plan(&parse_args(
+            &[
+                options,
+                &["--allow-empty", "-p", "x", arg(&source), arg(&destination)],
+            ]
+            .concat(),
+        ))
+    };
+
+    let reversed = plan(&["--sort=name", "--reverse"]);
+    let pairs: Vec<(String, String)> = source_names(&reversed)
+        .into_iter()
+        .zip(destination_names(&reversed))
+        .collect();
+    assert_eq!(
+        pairs,
+        [
+            ("img10.jpg", "x_0.jpg"),
+            ("c.jpg", "x_1.jpg"),
+            ("b.jpg", "x_2.jpg"),
+            ("a.jpg", "x_3.jpg")
+        ]
+        .map(|(source, destination)| (source.to_string(), destination.to_string()))
+    );
+    assert_eq!(
+        source_names(&plan(&["--sort=size", "--reverse"])),
+        ["img10.jpg", "b.jpg", "a.jpg", "c.jpg"]
+    );
+}
+
+#[test]
+fn several_sources_are_numbered_together() {
+    let dir = tempfile::tempdir().unwrap();
+    let (first, second, destination) = (
+        dir.path().join("day1"),
+        dir.path().join("day2"),
+        dir.path().join("dst"),
+    );
+    write_file(&first.join("b.jpg"), "B");
+    write_file(&first.join("a.jpg"), "A");
+    write_file(&second.join("c.jpg"), "C");
+    let paths = [arg(&first), arg(&second), arg(&destination)];
+
+    let plan = plan(&parse_args(
+        &[&["-p", "trip", "--sort=name"], &paths[..]].concat(),
+    ));
+    assert_eq!(source_names(&plan), ["a.jpg", "b.jpg", "c.jpg"]);
+    assert_eq!(
+        destination_names(&plan),
+        ["trip_0.jpg", "trip_1.jpg", "trip_2.jpg"]
+    );
+
+    match get_prefix(&parse_args(&paths)) {
+        Err(ImgmvError::AmbiguousPrefix(names)) => assert_eq!(names, ["day1", "day2"]),
+        result => panic!("expected AmbiguousPrefix, got {:?}", result),
+    }
+    let same_name = dir.path().join("other/day1");
+    fs::create_dir_all(&same_name).unwrap();
+    assert_eq!(
+        get_prefix(&parse_args(&[
+            arg(&first),
+            arg(&same_name),
+            arg(&destination)
+        ]))
+        .unwrap(),
+        "day1"
+    );
+}
+
+#[test]
+fn sizes_are_parsed_and_filter_the_files() {
+    assert_eq!(parse_size("512"), Ok(512));
+    assert_eq!(parse_size("10k"), Ok(10 << 10));
+    assert_eq!(parse_size("5M"), Ok(5 << 20));
+    assert_eq!(parse_size("2 GiB"), Ok(2 << 30));
+    assert_eq!(parse_size("1KB"), Ok(1 << 10));
+    assert_eq!(parse_size("64b"), Ok(64));
+    for invalid in [
+        "", "k", "1.5M", "-1", "10x", "1bbb", "10kbbb", "10kibib", "5ib", "1bk",
+    ] {
+        assert!(parse_size(invalid).is_err(), "{:?}", invalid);
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    for (name, size) in [("tiny.jpg", 10), ("small.jpg", 1024), ("big.jpg", 4096)] {
+        write_file(&dir.path().join(name), &"x".repeat(size));
+    }
+    let selected = |options: &[&str]| selected_files(dir.path(), options);
+    assert_eq!(selected(&["--min-size=1k"]), ["big.jpg", "small.jpg"]);
+    assert_eq!(selected(&["--max-size=1k"]), ["small.jpg", "tiny.jpg"]);
+    assert_eq!(
+        selected(&["--min-size=100", "--max-size=2k"]),
+        ["small.jpg"]
+    );
+}
+
+#[test]
+fn ext_selects_only_the_listed_extensions() {
+    assert_eq!(parse_extension("cr2"), Ok("cr2".to_string()));
+    assert_eq!(parse_extension(".cr2"), Ok("cr2".to_string()));
+    assert!(parse_extension(".").is_err());
+
+    let dir = tempfile::tempdir().unwrap();
+    for name in ["a.CR2", "b.nef", "c.jpg", "d.txt"] {
+        write_file(&dir.path().join(name), name);
+    }
+    let selected = |options: &[&str]| selected_files(dir.path(), options);
+    assert_eq!(selected(&["-e", "cr2"]), ["a.CR2"]);
+    assert_eq!(selected(&["-e", ".cr2"]), ["a.CR2"]);
+    assert_eq!(selected(&["-e", "cr2", "--ext=.NEF"]), ["a.CR2", "b.nef"]);
+    assert_eq!(selected(&["-e", "txt"]), ["d.txt"]);
+}
+
+#[test]
+fn copies_keep_the_modification_time() {
+    let (_dir, source, destination) = two_images();
+    set_mtime(&source.join("a.jpg"), 2019, 7, 14);
+
+    run(&parse_args(&[
+        "-c",
+        "-p",
+        "x",
+        "--sort=name",
+        arg(&source),
+        arg(&destination),
+    ]))
+    .unwrap();
+
+    let modified = |path: &Path| fs::metadata(path).unwrap().modified().unwrap();
+    let (original, copied) = (
+        modified(&source.join("a.jpg")),
+        modified(&destination.join("x_0.jpg")),
+    );
+    let difference = copied
+        .duration_since(original)
+        .unwrap_or_else(|e| e.duration());
+    assert!(
+        difference < std::time::Duration::from_secs(1),
+        "{:?}",
+        difference
+    );
+}
+
+#[test]
+fn verified_copies_match_or_are_removed() {
+    let (_dir, source, destination) = two_images();
+
+    let summary = run(&parse_args(&[
+        "-c",
+        "--verify",
+        "-p",
+        "x",
+        "--sort=name",
+        arg(&source),
+        arg(&destination),
+    ]))
+    .unwrap();
+    assert_eq!((summary.copied, summary.failed), (2, 0));
+    verify_copy(&source.join("a.jpg"), &destination.join("x_0.jpg")).unwrap();
+
+    let error = verify_copy(&source.join("a.jpg"), &destination.join("x_1.jpg")).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    assert!(!destination.join("x_1.jpg").exists());
+}
+
+#[test]
+fn by_date_places_files_in_month_folders() {
+    let dir = tempfile::tempdir().unwrap();
+    let (source, destination) = (dir.path().join("src"), dir.path().join("dst"));
+    for (name, month, day) in [("a.jpg", 1, 5), ("b.jpg", 2, 1), ("c.jpg", 1, 20)] {
+        write_file(&source.join(name), name);
+        set_mtime(&source.join(name), 2024, month, day);
+    }
+    fs::create_dir(&destination).unwrap();
+
+    let args = parse_args(&[
+        "--by-date",
+        "-p",
+        "x",
+        "--sort=name",
+        arg(&source),
+        arg(&destination),
+    ]);
+    run(&args).unwrap();
+
+    assert_eq!(
+        names(&destination.join("2024").join("01")),
+        ["x_0.jpg", "x_1.jpg"]
+    );
+    assert_eq!(names(&destination.join("2024").join("02")), ["x_0.jpg"]);
+    assert_eq!(
+        fs::read_to_string(destination.join("2024").join("02").join("x_0.jpg")).unwrap(),
+        "b.jpg"
+    );
+}
+
+#[test]
+fn date_formats_must_stay_in_the_destination() {
+    assert_eq!(parse_date_format("%Y/%m-%d"), Ok("%Y/%m-%d".to_string()));
+    assert!(parse_date_format("/tmp/escape/%Y").is_err());
+    assert!(parse_date_format("../../%Y").is_err());
+    assert!(parse_date_format("%Y/../%m").is_err());
+    assert!(parse_date_format("%Q").is_err());
+}
+
+#[test]
+fn colliding_destinations_get_a_counter() {
+    let mut used = UsedDestinations {
+        paths: HashSet::new(),
+        case_insensitive: false,
+    };
+    let names: Vec<PathBuf> = (0..3)
+        .map(|_| disambiguate(PathBuf::from("out/x.jpg"), &mut used))
+        .collect();
+    assert_eq!(
+        names,
+        ["out/x.jpg", "out/x-1.jpg", "out/x-2.jpg"].map(PathBuf::from)
+    );
+
+    let dir = tempfile::tempdir().unwrap();
+    for name in ["a.jpg", "b.jpg", "c.jpg"] {
+        write_file(&dir.path().join("src").join(name), name);
+    }
+    let plan = plan(&parse_args(&[
+        "-t",
+        "{prefix}{ext}",
+        "-p",
+        "x",
+        "--sort=name",
+        arg(&dir.path().join("src")),
+        arg(&dir.path().join("dst")),
+    ]));
+    assert_eq!(destination_names(&plan), ["x.jpg", "x-1.jpg", "x-2.jpg"]);
+}
+
+#[test]
+fn hidden_files_are_only_selected_on_request() {
+    let dir = tempfile::tempdir().unwrap();
+    for name in ["a.jpg", ".b.jpg", ".thumbnails/c.jpg"] {
+        write_file(&dir.path().join(name), name);
+    }
+
+    assert_eq!(selected_files(dir.path(), &["-r"]), ["a.jpg"]);
+    assert_eq!(
+        selected_files(dir.path(), &["-r", "--hidden"]),
+        [".b.jpg", ".thumbnails/c.jpg", "a.jpg"]
+    );
+}
+
+/// Read the status column of a CSV report.
+fn report_statuses(path: &Path) -> Vec<String> {
+    csv::Reader::from_path(path)
+        .unwrap()
+        .records()
+        .map(|record| record.unwrap()[3].to_string())
+        .collect()
+}
+
+#[test]
+fn report_marks_planned_files_in_dry_run() {
+    let dir = tempfile::tempdir().unwrap();
+    let (source, destination) = (dir.path().join("src"), dir.path().join("dst"));
+    write_file(&source.join("a, first.jpg"), "A");
+    write_file(&source.join("b.jpg"), "B");
+    write_file(&destination.join("x_0.jpg"), "old");
+    let report = dir.path().join("report.csv");
+
+    let args = parse_args(&[
+        "-d",
+        "-p",
+        "x",
+        "--sort=name",
+        "--report",
+        arg(&report),
+        arg(&source),
+        arg(&destination),
+    ]);
+    run(&args).unwrap();
+
+    assert_eq!(report_statuses(&report), ["skipped", "would-move"]);
+    let first = csv::Reader::from_path(&report)
+        .unwrap()
+        .records()
+        .next()
+        .unwrap()
+        .unwrap();
+    assert_eq!(&first[0], arg(&source.join("a, first.jpg")));
+    assert!(source.join("b.jpg").exists());
+}
+
+#[test]
+fn continue_numbers_after_the_highest_existing_index() {
+    let (_dir, source, destination) = two_images();
+    for name in [
+        "x_3.jpg",
+        "x_07.png",
+        "x_9_edit.jpg",
+        "y_20.jpg",
+        "x_.jpg",
+        "notes.txt",
+    ] {
+        write_file(&destination.join(name), name);
+    }
+
+    let args = parse_args(&[arg(&source)]);
+    assert_eq!(next_free_index(&destination, "x", &args), Some(8));
+    assert_eq!(next_free_index(&destination, "y", &args), Some(21));
+    assert_eq!(next_free_index(&destination, "z", &args), None);
+    let plan = plan(&parse_args(&[
+        "--continue",
+        "-p",
+        "x",
+        "--sort=name",
+        arg(&source),
+        arg(&destination),
+    ]));
+    assert_eq!(destination_names(&plan), ["x_8.jpg", "x_9.jpg"]);
+}
+
+#[test]
+fn continue_recognizes_names_written_with_the_naming_options() {
+    let (_dir, source, destination) = two_images();
+    for name in [
+        "x_3_END.jpg",
+        "x_5.jpg",
+        "x_b.jpg",
+        "x_ab.jpg",
+        "x_1_beach.jpg",
+    ] {
+        write_file(&destination.join(name), name);
+    }
+    let next = |options: &[&str]| {
+        let args = parse_args(&[options, &[arg(&source)]].concat());
+        next_free_index(&destination, "x", &args)
+    };
+
+    assert_eq!(next(&[]), Some(6));
+    assert_eq!(next(&["--suffix=END"]), Some(4));
+    assert_eq!(next(&["--index-style=alpha"]), Some(28));
+    assert_eq!(next(&["--keep-name"]), Some(4));
+    assert_eq!(next(&["--template=IMG{separator}{index}{ext}"]), None);
+
+    write_file(&destination.join("trip_7.jpg"), "");
+    assert_eq!(next(&["--lowercase-name"]), Some(6));
+    let args = parse_args(&["--lowercase-name", arg(&source)]);
+    assert_eq!(next_free_index(&destination, "Trip", &args), Some(8));
+}
+
+#[test]
+fn prefixes_are_sanitized_for_file_names() {
+    assert_eq!(sanitize_prefix("Summer  Trip", Some('_')), "Summer_Trip");
+    assert_eq!(sanitize_prefix("12:30 lunch", Some('_')), "12_30_lunch");
+    assert_eq!(sanitize_prefix("a: b", Some('_')), "a_b");
+    assert_eq!(sanitize_prefix("Ærø 日本 😀", Some('_')), "Ærø_日本_😀");
+    assert_eq!(sanitize_prefix("a\tb*?c", Some('-')), "a-b_c");
+    assert_eq!(sanitize_prefix("Summer  Trip", None), "Summer  Trip");
+    assert_eq!(sanitize_prefix("a\tb*?c", None), "a_b_c");
+
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("Kyoto: 京都 2024");
+    fs::create_dir(&source).unwrap();
+    assert_eq!(
+        get_prefix(&parse_args(&[arg(&source), arg(dir.path())])).unwrap(),
+        "Kyoto_ 京都 2024"
+    );
+    assert_eq!(
+        get_prefix(&parse_args(&[
+            "--space-replacement=_",
+            arg(&source),
+            arg(dir.path())
+        ]))
+        .unwrap(),
+        "Kyoto_京都_2024"
+    );
+    assert_eq!(
+        get_prefix(&parse_args(&["-p", "my trip", arg(&source)])).unwrap(),
+        "my trip"
+    );
+}
+
+#[test]
+fn source_lists_are_read_line_by_line_or_by_nul() {
+    let (_dir, source, _) = two_images();
+    let (a, b) = (source.join("a.jpg"), source.join("b.jpg"));
+    let missing = source.join("missing.jpg");
+
+    let lines = format!(
+        "{}\r\n\n{}\n{}\n{}",
+        arg(&a),
+        arg(&missing),
+        arg(&source),
+        arg(&b)
+    );
+    assert_eq!(
+        read_source_list(lines.as_bytes(), false).unwrap(),
+        [a.clone(), b.clone()]
+    );
+    let nul = format!("{}\0{}\0", arg(&b), arg(&a));
+    assert_eq!(read_source_list(nul.as_bytes(), true).unwrap(), [b, a]);
+    assert!(read_source_list(&b""[..], false).unwrap().is_empty());
+}
+
+#[test]
+fn tree_shows_placed_files_by_folder() {
+    let dir = tempfile::tempdir().unwrap();
+    let (source, destination) = (dir.path().join("src"), dir.path().join("dst"));
+    write_file(&source.join("a.jpg"), "A");
+    write_file(&source.join("sub").join("b.jpg"), "B");
+    write_file(&source.join("c.jpg"), "C");
+    write_file(&destination.join("x_1.jpg"), "old");
+
+    let plan = plan(&parse_args(&[
+        "-r",
+        "--preserve-structure",
+        "-p",
+        "x",
+        "--sort=name",
+        arg(&source),
+        arg(&destination),
+    ]));
+    let tree = render_tree(&destination, &plan.files);
+
+    assert_eq!(
+        tree,
+        format!(
+            "{}\n├── sub/\n│   └── x_0.jpg <- {:?}\n└── x_0.jpg <- {:?}\n",
+            destination.display(),
+            source.join("sub").join("b.jpg"),
+            source.join("a.jpg"),
+        )
+    );
+}
+
+#[test]
+fn date_bounds_are_inclusive_and_optional() {
+    let dir = tempfile::tempdir().unwrap();
+    write_exif_jpeg(
+        &dir.path().join("jan.jpg"),
+        Some("2024:01:15 08:00:00"),
+        None,
+    );
+    write_exif_jpeg(
+        &dir.path().join("feb.jpg"),
+        Some("2024:02:01 23:59:59"),
+        None,
+    );
+    write_file(&dir.path().join("mar.jpg"), "no EXIF");
+    set_mtime(&dir.path().join("mar.jpg"), 2024, 3, 10);
+    let selected = |options: &[&str]| selected_files(dir.path(), options);
+
+    assert_eq!(selected(&[]), ["feb.jpg", "jan.jpg", "mar.jpg"]);
+    assert_eq!(selected(&["--after=2024-02-01"]), ["feb.jpg", "mar.jpg"]);
+    assert_eq!(selected(&["--before=2024-02-01"]), ["feb.jpg", "jan.jpg"]);
+    assert_eq!(
+        selected(&["--after=2024-01-16", "--before=2024-03-09"]),
+        ["feb.jpg"]
+    );
+    assert_eq!(
+        selected(&["--after=2024-03-10", "--before=2024-03-10"]),
+        ["mar.jpg"]
+    );
+    assert!(selected(&["--after=2024-04-01"]).is_empty());
+}
+
+/// Write a PNG image of a size, with a pattern that varies with the seed.
+fn write_png(path: &Path, width: u32, height: u32, seed: u32) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    image::RgbImage::from_fn(width, height, |x, y| {
+        let value = ((x * 255 / width) ^ (y * 255 / height).wrapping_mul(seed)) as u8;
+        image::Rgb([value, value.wrapping_add(seed as u8), 255 - value])
+    })
+    .save(path)
+    .unwrap();
+}
+
+#[test]
+fn dimensions_are_read_from_the_images() {
+    let dir = tempfile::tempdir().unwrap();
+    let (source, destination) = (dir.path().join("src"), dir.path().join("dst"));
+    write_png(&source.join("a.png"), 4, 3, 1);
+    write_png(&source.join("b.png"), 2, 50, 1);
+    write_file(&source.join("c.png"), "not a PNG");
+
+    assert_eq!(image_dimensions(&source.join("a.png")), Some((4, 3)));
+    assert_eq!(image_dimensions(&source.join("c.png")), None);
+    let plan = plan(&parse_args(&[
+        "-t",
+        "{prefix}{separator}{index}_{width}x{height}{ext}",
+        "-p",
+        "x",
+        "--sort=name",
+        arg(&source),
+        arg(&destination),
+    ]));
+    assert_eq!(
+        destination_names(&plan),
+        ["x_0_4x3.png", "x_1_2x50.png", "x_2_x.png"]
+    );
+}
+
+#[test]
+fn in_place_renames_stage_the_files_in_the_way() {
+    let dir = tempfile::tempdir().unwrap();
+    for (name, contents) in [("a.jpg", "A"), ("d_0.jpg", "Z"), ("d_1.jpg", "Y")] {
+        write_file(&dir.path().join(name), contents);
+    }
+    let (first, second) = (dir.path().join("d_0.jpg"), dir.path().join("d_1.jpg"));
+
+    let staged = stage_files(&HashSet::from([&first, &second])).unwrap();
+    assert!(!first.exists() && !second.exists());
+    assert_eq!(fs::read_to_string(&staged[&first]).unwrap(), "Z");
+    for (file, staged_file) in &staged {
+        assert_eq!(unstage_file(staged_file, file), None);
+    }
+    assert_eq!(names(dir.path()), ["a.jpg", "d_0.jpg", "d_1.jpg"]);
+
+    let folder = arg(dir.path());
+    let summary = run(&parse_args(&["-p", "d", "--sort=name", folder, folder])).unwrap();
+
+    assert_eq!((summary.moved, summary.failed), (3, 0));
+    let contents: Vec<String> = ["d_0.jpg", "d_1.jpg", "d_2.jpg"]
+        .iter()
+        .map(|name| fs::read_to_string(dir.path().join(name)).unwrap())
+        .collect();
+    assert_eq!(contents, ["A", "Z", "Y"]);
+    assert_eq!(
+        names(dir.path()),
+        [".imgmv-undo.jsonl", "d_0.jpg", "d_1.jpg", "d_2.jpg"]
+    );
+}
+
+#[test]
+fn build_plan_names_and_moves_by_default() {
+    let dir = tempfile::tempdir().unwrap();
+    let (source, destination) = (dir.path().join("trip"), dir.path().join("dst"));
+    for name in ["b.jpg", "a.jpg", "notes.txt"] {
+        write_file(&source.join(name), name);
+    }
+    fs::create_dir(&destination).unwrap();
+
+    let plan = plan(&parse_args(&[
+        "--sort=name",
+        arg(&source),
+        arg(&destination),
+    ]));
+
+    assert_eq!(plan.operation, Operation::Move);
+    assert_eq!(destination_names(&plan), ["trip_0.jpg", "trip_1.jpg"]);
+    assert_eq!(plan.files[0].source, source.join("a.jpg"));
+    assert!(
+        plan.files
+            .iter()
+            .all(|file| file.action == Action::Create && !file.stage)
+    );
+    assert!(!destination.join("trip_0.jpg").exists());
+}
+
+#[test]
+fn build_plan_with_copy_prefix_and_reverse() {
+    let dir = tempfile::tempdir().unwrap();
+    for name in ["a.jpg", "b.jpg", "c.png"] {
+        write_file(&dir.path().join("src").join(name), name);
+    }
+    let (source, destination) = (dir.path().join("src"), dir.path().join("dst"));
+    fs::create_dir(&destination).unwrap();
+
+    let plan = plan(&parse_args(&[
+        "-c",
+        "-p",
+        "x",
+        "--sort=name",
+        "--reverse",
+        arg(&source),
+        arg(&destination),
+    ]));
+
+    assert_eq!(plan.operation, Operation::Copy);
+    assert_eq!(destination_names(&plan), ["x_0.png", "x_1.jpg", "x_2.jpg"]);
+    assert_eq!(plan.files[0].source, source.join("c.png"));
+}
+
+#[test]
+fn build_plan_handles_existing_destinations() {
+    let dir = tempfile::tempdir().unwrap();
+    let (source, destination) = (dir.path().join("src"), dir.path().join("dst"));
+    write_file(&source.join("a.jpg"), "A");
+    write_file(&source.join("b.jpg"), "B");
+    write_file(&destination.join("x_0.jpg"), "old");
+    let paths = [arg(&source), arg(&destination)];
+
+    let skip = plan(&parse_args(&["-p", "x", "--sort=name", paths[0], paths[1]]));
+    let overwrite = plan(&parse_args(&[
+        "-f",
+        "-p",
+        "x",
+        "--sort=name",
+        paths[0],
+        paths[1],
+    ]));
+    let rename = plan(&parse_args(&[
+        "--collision-strategy=rename",
+        "-p",
+        "x",
+        "--sort=name",
+        paths[0],
+        paths[1],
+    ]));
+
+    let actions = |plan: &Plan| {
+        plan.files
+            .iter()
+            .map(|file| file.action)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(actions(&skip), [Action::Skip, Action::Create]);
+    assert_eq!(actions(&overwrite), [Action::Overwrite, Action::Create]);
+    assert_eq!(actions(&rename), [Action::Create, Action::Create]);
+    assert_eq!(destination_names(&rename), ["x_0-1.jpg", "x_1.jpg"]);
+}
+
+/// A folder with `a.jpg`, `b.jpg` and `d_0.jpg`, where `d_0.jpg` is renamed in place to make
+/// room for `a.jpg`, and a folder `d_1.jpg` in the way of `b.jpg`.
+fn in_place_failure() -> tempfile::TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    write_file(&dir.path().join("a.jpg"), "A");
+    write_file(&dir.path().join("b.jpg"), "B");
+    write_file(&dir.path().join("d_0.jpg"), "Z");
+    fs::create_dir(dir.path().join("d_1.jpg")).unwrap();
+    dir
+}
+
+#[test]
+fn build_plan_stages_sources_renamed_in_place() {
+    let dir = in_place_failure();
+    let folder = arg(dir.path());
+
+    let plan = plan(&parse_args(&["-p", "d", "--sort=name", folder, folder]));
+
+    assert_eq!(destination_names(&plan), ["d_0.jpg", "d_1.jpg", "d_2.jpg"]);
+    let staged: Vec<bool> = plan.files.iter().map(|file| file.stage).collect();
+    assert_eq!(staged, [false, false, true]);
+}
+
+#[test]
+fn interactive_questions_count_only_the_handled_files() {
+    let (_dir, source, destination) = two_images();
+    write_file(&destination.join("x_1.jpg"), "old");
+    let args = parse_args(&[
+        "-i",
+        "-p",
+        "x",
+        "--sort=name",
+        "--collision-strategy=skip",
+        arg(&source),
+        arg(&destination),
+    ]);
+    let prompt = MockPrompt::new(Some(false));
+
+    let summary = run_with(&args, &prompt).unwrap();
+
+    assert_eq!(prompt.questions(), ["Move 1 files?"]);
+    assert_eq!(summary.total(), 2);
+    assert_eq!((summary.moved, summary.skipped), (0, 2));
+    assert_eq!(names(&source), ["a.jpg", "b.jpg"]);
+}
+
+#[test]
+fn ignore_files_exclude_matching_files() {
+    let dir = tempfile::tempdir().unwrap();
+    for name in ["a.jpg", "b_raw.jpg", "c.jpg", "old/d.jpg"] {
+        write_file(&dir.path().join(name), name);
+    }
+    assert!(read_ignore_file(dir.path()).unwrap().is_none());
+
+    write_file(
+        &dir.path().join(IGNORE_FILE_NAME),
+        "# raw exports\n*_raw.jpg\n\n  old/*  \n# c.jpg\n",
+    );
+    let ignored = read_ignore_file(dir.path()).unwrap().unwrap();
+    assert_eq!(ignored.len(), 2);
+    assert!(ignored.is_match("b_raw.jpg") && !ignored.is_match("c.jpg"));
+    assert_eq!(selected_files(dir.path(), &["-r"]), ["a.jpg", "c.jpg"]);
+
+    write_file(&dir.path().join(IGNORE_FILE_NAME), "[a\n");
+    assert!(matches!(
+        read_ignore_file(dir.path()),
+        Err(ImgmvError::InvalidIgnoreFile { .. })
+    ));
+}
+
+#[test]
+fn max_files_takes_the_first_files_in_sort_order() {
+    let dir = four_images();
+    let destination = dir.path().join("dst");
+    let plan = |max_files: &str| {
+        plan(&parse_args(&[
+            "--max-files",
+            max_files,
+            "-p",
+            "x",
+            "--sort=name",
+            arg(dir.path()),
+            arg(&destination),
+        ]))
+    };
+
+    assert_eq!(source_names(&plan("2")), ["a.jpg", "b.jpg"]);
+    assert_eq!(
+        destination_names(&plan("3")),
+        ["x_0.jpg", "x_1.jpg", "x_2.jpg"]
+    );
+    assert_eq!(plan("4").files.len(), 4);
+    assert_eq!(plan("10").files.len(), 4);
+    assert!(Args::try_parse_from(["imgmv", "--max-files=0", "src"]).is_err());
+}
+
+#[test]
+fn perceptual_dedup_skips_resized_copies() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = |name: &str| dir.path().join(name);
+    write_png(&path("a.png"), 64, 64, 1);
+    image::open(path("a.png"))
+        .unwrap()
+        .resize_exact(48, 40, image::imageops::FilterType::Triangle)
+        .save(path("b.png"))
+        .unwrap();
+    write_png(&path("c.png"), 64, 64, 7);
+    image::RgbImage::from_fn(64, 64, |x, _| image::Rgb([if x < 32 { 0 } else { 255 }; 3]))
+        .save(path("d.png"))
+        .unwrap();
+    write_file(&path("e.jpg"), "not an image");
+    let files: Vec<PathBuf> = ["a.png", "b.png", "c.png", "d.png", "e.jpg"]
+        .map(path)
+        .to_vec();
+
+    let kept =
+        remove_duplicates(files.clone(), &parse_args(&["--dedup-perceptual", "src"])).unwrap();
+
+    assert_eq!(kept, [&files[..1], &files[2..]].concat());
+}
+
+#[test]
+fn strict_failure_renames_staged_files_back() {
+    let dir = in_place_failure();
+    let folder = arg(dir.path());
+    let args = parse_args(&[
+        "-f",
+        "--strict",
+        "-j1",
+        "--sort=name",
+        "-p",
+        "d",
+        folder,
+        folder,
+    ]);
+
+    let summary = run(&args).unwrap();
+
+    assert_eq!((summary.moved, summary.skipped, summary.failed), (1, 1, 1));
+    assert_eq!(
+        names(dir.path()),
+        [
+            ".imgmv-undo.jsonl",
+            "b.jpg",
+            "d_0-1.jpg",
+            "d_0.jpg",
+            "d_1.jpg"
+        ]
+    );
+    assert_eq!(
+        fs::read_to_string(dir.path().join("d_0-1.jpg")).unwrap(),
+        "Z"
+    );
+    assert_eq!(fs::read_to_string(dir.path().join("d_0.jpg")).unwrap(), "A");
+
+    undo_operations(&dir.path().join(UNDO_LOG_NAME), &parse_args(&[folder])).unwrap();
+    assert_eq!(names(dir.path()), ["a.jpg", "b.jpg", "d_0.jpg", "d_1.jpg"]);
+    assert_eq!(fs::read_to_string(dir.path().join("d_0.jpg")).unwrap(), "Z");
+}
+
+#[test]
+fn preserve_structure_mirrors_the_source_folders() {
+    let dir = tempfile::tempdir().unwrap();
+    let (source, destination) = (dir.path().join("src"), dir.path().join("dst"));
+    for name in [
+        "a.jpg",
+        "2023/b.jpg",
+        "2023/c.jpg",
+        "2023/07/d.jpg",
+        "2024/e.jpg",
+    ] {
+        write_file(&source.join(name), name);
+    }
+
+    let plan = plan(&parse_args(&[
+        "-r",
+        "--preserve-structure",
+        "-p",
+        "x",
+        "--sort=name",
+        arg(&source),
+        arg(&destination),
+    ]));
+
+    let destinations = relative_files(
+        &destination,
+        plan.files
+            .iter()
+            .map(|file| file.destination.clone())
+            .collect(),
+    );
+    assert_eq!(
+        destinations,
+        [
+            "2023/07/x_0.jpg",
+            "2023/x_0.jpg",
+            "2023/x_1.jpg",
+            "2024/x_0.jpg",
+            "x_0.jpg"
+        ]
+    );
+    let d = plan
+        .files
+        .iter()
+        .find(|file| file.source.ends_with("07/d.jpg"))
+        .unwrap();
+    assert_eq!(d.destination, destination.join("2023/07/x_0.jpg"));
+}
+
+#[test]
+fn existing_destinations_are_listed_and_fail_the_check() {
+    let (_dir, source, destination) = two_images();
+    let options = ["-p", "x", "--sort=name", arg(&source), arg(&destination)];
+
+    assert_eq!(render_collisions(&plan(&parse_args(&options))), "");
+    assert!(run(&parse_args(&[&["--check"], &options[..]].concat())).is_ok());
+    write_file(&destination.join("x_0.jpg"), "old");
+    write_file(&destination.join("x_1.jpg"), "old");
+
+    assert_eq!(
+        render_collisions(&plan(&parse_args(&options))),
+        format!(
+            "Already exist:\n  {:?} (skipped)\n  {:?} (skipped)\n",
+            destination.join("x_0.jpg"),
+            destination.join("x_1.jpg")
+        )
+    );
+    let forced = plan(&parse_args(&[&["-f"], &options[..]].concat()));
+    assert!(render_collisions(&forced).ends_with(&format!("{:?}\n", destination.join("x_1.jpg"))));
+    let result = run(&parse_args(&[&["--check"], &options[..]].concat()));
+    assert!(matches!(result, Err(ImgmvError::Collisions(2))));
+    assert_eq!(names(&source), ["a.jpg", "b.jpg"]);
+}
+
+#[test]
+fn date_prefix_formats_the_capture_date() {
+    let (_dir, source, destination) = two_images();
+    write_exif_jpeg(&source.join("a.jpg"), Some("2023:07:15 14:30:22"), None);
+    write_exif_jpeg(&source.join("b.jpg"), Some("2023:07:16 09:05:00"), None);
+    let names = |options: &[&str]| {
+        destination_names(&plan(&parse_args(
+            &[options, &["--sort=name", arg(&source), arg(&destination)]].concat(),
+        )))
+    };
+
+    assert_eq!(
+        names(&["--date-prefix=%Y%m%d_%H%M%S"]),
+        ["20230715_143022_0.jpg", "20230716_090500_1.jpg"]
+    );
+    assert_eq!(
+        names(&["--date-prefix=%Y-%m-%d", "-p", "trip"]),
+        ["2023-07-15_trip_0.jpg", "2023-07-16_trip_1.jpg"]
+    );
+}
+
+#[test]
+fn glob_sources_match_a_subset_of_files() {
+    assert_eq!(glob_base(Path::new("trip/*.jpg")), Path::new("trip"));
+    assert_eq!(
+        glob_base(Path::new("a/b/IMG_?[0-9]/*.jpg")),
+        Path::new("a/b")
+    );
+    assert_eq!(glob_base(Path::new("*.jpg")), Path::new(""));
+    assert!(is_glob(Path::new("trip/*.jpg")) && !is_glob(Path::new("trip/a.jpg")));
+
+    let dir = tempfile::tempdir().unwrap();
+    let trip = dir.path().join("trip");
+    for name in [
+        "IMG_1.jpg",
+        "IMG_2.png",
+        "IMG_3.jpg",
+        ".IMG_4.jpg",
+        "sub/IMG_5.jpg",
+    ] {
+        write_file(&trip.join(name), name);
+    }
+    let pattern = trip.join("IMG_*.jpg");
+    assert_eq!(
+        relative_files(&trip, expand_glob(&pattern, false, false).unwrap()),
+        ["IMG_1.jpg", "IMG_3.jpg"]
+    );
+    assert!(expand_glob(Path::new("[a"), false, false).is_err());
+
+    let args = parse_args(&[arg(&pattern), arg(dir.path())]);
+    assert_eq!(get_prefix(&args).unwrap(), "trip");
+    assert_eq!(
+        relative_files(
+            &trip,
+            get_source_files(&canonicalize_sources(&args).unwrap(), &args).unwrap()
+        ),
+        ["IMG_1.jpg", "IMG_3.jpg"]
+    );
+}
+
+#[test]
+fn atomic_failure_restores_the_tree() {
+    let dir = tempfile::tempdir().unwrap();
+    for (name, contents) in [
+        ("a.jpg", "A"),
+        ("b.jpg", "B"),
+        ("c.jpg", "C"),
+        ("d_0.jpg", "Z"),
+    ] {
+        write_file(&dir.path().join(name), contents);
+    }
+    let folder = arg(dir.path());
+    let args = parse_args(&["--atomic", "-j1", "--sort=name", "-p", "d", folder, folder]);
+    let fail_b = |operation, source: &Path, destination: &Path| {
+        if source.ends_with("b.jpg") {
+            Err(std::io::Error::other("injected failure"))
+        } else {
+            disk_file_operation(&args)(operation, source, destination)
+        }
+    };
+
+    let result = move_images(
+        canonicalize_sources(&args).unwrap(),
+        dir.path().to_path_buf(),
+        "d",
+        &args,
+        &fail_b,
+        &(),
+        &MockPrompt::new(None),
+    );
+
+    assert!(matches!(
+        result,
+        Err(ImgmvError::RolledBack {
+            failed: 1,
+            reverted: 2,
+            completed: 2
+        })
+    ));
+    assert_eq!(names(dir.path()), ["a.jpg", "b.jpg", "c.jpg", "d_0.jpg"]);
+    for (name, contents) in [
+        ("a.jpg", "A"),
+        ("b.jpg", "B"),
+        ("c.jpg", "C"),
+        ("d_0.jpg", "Z"),
+    ] {
+        assert_eq!(fs::read_to_string(dir.path().join(name)).unwrap(), contents);
+    }
+}
+
+#[test]
+fn report_is_written_after_an_atomic_rollback() {
+    let dir = tempfile::tempdir().unwrap();
+    for name in ["a.jpg", "b.jpg", "c.jpg"] {
+        write_file(&dir.path().join("src").join(name), name);
+    }
+    let (source, destination) = (dir.path().join("src"), dir.path().join("dst"));
+    fs::create_dir(&destination).unwrap();
+    let report = dir.path().join("report.csv");
+    let args = parse_args(&[
+        "--atomic",
+        "-j1",
+        "--sort=name",
+        "--report",
+        arg(&report),
+        arg(&source),
+        arg(&destination),
+    ]);
+    let fail_b = |operation, source: &Path, destination: &Path| {
+        if source.ends_with("b.jpg") {
+            Err(std::io::Error::other("injected failure"))
+        } else {
+            disk_file_operation(&args)(operation, source, destination)
+        }
+    };
+
+    let result = move_images(
+        canonicalize_sources(&args).unwrap(),
+        destination.clone(),
+        "x",
+        &args,
+        &fail_b,
+        &(),
+        &MockPrompt::new(None),
+    );
+
+    assert!(matches!(result, Err(ImgmvError::RolledBack { .. })));
+    assert_eq!(
+        report_statuses(&report),
+        ["rolled-back", "failed", "skipped"]
+    );
+    assert_eq!(names(&source), ["a.jpg", "b.jpg", "c.jpg"]);
+}
+
+#[test]
+fn index_width_follows_the_largest_emitted_index() {
+    let format = |index, start, count| format_index(index, start, count, None, IndexStyle::Decimal);
+
+    assert_eq!(format(5, 5, 5), "5");
+    assert_eq!(format(9, 5, 5), "9");
+    assert_eq!(format(5, 5, 6), "05");
+    assert_eq!(format(10, 5, 6), "10");
+    assert_eq!(format(95, 95, 6), "095");
+    assert_eq!(format(5, 5, 6).len(), format(10, 5, 6).len());
+
+    let dir = four_images();
+    let names = |options: &[&str]| {
+        destination_names(&plan(&parse_args(
+            &[
+                options,
+                &["-p", "x", "--sort=name", arg(dir.path()), arg(dir.path())],
+            ]
+            .concat(),
+        )))
+    };
+    assert_eq!(names(&["-s", "8", "--max-files=2"]), ["x_8.jpg", "x_9.jpg"]);
+    assert_eq!(
+        names(&["-s", "8", "--max-files=3"]),
+        ["x_08.jpg", "x_09.jpg", "x_10.jpg"]
+    );
+}
+
+#[test]
+fn space_replacement_collapses_runs_of_spaces() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("Summer   Trip  2024");
+    fs::create_dir(&source).unwrap();
+    let prefix = |options: &[&str]| {
+        get_prefix(&parse_args(
+            &[options, &[arg(&source), arg(dir.path())]].concat(),
+        ))
+        .unwrap()
+    };
+
+    assert_eq!(prefix(&[]), "Summer   Trip  2024");
+    assert_eq!(prefix(&["--space-replacement=_"]), "Summer_Trip_2024");
+    assert_eq!(prefix(&["--space-replacement=-"]), "Summer-Trip-2024");
+    assert_eq!(prefix(&["--space-replacement", " "]), "Summer Trip 2024");
+    assert_eq!(
+        prefix(&["--space-replacement=.", "-p", "my \t big  day"]),
+        "my.big.day"
+    );
+    assert!(Args::try_parse_from(["imgmv", "--space-replacement=/", "src"]).is_err());
+    assert!(Args::try_parse_from(["imgmv", "--space-replacement=ab", "src"]).is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn identical_copies_are_hard_linked() {
+    use std::os::unix::fs::MetadataExt;
+    let dir = tempfile::tempdir().unwrap();
+    let (source, destination) = (dir.path().join("src"), dir.path().join("dst"));
+    for (name, contents) in [("a.jpg", "same"), ("b.jpg", "same"), ("c.jpg", "other")] {
+        write_file(&source.join(name), contents);
+    }
+    fs::create_dir(&destination).unwrap();
+    let first_copies = std::sync::Mutex::new(HashMap::new());
+    let inode = |name: &str| fs::metadata(destination.join(name)).unwrap().ino();
+
+    for (from, to) in [
+        ("a.jpg", "x_0.jpg"),
+        ("b.jpg", "x_1.jpg"),
+        ("c.jpg", "x_2.jpg"),
+    ] {
+        copy_or_link(
+            &source.join(from),
+            &destination.join(to),
+            &first_copies,
+            None,
+        )
+        .unwrap();
+    }
+
+    assert_eq!(inode("x_0.jpg"), inode("x_1.jpg"));
+    assert_ne!(inode("x_0.jpg"), inode("x_2.jpg"));
+    assert_ne!(
+        inode("x_0.jpg"),
+        fs::metadata(source.join("a.jpg")).unwrap().ino()
+    );
+    assert_eq!(
+        fs::read_to_string(destination.join("x_2.jpg")).unwrap(),
+        "other"
+    );
+
+    let summary = run(&parse_args(&[
+        "-c",
+        "--hardlink-dupes",
+        "-p",
+        "y",
+        "--sort=name",
+        arg(&source),
+        arg(&destination),
+    ]))
+    .unwrap();
+    assert_eq!(summary.copied, 3);
+    assert_eq!(inode("y_0.jpg"), inode("y_1.jpg"));
+}
+
+#[test]
+fn prefix_and_suffix_are_optional_and_compose() {
+    let (_dir, source, destination) = two_images();
+    let names = |options: &[&str]| {
+        destination_names(&plan(&parse_args(
+            &[options, &["--sort=name", arg(&source), arg(&destination)]].concat(),
+        )))
+    };
+
+    assert_eq!(names(&["-p", "x"]), ["x_0.jpg", "x_1.jpg"]);
+    assert_eq!(
+        names(&["-p", "", "--suffix=event"]),
+        ["0_event.jpg", "1_event.jpg"]
+    );
+    assert_eq!(
+        names(&["-p", "x", "--suffix=event"]),
+        ["x_0_event.jpg", "x_1_event.jpg"]
+    );
+    assert_eq!(
+        names(&["-p", "x", "--suffix=big  day", "--separator=-"]),
+        ["x-0-big  day.jpg", "x-1-big  day.jpg"]
+    );
+}
+
+#[test]
+fn skip_renamed_makes_repeated_runs_no_ops() {
+    let dir = tempfile::tempdir().unwrap();
+    write_file(&dir.path().join("b.jpg"), "B");
+    write_file(&dir.path().join("a.jpg"), "A");
+    let folder = arg(dir.path());
+    let options = ["--skip-renamed", "-p", "x", "--sort=name", folder, folder];
+
+    let first = run(&parse_args(&options)).unwrap();
+    let second = run(&parse_args(&options)).unwrap();
+
+    assert_eq!(first.moved, 2);
+    assert_eq!(second.total(), 0);
+    assert_eq!(fs::read_to_string(dir.path().join("x_0.jpg")).unwrap(), "A");
+    assert_eq!(fs::read_to_string(dir.path().join("x_1.jpg")).unwrap(), "B");
+
+    write_file(&dir.path().join("c.jpg"), "C");
+    let third = run(&parse_args(&[&["--continue"], &options[..]].concat())).unwrap();
+    assert_eq!(third.total(), 1);
+    assert_eq!(
+        names(dir.path()),
+        [".imgmv-undo.jsonl", "x_0.jpg", "x_1.jpg", "x_2.jpg"]
+    );
+}
+
+#[test]
+fn dedup_is_the_same_serially_and_in_parallel() {
+    let dir = tempfile::tempdir().unwrap();
+    let files: Vec<PathBuf> = (0..60)
+        .map(|i| {
+            let path = dir.path().join(format!("{:02}.png", i));
+            write_png(&path, 16, 16, i % 7 + 1);
+            if i % 5 == 0 {
+                fs::copy(dir.path().join("00.png"), &path).unwrap();
+            }
+            path
+        })
+        .collect();
+    let dedup = |jobs: &str| {
+        remove_duplicates(
+            files.clone(),
+            &parse_args(&["--dedup", "--dedup-perceptual", "-j", jobs, "src"]),
+        )
+        .unwrap()
+    };
+
+    let serial = dedup("1");
+    assert!(serial.len() < files.len());
+    assert_eq!(serial[0], files[0]);
+    assert_eq!(dedup("8"), serial);
+}
+
+#[test]
+fn table_aligns_the_destination_column() {
+    let operation = |source: &str, destination: &str| FileOperation {
+        source: PathBuf::from("src").join(source),
+        destination: PathBuf::from("dst").join(destination),
+        operation: Operation::Move,
+    };
+    let operations = [
+        operation("a.jpg", "x_0.jpg"),
+        operation("IMG_1234.jpg", "x_1.jpg"),
+        operation("éé.png", "x_2.png"),
+    ];
+
+    let table = render_table(&operations.iter().collect::<Vec<_>>());
+
+    assert_eq!(
+        table,
+        "a.jpg        → x_0.jpg\nIMG_1234.jpg → x_1.jpg\néé.png       → x_2.png\n"
+    );
+    let columns: Vec<usize> = table
+        .lines()
+        .map(|line| line.chars().position(|c| c == '→').unwrap())
+        .collect();
+    assert_eq!(columns, [13, 13, 13]);
+    assert_eq!(render_table(&[]), "");
+}
+
+#[test]
+fn alpha_indices_roll_over_from_z_to_aa() {
+    assert_eq!(
+        [0, 1, 25, 26, 27, 51, 52].map(alpha_index),
+        ["a", "b", "z", "aa", "ab", "az", "ba"]
+    );
+    assert_eq!(alpha_index(26 * 26 + 25), "zz");
+    assert_eq!(alpha_index(26 * 26 + 26), "aaa");
+    // Alphabetic indices are never padded.
+    assert_eq!(format_index(0, 0, 100, Some(3), IndexStyle::Alpha), "a");
+
+    let (_dir, source, destination) = two_images();
+    let plan = plan(&parse_args(&[
+        "--index-style=alpha",
+        "-s",
+        "25",
+        "-p",
+        "photo",
+        "--sort=name",
+        arg(&source),
+        arg(&destination),
+    ]));
+    assert_eq!(destination_names(&plan), ["photo_z.jpg", "photo_aa.jpg"]);
+}
+
+#[test]
+fn by_camera_groups_files_by_their_model() {
+    let dir = tempfile::tempdir().unwrap();
+    let (source, destination) = (dir.path().join("src"), dir.path().join("dst"));
+    write_exif_jpeg(&source.join("a.jpg"), None, Some("Canon EOS R5"));
+    write_exif_jpeg(&source.join("b.jpg"), None, Some("Pixel 8"));
+    write_exif_jpeg(&source.join("c.jpg"), None, Some("Canon EOS R5"));
+    write_file(&source.join("d.jpg"), "no EXIF");
+
+    assert_eq!(
+        exif_camera(&source.join("b.jpg")).as_deref(),
+        Some("Pixel 8")
+    );
+    assert_eq!(exif_camera(&source.join("d.jpg")), None);
+    let plan = plan(&parse_args(&[
+        "--by-camera",
+        "-p",
+        "x",
+        "--sort=name",
+        arg(&source),
+        arg(&destination),
+    ]));
+    assert_eq!(
+        relative_files(
+            &destination,
+            plan.files
+                .iter()
+                .map(|file| file.destination.clone())
+                .collect()
+        ),
+        [
+            "Canon EOS R5/x_0.jpg",
+            "Canon EOS R5/x_1.jpg",
+            "Pixel 8/x_0.jpg",
+            "unknown/x_0.jpg"
+        ]
+    );
+}
+
+#[test]
+fn file_operations_are_called_in_plan_order() {
+    let (_dir, source, destination) = two_images();
+    write_file(&source.join("c.jpg"), "C");
+    let args = parse_args(&["-c", "-j1", "--sort=name", arg(&source), arg(&destination)]);
+    let calls = std::sync::Mutex::new(Vec::new());
+    let record = |operation, source: &Path, destination: &Path| {
+        calls.lock().unwrap().push((
+            operation,
+            source.file_name().unwrap().to_string_lossy().to_string(),
+            destination
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .to_string(),
+        ));
+        Ok(())
+    };
+
+    let summary = move_images(
+        canonicalize_sources(&args).unwrap(),
+        destination.clone(),
+        "x",
+        &args,
+        &record,
+        &(),
+        &MockPrompt::new(None),
+    )
+    .unwrap();
+
+    assert_eq!(summary.copied, 3);
+    assert_eq!(
+        calls.into_inner().unwrap(),
+        [("a", "x_0"), ("b", "x_1"), ("c", "x_2")].map(|(source, destination)| (
+            Operation::Copy,
+            format!("{}.jpg", source),
+            format!("{}.jpg", destination)
+        ))
+    );
+    assert_eq!(names(&destination), [UNDO_LOG_NAME]);
+}
+
+#[test]
+fn each_collision_strategy_handles_an_existing_destination() {
+    let outcome = |strategy: &str| {
+        let (_dir, source, destination) = two_images();
+        write_file(&destination.join("x_0.jpg"), "old");
+        let result = run(&parse_args(&[
+            &format!("--collision-strategy={}", strategy),
+            "-p",
+            "x",
+            "--sort=name",
+            arg(&source),
+            arg(&destination),
+        ]));
+        let contents: Vec<String> = names(&destination)
+            .iter()
+            .filter(|name| *name != UNDO_LOG_NAME)
+            .map(|name| {
+                format!(
+                    "{}={}",
+                    name,
+                    fs::read_to_string(destination.join(name)).unwrap()
+                )
+            })
+            .collect();
+        (result, contents)
+    };
+
+    let (result, contents) = outcome("skip");
+    assert_eq!(result.unwrap().skipped, 1);
+    assert_eq!(contents, ["x_0.jpg=old", "x_1.jpg=B"]);
+    let (result, contents) = outcome("overwrite");
+    assert_eq!(result.unwrap().moved, 2);
+    assert_eq!(contents, ["x_0.jpg=A", "x_1.jpg=B"]);
+    let (result, contents) = outcome("rename");
+    assert_eq!(result.unwrap().moved, 2);
+    assert_eq!(contents, ["x_0-1.jpg=A", "x_0.jpg=old", "x_1.jpg=B"]);
+    let (result, contents) = outcome("error");
+    assert!(matches!(result, Err(ImgmvError::Collisions(1))));
+    assert_eq!(contents, ["x_0.jpg=old"]);
+}
+
+#[test]
+fn prefix_files_name_the_event_if_present() {
+    let (_dir, source, destination) = two_images();
+    let prefix = || {
+        get_prefix(&parse_args(&[
+            "--prefix-file=title.txt",
+            arg(&source),
+            arg(&destination),
+        ]))
+        .unwrap()
+    };
+    assert_eq!(read_prefix_file(&source.join("title.txt")), None);
+    assert_eq!(prefix(), "src");
+
+    write_file(
+        &source.join("title.txt"),
+        "  Summer Trip: Lake  \nmore notes\n",
+    );
+    assert_eq!(
+        read_prefix_file(&source.join("title.txt")).as_deref(),
+        Some("Summer Trip: Lake")
+    );
+    assert_eq!(prefix(), "Summer Trip_ Lake");
+    assert_eq!(
+        selected_files(&source, &["--prefix-file=title.txt", "-a"]),
+        ["a.jpg", "b.jpg"]
+    );
+
+    write_file(&source.join("title.txt"), "\nSummer\n");
+    assert_eq!(prefix(), "src");
+}
+
+#[test]
+fn no_rename_keeps_the_names_and_resolves_collisions() {
+    let dir = tempfile::tempdir().unwrap();
+    let (first, second, destination) = (
+        dir.path().join("day1"),
+        dir.path().join("day2"),
+        dir.path().join("dst"),
+    );
+    write_file(&first.join("IMG_1.jpg"), "1");
+    write_file(&first.join("IMG_2.jpg"), "2");
+    write_file(&second.join("IMG_1.jpg"), "other 1");
+    write_file(&destination.join("IMG_2.jpg"), "old");
+    let options = [
+        "--no-rename",
+        "--collision-strategy=rename",
+        "--sort=name",
+        arg(&first),
+        arg(&second),
+        arg(&destination),
+    ];
+
+    let summary = run(&parse_args(&options)).unwrap();
+
+    assert_eq!(summary.moved, 3);
+    let contents: Vec<String> = ["IMG_1.jpg", "IMG_1-1.jpg", "IMG_2.jpg", "IMG_2-1.jpg"]
+        .iter()
+        .map(|name| fs::read_to_string(destination.join(name)).unwrap())
+        .collect();
+    assert_eq!(contents, ["1", "other 1", "old", "2"]);
+}
+
+#[test]
+fn summary_adds_up_the_bytes_handled() {
+    assert_eq!(format_size(0), "0 B");
+    assert_eq!(format_size(1023), "1023 B");
+    assert_eq!(format_size(1024), "1.0 KiB");
+    assert_eq!(format_size(1536), "1.5 KiB");
+    assert_eq!(format_size(5 << 20), "5.0 MiB");
+    assert_eq!(format_size((1 << 30) + (1 << 29) / 2), "1.2 GiB");
+    assert_eq!(format_size(u64::MAX), "16384.0 PiB");
+
+    let (_dir, source, destination) = two_images();
+    write_file(&source.join("a.jpg"), &"a".repeat(1000));
+    write_file(&source.join("b.jpg"), &"b".repeat(2000));
+    let options = ["-c", "-p", "x", arg(&source), arg(&destination)];
+
+    let dry_run = run(&parse_args(&[&["-d"], &options[..]].concat())).unwrap();
+    let summary = run(&parse_args(&options)).unwrap();
+
+    assert_eq!(dry_run.bytes, 3000);
+    assert_eq!(summary.bytes, 3000);
+    assert!(summary.to_string().ends_with("(2.9 KiB)"), "{}", summary);
+}
+
+#[test]
+fn with_retries_retries_transient_errors_only() {
+    use std::cell::Cell;
+    use std::io::{Error, ErrorKind};
+
+    let args = parse_args(&["--retries", "2", "--retry-delay", "1", "src", "dst"]);
+    let attempts = Cell::new(0);
+    let flaky = || {
+        attempts.set(attempts.get() + 1);
+        match attempts.get() {
+            1 | 2 => Err(Error::new(ErrorKind::Interrupted, "busy")),
+            _ => Ok(()),
+        }
+    };
+
+    assert!(with_retries(&args, flaky).is_ok());
+    assert_eq!(attempts.get(), 3);
+
+    attempts.set(0);
+    let always_busy = || {
+        attempts.set(attempts.get() + 1);
+        Err(Error::new(ErrorKind::TimedOut, "busy"))
+    };
+    assert_eq!(
+        with_retries(&args, always_busy).unwrap_err().kind(),
+        ErrorKind::TimedOut
+    );
+    assert_eq!(attempts.get(), 3);
+
+    attempts.set(0);
+    let missing = || {
+        attempts.set(attempts.get() + 1);
+        Err(Error::new(ErrorKind::NotFound, "gone"))
+    };
+    assert_eq!(
+        with_retries(&args, missing).unwrap_err().kind(),
+        ErrorKind::NotFound
+    );
+    assert_eq!(attempts.get(), 1);
+}
+
+#[test]
+fn batch_size_splits_the_images_into_batches() {
+    let (_dir, source, destination) = two_images();
+    for name in ["c.jpg", "d.jpg", "e.jpg"] {
+        write_file(&source.join(name), name);
+    }
+    let options = [
+        "-p",
+        "x",
+        "--sort=name",
+        "--batch-size=2",
+        arg(&source),
+        arg(&destination),
+    ];
+    let destinations = |args: &Args| {
+        let plan = plan(args);
+        let files = plan.files.iter().map(|file| file.destination.clone());
+        relative_files(&destination, files.collect())
+    };
+
+    assert_eq!(
+        destinations(&parse_args(&options)),
+        [
+            "batch_000/x_0.jpg",
+            "batch_000/x_1.jpg",
+            "batch_001/x_0.jpg",
+            "batch_001/x_1.jpg",
+            "batch_002/x_0.jpg",
+        ]
+    );
+    assert_eq!(
+        destinations(&parse_args(&[&["--global-index"], &options[..]].concat())),
+        [
+            "batch_000/x_0.jpg",
+            "batch_000/x_1.jpg",
+            "batch_001/x_2.jpg",
+            "batch_001/x_3.jpg",
+            "batch_002/x_4.jpg",
+        ]
+    );
+
+    run(&parse_args(&options)).unwrap();
+    assert_eq!(
+        fs::read_to_string(destination.join("batch_001/x_0.jpg")).unwrap(),
+        "c.jpg"
+    );
+    assert_eq!(
+        fs::read_to_string(destination.join("batch_002/x_0.jpg")).unwrap(),
+        "e.jpg"
+    );
+}
+
+#[test]
+fn since_file_only_handles_files_changed_after_the_last_run() {
+    let (dir, source, destination) = two_images();
+    set_mtime(&source.join("a.jpg"), 2020, 1, 1);
+    set_mtime(&source.join("b.jpg"), 2020, 1, 2);
+    let marker = dir.path().join("last-run");
+    let second_destination = dir.path().join("dst2");
+    fs::create_dir(&second_destination).unwrap();
+    let options = |destination: &Path| {
+        parse_args(&[
+            "-c",
+            "-p",
+            "x",
+            "--since-file",
+            arg(&marker),
+            arg(&source),
+            arg(destination),
+        ])
+    };
+
+    let first = run(&options(&destination)).unwrap();
+    assert_eq!(first.copied, 2);
+    assert!(read_since_file(&marker).unwrap().is_some());
+
+    write_file(&source.join("c.jpg"), "C");
+    let second = run(&options(&second_destination)).unwrap();
+
+    assert_eq!(second.copied, 1);
+    assert!(!second_destination.join("x_1.jpg").exists());
+    assert_eq!(
+        fs::read_to_string(second_destination.join("x_0.jpg")).unwrap(),
+        "C"
+    );
+}
+
+#[test]
+fn missing_sources_and_prefixes_are_typed_errors() {
+    let dir = tempfile::tempdir().unwrap();
+    let missing = dir.path().join("missing");
+    let args = parse_args(&[arg(&missing), arg(dir.path())]);
+
+    match canonicalize_sources(&args) {
+        Err(ImgmvError::SourceNotFound { path, source }) => {
+            assert_eq!(path, missing);
+            assert_eq!(source.kind(), std::io::ErrorKind::NotFound);
+        }
+        result => panic!("expected SourceNotFound, got {:?}", result),
+    }
+    assert!(matches!(
+        get_prefix(&parse_args(&["/", arg(dir.path())])),
+        Err(ImgmvError::NoPrefix)
+    ));
+    assert!(matches!(
+        get_prefix(&parse_args(&["..", arg(dir.path())])),
+        Err(ImgmvError::NoPrefix)
+    ));
+}
+
+#[test]
+fn consistent_extension_case_follows_the_first_file() {
+    let (_dir, source, destination) = two_images();
+    fs::rename(source.join("a.jpg"), source.join("a.JPG")).unwrap();
+    write_file(&source.join("c.Jpg"), "C");
+    write_file(&source.join("d.png"), "D");
+    write_file(&source.join("e.PNG"), "E");
+
+    let plan = plan(&parse_args(&[
+        "-p",
+        "x",
+        "--sort=name",
+        "--ext-case=consistent",
+        arg(&source),
+        arg(&destination),
+    ]));
+
+    assert_eq!(
+        destination_names(&plan),
+        ["x_0.JPG", "x_1.JPG", "x_2.JPG", "x_3.png", "x_4.png"]
+    );
+    assert_eq!(
+        first_ext_cases(&[
+            PathBuf::from("b.jpg"),
+            PathBuf::from("a.JPG"),
+            PathBuf::from("c")
+        ]),
+        HashMap::from([("jpg".to_string(), "jpg".to_string())])
+    );
+}
+
+#[test]
+fn files_are_not_moved_or_copied_onto_themselves() {
+    let (dir, source, _destination) = two_images();
+    let a = source.join("a.jpg");
+    let link = dir.path().join("link.jpg");
+    fs::hard_link(&a, &link).unwrap();
+
+    assert!(is_same_file(&a, &a));
+    assert!(is_same_file(&a, &link));
+    assert!(!is_same_file(&a, &source.join("b.jpg")));
+    assert!(!is_same_file(
+        &dir.path().join("missing.jpg"),
+        &dir.path().join("missing.jpg")
+    ));
+
+    for operation in ["-c", "-m"] {
+        let summary = run(&parse_args(&[
+            operation,
+            "--no-rename",
+            arg(&source),
+            arg(&source),
+        ]))
+        .unwrap();
+
+        assert_eq!((summary.copied, summary.moved, summary.failed), (0, 0, 0));
+        assert_eq!(fs::read_to_string(&a).unwrap(), "A");
+        assert_eq!(fs::read_to_string(source.join("b.jpg")).unwrap(), "B");
+    }
+}
+
+#[test]
+fn throttled_copies_keep_the_content_and_are_limited() {
+    let (_dir, source, destination) = two_images();
+    let contents: Vec<u8> = (0..150_000).map(|i| (i % 251) as u8).collect();
+    fs::write(source.join("a.jpg"), &contents).unwrap();
+    let throttle = Throttle::new(100_000);
+
+    let started = std::time::Instant::now();
+    copy_throttled(&source.join("a.jpg"), &destination.join("a.jpg"), &throttle).unwrap();
+
+    assert_eq!(fs::read(destination.join("a.jpg")).unwrap(), contents);
+    // The bucket holds one second of bytes, so the rest takes about half a second.
+    assert!(started.elapsed() >= std::time::Duration::from_millis(400));
+    let (_, tokens) = *throttle.bucket.lock().unwrap();
+    assert!(tokens < 100_000.0, "{}", tokens);
+
+    let summary = run(&parse_args(&[
+        "-c",
+        "-p",
+        "x",
+        "--sort=name",
+        "--throttle=1000000",
+        arg(&source),
+        arg(&destination),
+    ]))
+    .unwrap();
+    assert_eq!(summary.copied, 2);
+    assert_eq!(fs::read(destination.join("x_0.jpg")).unwrap(), contents);
+    assert_eq!(
+        fs::read_to_string(destination.join("x_1.jpg")).unwrap(),
+        "B"
+    );
+}
+
+#[test]
+fn zip_archives_get_the_generated_names() {
+    let (dir, source, destination) = two_images();
+    write_file(&source.join("c.png"), "C");
+    let zip_path = dir.path().join("images.zip");
+    let args = parse_args(&[
+        "-p",
+        "x",
+        "--sort=name",
+        "--zip",
+        arg(&zip_path),
+        arg(&source),
+        arg(&destination),
+    ]);
+    let source_paths = canonicalize_sources(&args).unwrap();
+    let mut source_files = get_source_files(&source_paths, &args).unwrap();
+    sort_source_files(&mut source_files, &args);
+    let pairs = generate_source_destination_pairs(
+        source_files,
+        &source_paths,
+        &args.destination(),
+        "x",
+        false,
+        &args,
+    );
+    let pair_names: Vec<String> = pairs
+        .iter()
+        .map(|(_, destination_file)| {
+            destination_file
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .to_string()
+        })
+        .collect();
+
+    let summary = run(&args).unwrap();
+
+    let mut archive = zip::ZipArchive::new(fs::File::open(&zip_path).unwrap()).unwrap();
+    let entries: Vec<String> = (0..archive.len())
+        .map(|index| archive.by_index(index).unwrap().name().unwrap().to_string())
+        .collect();
+    assert_eq!(entries, pair_names);
+    assert_eq!(entries, ["x_0.jpg", "x_1.jpg", "x_2.png"]);
+    let mut contents = String::new();
+    std::io::Read::read_to_string(&mut archive.by_name("x_2.png").unwrap(), &mut contents).unwrap();
+    assert_eq!(contents, "C");
+    assert_eq!(summary.moved, 3);
+    assert!(names(&source).is_empty());
+    assert!(names(&destination).is_empty());
+}
+
+#[test]
+fn destinations_differing_in_case_collide_on_case_insensitive_file_systems() {
+    let used = |case_insensitive| UsedDestinations {
+        paths: HashSet::new(),
+        case_insensitive,
+    };
+
+    let mut insensitive = used(true);
+    assert_eq!(
+        disambiguate(PathBuf::from("d/x_0.JPG"), &mut insensitive),
+        Path::new("d/x_0.JPG")
+    );
+    assert_eq!(
+        disambiguate(PathBuf::from("d/x_0.jpg"), &mut insensitive),
+        Path::new("d/x_0-1.jpg")
+    );
+    assert!(insensitive.contains(Path::new("D/X_0.jpg")));
+
+    let mut sensitive = used(false);
+    assert_eq!(
+        disambiguate(PathBuf::from("d/x_0.JPG"), &mut sensitive),
+        Path::new("d/x_0.JPG")
+    );
+    assert_eq!(
+        disambiguate(PathBuf::from("d/x_0.jpg"), &mut sensitive),
+        Path::new("d/x_0.jpg")
+    );
+    assert!(!sensitive.contains(Path::new("D/X_0.jpg")));
+
+    #[cfg(target_os = "linux")]
+    {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(!is_case_insensitive(dir.path()));
+        assert!(names(dir.path()).is_empty());
+    }
+}
+
+#[test]
+fn ext_prefix_numbers_each_mapped_extension_separately() {
+    let (_dir, source, destination) = two_images();
+    for name in ["c.MP4", "d.mp4", "e.png", "f.JPG"] {
+        write_file(&source.join(name), name);
+    }
+    let args = parse_args(&[
+        "-p",
+        "x",
+        "--sort=name",
+        "-e=jpg",
+        "-e=mp4",
+        "-e=png",
+        "--ext-prefix",
+        "jpg=photo,.mp4=my clip",
+        arg(&source),
+        arg(&destination),
+    ]);
+
+    assert_eq!(
+        args.ext_prefix,
+        [
+            ("jpg".to_string(), "photo".to_string()),
+            ("mp4".to_string(), "my clip".to_string())
+        ]
+    );
+    assert_eq!(
+        ext_prefix(Path::new("a.JPG"), &args).as_deref(),
+        Some("photo")
+    );
+    assert_eq!(
+        ext_prefix(Path::new("c.mp4"), &args).as_deref(),
+        Some("my clip")
+    );
+    assert_eq!(ext_prefix(Path::new("e.png"), &args), None);
+    assert_eq!(ext_prefix(Path::new("jpg"), &args), None);
+    assert_eq!(
+        destination_names(&plan(&args)),
+        [
+            "photo_0.jpg",
+            "photo_1.jpg",
+            "my clip_0.MP4",
+            "my clip_1.mp4",
+            "x_0.png",
+            "photo_2.JPG"
+        ]
+    );
+}
+
+#[test]
+fn lowercase_name_lowercases_the_whole_name() {
+    let (_dir, source, destination) = two_images();
+    fs::rename(source.join("a.jpg"), source.join("A.JPG")).unwrap();
+    let options = [
+        "-p",
+        "Trip",
+        "--separator=-X-",
+        "--suffix=END",
+        "--index-format=%03d",
+        "--sort=name",
+        arg(&source),
+        arg(&destination),
+    ];
+
+    assert_eq!(
+        destination_names(&plan(&parse_args(&options))),
+        ["Trip-X-000-X-END.JPG", "Trip-X-001-X-END.jpg"]
+    );
+    assert_eq!(
+        destination_names(&plan(&parse_args(
+            &[&["--lowercase-name"], &options[..]].concat()
+        ))),
+        ["trip-x-000-x-end.jpg", "trip-x-001-x-end.jpg"]
+    );
+}
+
+#[test]
+fn overlong_destinations_are_reported_before_anything_is_moved() {
+    let long_name = format!("{}.jpg", "x".repeat(300));
+    let deep_path: PathBuf = std::iter::repeat_n("folder", 5000 / 7).collect();
+
+    assert_eq!(path_length_error(Path::new("dst/x_0.jpg")), None);
+    assert!(
+        path_length_error(&Path::new("dst").join(&long_name))
+            .unwrap()
+            .starts_with("file name is 304 characters long")
+    );
+    assert!(
+        path_length_error(&deep_path.join("x_0.jpg"))
+            .unwrap()
+            .starts_with("path is")
+    );
+
+    let (_dir, source, destination) = two_images();
+    let prefix = "p".repeat(300);
+    let result = run(&parse_args(&[
+        "-p",
+        &prefix,
+        arg(&source),
+        arg(&destination),
+    ]));
+
+    assert!(
+        matches!(result, Err(ImgmvError::PathsTooLong(2))),
+        "{:?}",
+        result
+    );
+    assert_eq!(names(&source), ["a.jpg", "b.jpg"]);
+    assert!(names(&destination).is_empty());
+}
+
+#[test]
+fn mtime_prefix_uses_the_modification_time_of_every_file() {
+    let (_dir, source, destination) = two_images();
+    write_exif_jpeg(&source.join("a.jpg"), Some("2001:01:01 10:00:00"), None);
+    set_mtime(&source.join("a.jpg"), 2023, 5, 6);
+    set_mtime(&source.join("b.jpg"), 2023, 5, 7);
+    write_file(&source.join("c.png"), "C");
+    set_mtime(&source.join("c.png"), 2023, 5, 7);
+    let options = [
+        "--mtime-prefix=%Y-%m-%d",
+        "--sort=name",
+        arg(&source),
+        arg(&destination),
+    ];
+
+    assert_eq!(
+        destination_names(&plan(&parse_args(&options))),
+        ["2023-05-06_0.jpg", "2023-05-07_1.jpg", "2023-05-07_2.png"]
+    );
+    assert_eq!(
+        destination_names(&plan(&parse_args(
+            &[&["-p", "trip"], &options[..]].concat()
+        ))),
+        [
+            "2023-05-06_trip_0.jpg",
+            "2023-05-07_trip_1.jpg",
+            "2023-05-07_trip_2.png"
+        ]
+    );
+    assert_eq!(
+        destination_names(&plan(&parse_args(&[
+            "--mtime-prefix=%B %Y",
+            "-p",
+            "trip",
+            "--sort=name",
+            arg(&source),
+            arg(&destination),
+        ]))),
+        [
+            "May 2023_trip_0.jpg",
+            "May 2023_trip_1.jpg",
+            "May 2023_trip_2.png"
+        ]
+    );
+}
+
+#[test]
+fn equal_sort_keys_are_ordered_by_name() {
+    let dir = tempfile::tempdir().unwrap();
+    let folder = dir.path();
+    fs::create_dir_all(folder.join("x")).unwrap();
+    fs::create_dir_all(folder.join("y")).unwrap();
+    let files: Vec<PathBuf> = [
+        "y/img10.jpg",
+        "x/img10.jpg",
+        "img2.jpg",
+        "IMG1.jpg",
+        "old.jpg",
+    ]
+    .iter()
+    .map(|name| folder.join(name))
+    .collect();
+    for file in &files {
+        write_file(file, "same size");
+        set_mtime(file, 2022, 2, 2);
+    }
+    set_mtime(&folder.join("old.jpg"), 2020, 1, 1);
+
+    for (sort, expected) in [
+        (
+            SortOrder::Mtime,
+            [
+                "old.jpg",
+                "IMG1.jpg",
+                "img2.jpg",
+                "x/img10.jpg",
+                "y/img10.jpg",
+            ],
+        ),
+        (
+            SortOrder::Size,
+            [
+                "IMG1.jpg",
+                "img2.jpg",
+                "x/img10.jpg",
+                "y/img10.jpg",
+                "old.jpg",
+            ],
+        ),
+    ] {
+        let mut sorted = files.clone();
+        sort_by_order(&mut sorted, sort);
+        let sorted: Vec<String> = sorted
+            .iter()
+            .map(|file| {
+                file.strip_prefix(folder)
+                    .unwrap()
+                    .to_string_lossy()
+                    .replace('\\', "/")
+            })
+            .collect();
+
+        assert_eq!(sorted, expected, "{:?}", sort);
+    }
+}
+
+#[test]
+fn watch_handles_new_files_and_keeps_the_undo_log() {
+    let dir = tempfile::tempdir().unwrap();
+    let (source, destination) = (dir.path().join("src"), dir.path().join("dst"));
+    write_file(&source.join("a.jpg"), "A");
+    fs::create_dir(&destination).unwrap();
+    let args: &'static Args = Box::leak(Box::new(parse_args(&[
+        "-p",
+        "img",
+        arg(&source),
+        arg(&destination),
+    ])));
+    assert_eq!(run(args).unwrap().moved, 1);
+
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let source_paths = canonicalize_sources(args).unwrap();
+    let destination_path = destination.clone();
+    std::thread::spawn(move || {
+        watch_images(
+            &source_paths,
+            &destination_path,
+            "img",
+            args,
+            &disk_file_operation(args),
+            &(),
+            &MockPrompt::new(None),
+            |summary| {
+                sender.send(*summary).unwrap();
+                std::ops::ControlFlow::Break(())
+            },
+        )
+    });
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    write_file(&source.join("b.jpg"), "B");
+
+    let summary = receiver
+        .recv_timeout(std::time::Duration::from_secs(30))
+        .expect("the new file is handled");
+    assert_eq!(summary.moved, 1);
+    assert_eq!(
+        names(&destination),
+        [".imgmv-undo.jsonl", "img_0.jpg", "img_1.jpg"]
+    );
+    let undo_log = fs::read_to_string(destination.join(UNDO_LOG_NAME)).unwrap();
+    assert_eq!(undo_log.lines().count(), 2);
+}
+
+#[test]
+fn prefix_from_ancestors_joins_the_last_folder_names() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("2023").join("Summer trip").join("DCIM");
+    fs::create_dir_all(&source).unwrap();
+    let prefix = |count: &str| {
+        get_prefix(&parse_args(&[
+            "--prefix-from-ancestors",
+            count,
+            arg(&source),
+            "dst",
+        ]))
+    };
+
+    assert_eq!(ancestor_names(&source, 1).unwrap(), ["DCIM"]);
+    assert_eq!(ancestor_names(&source, 2).unwrap(), ["Summer trip", "DCIM"]);
+    assert_eq!(prefix("1").unwrap(), "DCIM");
+    assert_eq!(prefix("2").unwrap(), "Summer trip_DCIM");
+    assert_eq!(prefix("3").unwrap(), "2023_Summer trip_DCIM");
+
+    let depth = source.canonicalize().unwrap().components().count();
+    assert!(matches!(
+        ancestor_names(&source, depth),
+        Err(ImgmvError::TooFewAncestors { count, .. }) if count == depth
+    ));
+    assert!(matches!(
+        prefix(&depth.to_string()),
+        Err(ImgmvError::TooFewAncestors { .. })
+    ));
+    assert!(matches!(
+        ancestor_names(&dir.path().join("missing"), 1),
+        Err(ImgmvError::SourceNotFound { .. })
+    ));
+}
+
+#[test]
+fn check_space_compares_the_copied_size_with_the_free_space() {
+    let (_dir, source, destination) = two_images();
+    let sizes = [1000, 500];
+    let check = |options: &[&str], available: Option<u64>| {
+        let args = parse_args(&[options, &[arg(&source), arg(&destination)]].concat());
+        let plan = plan(&args);
+        let queried = std::sync::Mutex::new(None);
+        let result = check_space(&plan, &sizes, &destination, &args, |folder| {
+            *queried.lock().unwrap() = Some(folder.to_path_buf());
+            available.ok_or_else(|| std::io::ErrorKind::Unsupported.into())
+        });
+        (result, queried.into_inner().unwrap())
+    };
+
+    let (result, queried) = check(&["-c", "--check-space"], Some(1500));
+    assert!(result.is_ok());
+    assert_eq!(queried, Some(destination.clone()));
+    assert!(matches!(
+        check(&["-c", "--check-space"], Some(1499)).0,
+        Err(ImgmvError::InsufficientSpace {
+            required: 1500,
+            available: 1499,
+            ..
+        })
+    ));
+    // A dry run only warns, and an unknown free space is not checked.
+    assert!(check(&["-c", "-d", "-q"], Some(0)).0.is_ok());
+    assert!(check(&["-c", "--check-space"], None).0.is_ok());
+    // Moves within the same device need no space.
+    assert_eq!(check(&["--check-space"], Some(0)).1, None);
+}
+
+#[test]
+fn min_age_skips_recently_modified_files() {
+    let dir = tempfile::tempdir().unwrap();
+    write_file(&dir.path().join("old.jpg"), "old");
+    write_file(&dir.path().join("new.jpg"), "new");
+    set_mtime(&dir.path().join("old.jpg"), 2020, 1, 1);
+
+    assert_eq!(
+        parse_duration("1h30m"),
+        Ok(std::time::Duration::from_secs(5400))
+    );
+    assert_eq!(selected_files(dir.path(), &[]), ["new.jpg", "old.jpg"]);
+    assert_eq!(selected_files(dir.path(), &["--min-age=30m"]), ["old.jpg"]);
+    assert_eq!(
+        selected_files(dir.path(), &["--min-age=0s"]),
+        ["new.jpg", "old.jpg"]
+    );
+}
+
+/// A progress sink that records the events it gets.
+#[derive(Default)]
+struct RecordingSink(std::sync::Mutex<Vec<String>>);
+
+impl RecordingSink {
+    fn record(&self, event: String) {
+        self.0.lock().unwrap().push(event);
+    }
+
+    fn events(&self) -> Vec<String> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl ProgressSink for RecordingSink {
+    fn on_start(&self, total: usize) {
+        self.record(format!("start {}", total));
+    }
+
+    fn on_file_done(&self, file_operation: &FileOperation) {
+        let name = |path: &Path| path.file_name().unwrap().to_string_lossy().to_string();
+        self.record(format!(
+            "done {} -> {}",
+            name(&file_operation.source),
+            name(&file_operation.destination)
+        ));
+    }
+
+    fn on_error(&self, source: &Path, message: &str) {
+        assert!(message.contains("injected failure"), "{}", message);
+        self.record(format!(
+            "error {}",
+            source.file_name().unwrap().to_string_lossy()
+        ));
+    }
+
+    fn on_finish(&self, summary: &Summary) {
+        self.record(format!(
+            "finish {} moved, {} failed",
+            summary.moved, summary.failed
+        ));
+    }
+}
+
+#[test]
+fn progress_sinks_get_every_event_in_order() {
+    let (_dir, source, destination) = two_images();
+    write_file(&source.join("c.jpg"), "C");
+    let args = parse_args(&[
+        "-j1",
+        "-p",
+        "x",
+        "--sort=name",
+        arg(&source),
+        arg(&destination),
+    ]);
+    let fail_b = |operation, source: &Path, destination: &Path| {
+        if source.ends_with("b.jpg") {
+            Err(std::io::Error::other("injected failure"))
+        } else {
+            disk_file_operation(&args)(operation, source, destination)
+        }
+    };
+    let sink = RecordingSink::default();
+
+    let summary = move_images(
+        canonicalize_sources(&args).unwrap(),
+        destination.clone(),
+        "x",
+        &args,
+        &fail_b,
+        &sink,
+        &MockPrompt::new(None),
+    );
+
+    assert!(summary.is_ok(), "{:?}", summary);
+    assert_eq!(
+        sink.events(),
+        [
+            "start 3",
+            "done a.jpg -> x_0.jpg",
+            "error b.jpg",
+            "done c.jpg -> x_2.jpg",
+            "finish 2 moved, 1 failed",
+        ]
+    );
+}
+
+#[test]
+fn hash_names_are_shared_by_identical_files_only() {
+    let (dir, source, destination) = two_images();
+    write_file(&source.join("c.jpg"), "A");
+    let args = parse_args(&[
+        "--hash-name",
+        "--hash-length=4",
+        arg(&source),
+        arg(&destination),
+    ]);
+    let mut hash_names = HashMap::new();
+    let folder = dir.path().join("empty");
+
+    let first = hash_destination(&folder, "abcd1111", ".jpg", &args, &mut hash_names);
+    let same = hash_destination(&folder, "abcd1111", ".jpg", &args, &mut hash_names);
+    let other = hash_destination(&folder, "abcd2222", ".jpg", &args, &mut hash_names);
+    let png = hash_destination(&folder, "abcd2222", ".png", &args, &mut hash_names);
+
+    assert_eq!(first, folder.join("abcd.jpg"));
+    assert_eq!(same, first);
+    assert_eq!(other, folder.join("abcd2.jpg"));
+    assert_eq!(png, folder.join("abcd.png"));
+
+    let hash = |name: &str| hex_hash_file(&source.join(name), HashAlgo::Blake3).unwrap();
+    let (hash_a, hash_b) = (hash("a.jpg"), hash("b.jpg"));
+    let summary = run(&parse_args(&[
+        "-c",
+        "--hash-name",
+        arg(&source),
+        arg(&destination),
+    ]))
+    .unwrap();
+
+    let mut expected = vec![
+        ".imgmv-undo.jsonl".to_string(),
+        format!("{}.jpg", &hash_a[..8]),
+        format!("{}.jpg", &hash_b[..8]),
+    ];
+    expected.sort();
+    assert_eq!(names(&destination), expected);
+    assert_eq!(summary.copied + summary.skipped + summary.unchanged, 3);
+    assert_eq!(
+        fs::read_to_string(destination.join(format!("{}.jpg", &hash_a[..8]))).unwrap(),
+        "A"
+    );
+}
+
+#[test]
+fn extensionless_files_get_the_sniffed_extension() {
+    let (_dir, source, destination) = two_images();
+    fs::remove_file(source.join("b.jpg")).unwrap();
+    write_exif_jpeg(&source.join("IMG_0001"), Some("2021:01:01 10:00:00"), None);
+    fs::write(source.join("blob"), [0x00, 0x13, 0x37, 0x42, 0x00, 0x99]).unwrap();
+
+    assert_eq!(
+        sniffed_extension(&source.join("IMG_0001"), true),
+        Some("jpg")
+    );
+    assert_eq!(sniffed_extension(&source.join("IMG_0001"), false), None);
+    assert_eq!(sniffed_extension(&source.join("blob"), true), None);
+    assert_eq!(sniffed_extension(&source.join("a.jpg"), true), None);
+
+    assert_eq!(selected_files(&source, &[]), ["a.jpg"]);
+    assert_eq!(
+        selected_files(&source, &["--sniff-ext"]),
+        ["IMG_0001", "a.jpg"]
+    );
+    assert_eq!(
+        destination_names(&plan(&parse_args(&[
+            "-p",
+            "x",
+            "--sort=name",
+            "--all",
+            "--sniff-ext",
+            arg(&source),
+            arg(&destination),
+        ]))),
+        ["x_0.jpg", "x_1", "x_2.jpg"]
+    );
+}
+
+#[test]
+fn confirm_above_asks_only_for_more_files_than_the_limit() {
+    let (_dir, source, destination) = two_images();
+    let args = |limit: &str, extra: &[&str]| {
+        parse_args(
+            &[
+                &["-c", "-p", "x", "--confirm-above", limit],
+                extra,
+                &[arg(&source), arg(&destination)],
+            ]
+            .concat(),
+        )
+    };
+
+    let below = MockPrompt::new(Some(false));
+    assert_eq!(run_with(&args("2", &[]), &below).unwrap().copied, 2);
+    assert!(below.questions().is_empty());
+
+    let declined = MockPrompt::new(Some(false));
+    let summary = run_with(&args("1", &["--collision-strategy=overwrite"]), &declined).unwrap();
+    assert_eq!((summary.copied, summary.skipped), (0, 2));
+    assert_eq!(declined.questions(), ["Copy 2 files, more than 1?"]);
+
+    let accepted = MockPrompt::new(Some(true));
+    let summary = run_with(&args("1", &["--collision-strategy=overwrite"]), &accepted).unwrap();
+    assert_eq!(summary.copied, 2);
+    assert_eq!(accepted.questions().len(), 1);
+
+    let script = MockPrompt::new(None);
+    assert!(matches!(
+        run_with(&args("1", &["--collision-strategy=overwrite"]), &script),
+        Err(ImgmvError::ConfirmationRequired { count: 2, limit: 1 })
+    ));
+    let confirmed = run_with(
+        &args("1", &["--collision-strategy=overwrite", "--yes"]),
+        &script,
+    );
+    assert_eq!(confirmed.unwrap().copied, 2);
+}
+
+#[test]
+fn index_format_sets_the_index_width() {
+    assert_eq!(parse_index_format("%03d"), Ok(3));
+    assert_eq!(parse_index_format("%d"), Ok(0));
+    for invalid in ["03d", "%3d", "%0xd", "%03s", "%"] {
+        assert!(parse_index_format(invalid).is_err(), "{}", invalid);
+    }
+    let error = Args::try_parse_from(["imgmv", "--index-format=%5d", "src"]).unwrap_err();
+    assert!(
+        error.to_string().contains("only zero padding is supported"),
+        "{}",
+        error
+    );
+
+    let dir = tempfile::tempdir().unwrap();
+    let (source, destination) = (dir.path().join("src"), dir.path().join("dst"));
+    for index in 0..11 {
+        write_file(&source.join(format!("img{:02}.jpg", index)), "image");
+    }
+    let names = |format: &str| {
+        let plan = plan(&parse_args(&[
+            "-p",
+            "x",
+            "--sort=name",
+            "--index-format",
+            format,
+            arg(&source),
+            arg(&destination),
+        ]));
+        let names = destination_names(&plan);
+        [names[0].clone(), names[10].clone()]
+    };
+
+    assert_eq!(names("%03d"), ["x_000.jpg", "x_010.jpg"]);
+    assert_eq!(names("%d"), ["x_0.jpg", "x_10.jpg"]);
+}
+
+#[test]
+fn empty_files_are_skipped_unless_allowed() {
+    let (_dir, source, destination) = two_images();
+    write_file(&source.join("a.jpg"), "");
+
+    assert_eq!(selected_files(&source, &[]), ["b.jpg"]);
+    assert_eq!(
+        selected_files(&source, &["--allow-empty"]),
+        ["a.jpg", "b.jpg"]
+    );
+    assert_eq!(
+        selected_files(&source, &["--allow-empty", "--min-size=1"]),
+        ["b.jpg"]
+    );
+
+    run(&parse_args(&["-p", "x", arg(&source), arg(&destination)])).unwrap();
+    assert_eq!(names(&source), ["a.jpg"]);
+    assert_eq!(
+        fs::read_to_string(destination.join("x_0.jpg")).unwrap(),
+        "B"
+    );
+}
+
+#[test]
+fn files_already_named_as_planned_are_unchanged() {
+    let dir = tempfile::tempdir().unwrap();
+    let folder = dir.path().join("x");
+    write_file(&folder.join("x_0.jpg"), "A");
+    write_file(&folder.join("x_1.JPG"), "B");
+    let options = ["--sort=name", "--lowercase-ext", arg(&folder), arg(&folder)];
+
+    let plan = plan(&parse_args(&[&["--skip-unchanged"], &options[..]].concat()));
+    assert!(
+        plan.files
+            .iter()
+            .all(|file| file.action == Action::Unchanged)
+    );
+
+    let summary = run(&parse_args(&[&["--skip-unchanged"], &options[..]].concat())).unwrap();
+    assert_eq!(
+        summary,
+        Summary {
+            unchanged: 2,
+            ..Summary::default()
+        }
+    );
+    assert_eq!(names(&folder), [".imgmv-undo.jsonl", "x_0.jpg", "x_1.JPG"]);
+
+    let summary = run(&parse_args(&options)).unwrap();
+    assert_eq!((summary.moved, summary.unchanged), (1, 1));
+    assert_eq!(names(&folder), [".imgmv-undo.jsonl", "x_0.jpg", "x_1.jpg"]);
+}
+
+#[test]
+fn map_files_give_the_new_names() {
+    let (dir, source, destination) = two_images();
+    write_file(&source.join("c.jpg"), "C");
+    let map = dir.path().join("map.csv");
+    write_file(
+        &map,
+        "original,new\na.jpg, beach.jpg\nb.jpg,2023/sunset.jpg\n",
+    );
+
+    assert_eq!(
+        read_map(&map).unwrap(),
+        HashMap::from([
+            ("a.jpg".to_string(), PathBuf::from("beach.jpg")),
+            ("b.jpg".to_string(), PathBuf::from("2023/sunset.jpg")),
+        ])
+    );
+    let args = parse_args(&["--map", arg(&map), arg(&source), arg(&destination)]);
+    let source_paths = canonicalize_sources(&args).unwrap();
+    let mut source_files = get_source_files(&source_paths, &args).unwrap();
+    source_files.sort();
+    let pairs = mapped_pairs(
+        source_files,
+        &map,
+        &source_paths,
+        &destination,
+        false,
+        &args,
+    )
+    .unwrap();
+    assert_eq!(
+        relative_files(
+            &destination,
+            pairs.into_iter().map(|(_, file)| file).collect()
+        ),
+        ["2023/sunset.jpg", "beach.jpg"]
+    );
+
+    let strict = parse_args(&[
+        "--map",
+        arg(&map),
+        "--map-strict",
+        arg(&source),
+        arg(&destination),
+    ]);
+    assert!(matches!(run(&strict), Err(ImgmvError::Unmapped(1))));
+    assert_eq!(names(&source), ["a.jpg", "b.jpg", "c.jpg"]);
+
+    let summary = run(&args).unwrap();
+    assert_eq!(summary.moved, 2);
+    assert_eq!(names(&source), ["c.jpg"]);
+    assert_eq!(
+        fs::read_to_string(destination.join("beach.jpg")).unwrap(),
+        "A"
+    );
+    assert_eq!(
+        fs::read_to_string(destination.join("2023/sunset.jpg")).unwrap(),
+        "B"
+    );
+
+    for invalid in [
+        "a.jpg\n",
+        "a.jpg,../out.jpg\n",
+        "a.jpg,x.jpg\na.jpg,y.jpg\n",
+    ] {
+        write_file(&map, invalid);
+        assert!(
+            matches!(read_map(&map), Err(ImgmvError::InvalidMap { .. })),
+            "{:?}",
+            invalid
+        );
+    }
+}
+
+#[test]
+fn map_files_keep_the_destination_folders() {
+    let (dir, source, destination) = two_images();
+    write_file(&source.join("trip/c.jpg"), "C");
+    let map = dir.path().join("map.csv");
+    write_file(&map, "a.jpg,beach.jpg\nc.jpg,sunset.jpg\n");
+
+    let plan = plan(&parse_args(&[
+        "--map",
+        arg(&map),
+        "-r",
+        "--preserve-structure",
+        "--by-camera",
+        arg(&source),
+        arg(&destination),
+    ]));
+    assert_eq!(
+        relative_files(
+            &destination,
+            plan.files
+                .iter()
+                .map(|file| file.destination.clone())
+                .collect()
+        ),
+        ["trip/unknown/sunset.jpg", "unknown/beach.jpg"]
+    );
+}
+
+#[test]
+fn ext_rewrite_renames_the_mapped_extensions() {
+    assert_eq!(
+        parse_ext_rewrite(".JPEG=.jpg"),
+        Ok(("jpeg".to_string(), "jpg".to_string()))
+    );
+    for invalid in ["jpeg", "=jpg", "jpeg=", "jpeg=a/b"] {
+        assert!(parse_ext_rewrite(invalid).is_err(), "{}", invalid);
+    }
+
+    let (_dir, source, destination) = two_images();
+    fs::rename(source.join("a.jpg"), source.join("a.jpeg")).unwrap();
+    fs::rename(source.join("b.jpg"), source.join("b.JPEG")).unwrap();
+    write_file(&source.join("c.tiff"), "C");
+    write_file(&source.join("d.png"), "D");
+    let args = parse_args(&[
+        "-p",
+        "x",
+        "--sort=name",
+        "--ext-rewrite",
+        "jpeg=jpg,tiff=tif",
+        arg(&source),
+        arg(&destination),
+    ]);
+
+    assert_eq!(
+        destination_names(&plan(&args)),
+        ["x_0.jpg", "x_1.jpg", "x_2.tif", "x_3.png"]
+    );
+    run(&args).unwrap();
+    assert_eq!(
+        fs::read_to_string(destination.join("x_0.jpg")).unwrap(),
+        "A"
+    );
+    assert_eq!(
+        fs::read_to_string(destination.join("x_1.jpg")).unwrap(),
+        "B"
+    );
+}
+
+#[test]
+fn list_only_lists_the_selected_source_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("trip");
+    for name in ["b.jpg", "a.jpg", "trip_0.jpg", "notes.txt"] {
+        write_file(&source.join(name), name);
+    }
+    let source_paths = vec![source.clone()];
+
+    let args = parse_args(&["--sort=name", arg(&source)]);
+    let mut found = get_source_files(&source_paths, &args).unwrap();
+    found.sort();
+    assert_eq!(list_source_files(&source_paths, &args).unwrap(), found);
+
+    let args = parse_args(&[
+        "--sort=name",
+        "--skip-renamed",
+        "--max-files",
+        "1",
+        arg(&source),
+    ]);
+    assert_eq!(
+        list_source_files(&source_paths, &args).unwrap(),
+        [source.join("a.jpg")]
+    );
+}
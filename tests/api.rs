@@ -0,0 +1,167 @@
+//! Tests of the public library API, used as another tool would use it.
+
+use clap::Parser;
+use imgmv_rs::{
+    Args, ImgmvError, TerminalPrompt, disk_file_operation, generate_source_destination_pairs,
+    get_prefix, get_source_files, move_images,
+};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Parse command-line arguments, as given after `imgmv`.
+fn parse_args(args: &[&Path]) -> Args {
+    Args::try_parse_from(std::iter::once(Path::new("imgmv")).chain(args.iter().copied()))
+        .expect("the test arguments are valid")
+}
+
+/// Create a source folder named `holiday` with two images and a text file.
+fn holiday() -> (tempfile::TempDir, PathBuf) {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("holiday");
+    fs::create_dir(&source).unwrap();
+    for name in ["beach.jpg", "dinner.png", "notes.txt"] {
+        fs::write(source.join(name), name).unwrap();
+    }
+    (dir, source)
+}
+
+#[test]
+fn get_prefix_uses_the_source_folder_name() {
+    let (dir, source) = holiday();
+    let args = parse_args(&[&source, dir.path()]);
+
+    assert_eq!(get_prefix(&args).unwrap(), "holiday");
+}
+
+#[test]
+fn get_prefix_without_a_folder_name_fails() {
+    let args = parse_args(&[Path::new("/"), Path::new("/tmp")]);
+
+    assert!(matches!(get_prefix(&args), Err(ImgmvError::NoPrefix)));
+}
+
+#[test]
+fn get_source_files_finds_only_images() {
+    let (dir, source) = holiday();
+    let args = parse_args(&[&source, dir.path()]);
+
+    let mut source_files = get_source_files(std::slice::from_ref(&source), &args).unwrap();
+    source_files.sort();
+
+    assert_eq!(
+        source_files,
+        [source.join("beach.jpg"), source.join("dinner.png")]
+    );
+}
+
+#[test]
+fn generate_source_destination_pairs_numbers_the_files() {
+    let (dir, source) = holiday();
+    let args = parse_args(&[&source, dir.path()]);
+    let source_files = vec![source.join("dinner.png"), source.join("beach.jpg")];
+
+    let pairs = generate_source_destination_pairs(
+        source_files.clone(),
+        std::slice::from_ref(&source),
+        dir.path(),
+        "holiday",
+        false,
+        &args,
+    );
+
+    assert_eq!(
+        pairs,
+        [
+            (source_files[0].clone(), dir.path().join("holiday_0.png")),
+            (source_files[1].clone(), dir.path().join("holiday_1.jpg")),
+        ]
+    );
+}
+
+#[test]
+fn generate_source_destination_pairs_compares_names_ignoring_case_on_request() {
+    let (dir, source) = holiday();
+    fs::write(source.join("BEACH.JPG"), "BEACH.JPG").unwrap();
+    let album = dir.path().join("album");
+    fs::create_dir(&album).unwrap();
+    let args = parse_args(&[Path::new("--no-rename"), &source, &album]);
+    let source_files = vec![source.join("beach.jpg"), source.join("BEACH.JPG")];
+    let pairs = |case_insensitive| {
+        generate_source_destination_pairs(
+            source_files.clone(),
+            std::slice::from_ref(&source),
+            &album,
+            "",
+            case_insensitive,
+            &args,
+        )
+    };
+
+    let destinations = |pairs: Vec<(PathBuf, PathBuf)>| {
+        pairs
+            .into_iter()
+            .map(|(_, destination)| destination)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        destinations(pairs(true)),
+        [album.join("beach.jpg"), album.join("BEACH-1.JPG")]
+    );
+    assert_eq!(
+        destinations(pairs(false)),
+        [album.join("beach.jpg"), album.join("BEACH.JPG")]
+    );
+    assert_eq!(fs::read_dir(&album).unwrap().count(), 0);
+}
+
+#[test]
+fn move_images_moves_and_renames_the_images() {
+    let (dir, source) = holiday();
+    let destination = dir.path().join("album");
+    fs::create_dir(&destination).unwrap();
+    let args = parse_args(&[Path::new("--sort=name"), &source, &destination]);
+
+    let summary = move_images(
+        vec![source.clone()],
+        destination.clone(),
+        "holiday",
+        &args,
+        &disk_file_operation(&args),
+        &(),
+        &TerminalPrompt,
+    )
+    .unwrap();
+
+    assert_eq!((summary.moved, summary.failed), (2, 0));
+    assert_eq!(
+        fs::read_to_string(destination.join("holiday_0.jpg")).unwrap(),
+        "beach.jpg"
+    );
+    assert_eq!(
+        fs::read_to_string(destination.join("holiday_1.png")).unwrap(),
+        "dinner.png"
+    );
+    assert!(source.join("notes.txt").exists());
+    assert!(!source.join("beach.jpg").exists());
+}
+
+#[test]
+fn move_images_in_a_dry_run_leaves_the_files_alone() {
+    let (dir, source) = holiday();
+    let args = parse_args(&[Path::new("--dry-run"), &source, dir.path()]);
+
+    let summary = move_images(
+        vec![source.clone()],
+        dir.path().to_path_buf(),
+        "holiday",
+        &args,
+        &disk_file_operation(&args),
+        &(),
+        &TerminalPrompt,
+    )
+    .unwrap();
+
+    assert_eq!(summary.moved, 2);
+    assert!(source.join("beach.jpg").exists());
+    assert!(!dir.path().join("holiday_0.jpg").exists());
+}
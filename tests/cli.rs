@@ -0,0 +1,281 @@
+//! Tests of the `imgmv` command, run as a user would run it.
+
+use clap::Parser;
+use imgmv_rs::{
+    Args, canonicalize_sources, generate_source_destination_pairs, get_destination_path,
+    get_source_files,
+};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Output;
+
+/// Run `imgmv` in a folder with the arguments, ignoring any config file outside the folder and
+/// with the trash in `data/Trash` in the folder.
+fn imgmv(folder: &Path, args: &[&str]) -> Output {
+    imgmv_with_input(folder, args, "")
+}
+
+/// Run `imgmv` in a folder as `imgmv` does, with the input on stdin.
+fn imgmv_with_input(folder: &Path, args: &[&str], input: &str) -> Output {
+    let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_imgmv"))
+        .args(args)
+        .current_dir(folder)
+        .env("XDG_CONFIG_HOME", folder)
+        .env("XDG_DATA_HOME", folder.join("data"))
+        .env_remove("RUST_LOG")
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .expect("imgmv can be run");
+    std::io::Write::write_all(&mut child.stdin.take().unwrap(), input.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+/// Get the standard output of a successful run.
+fn stdout(output: Output) -> String {
+    assert!(
+        output.status.success(),
+        "imgmv failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Create a folder with a source folder `src` with two images, and a destination folder `dst`.
+fn two_images() -> (tempfile::TempDir, PathBuf, PathBuf) {
+    let dir = tempfile::tempdir().unwrap();
+    let (source, destination) = (dir.path().join("src"), dir.path().join("dst"));
+    fs::create_dir(&source).unwrap();
+    fs::create_dir(&destination).unwrap();
+    fs::write(source.join("a.jpg"), "A").unwrap();
+    fs::write(source.join("b.jpg"), "B").unwrap();
+    (dir, source, destination)
+}
+
+#[test]
+fn json_dry_run_prints_the_planned_pairs() {
+    let (dir, ..) = two_images();
+    let options = [
+        "-d",
+        "--format=json",
+        "-p",
+        "x",
+        "--sort=name",
+        "src",
+        "dst",
+    ];
+
+    let output = stdout(imgmv(dir.path(), &options));
+
+    let operations: Vec<serde_json::Value> = serde_json::from_str(&output).unwrap();
+    let printed: Vec<(PathBuf, PathBuf)> = operations
+        .iter()
+        .map(|operation| {
+            assert_eq!(operation["operation"], "move");
+            (
+                PathBuf::from(operation["source"].as_str().unwrap()),
+                PathBuf::from(operation["destination"].as_str().unwrap()),
+            )
+        })
+        .collect();
+    let (source, destination) = (dir.path().join("src"), dir.path().join("dst"));
+    let args = Args::try_parse_from(
+        ["imgmv"]
+            .iter()
+            .chain(&options[..options.len() - 2])
+            .map(Path::new)
+            .chain([source.as_path(), &destination]),
+    )
+    .unwrap();
+    let source_paths = canonicalize_sources(&args).unwrap();
+    let mut source_files = get_source_files(&source_paths, &args).unwrap();
+    source_files.sort();
+    let pairs = generate_source_destination_pairs(
+        source_files,
+        &source_paths,
+        &get_destination_path(&args).unwrap(),
+        "x",
+        false,
+        &args,
+    );
+    assert_eq!(printed, pairs);
+    assert_eq!(fs::read_dir(&destination).unwrap().count(), 0);
+}
+
+#[test]
+fn malformed_config_files_are_reported() {
+    let (dir, ..) = two_images();
+    fs::write(dir.path().join("imgmv.toml"), "copy = \"yes\"\n").unwrap();
+
+    let output = imgmv(dir.path(), &["-d", "src", "dst"]);
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("imgmv.toml"), "{}", stderr);
+}
+
+#[test]
+fn quiet_runs_print_nothing() {
+    let (dir, ..) = two_images();
+
+    let loud = stdout(imgmv(dir.path(), &["-v", "-c", "src", "dst"]));
+    let quiet = stdout(imgmv(dir.path(), &["-q", "-f", "src", "dst"]));
+
+    assert!(!loud.is_empty());
+    assert_eq!(quiet, "");
+    assert_eq!(fs::read_dir(dir.path().join("src")).unwrap().count(), 0);
+    assert!(
+        !imgmv(dir.path(), &["-q", "-v", "src", "dst"])
+            .status
+            .success()
+    );
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+#[test]
+fn trash_keeps_overwritten_files_in_the_trash() {
+    let (dir, _, destination) = two_images();
+    fs::write(destination.join("x_0.jpg"), "old").unwrap();
+    let options = [
+        "-f",
+        "--trash",
+        "-v",
+        "-p",
+        "x",
+        "--sort=name",
+        "src",
+        "dst",
+    ];
+
+    let dry_run = stdout(imgmv(dir.path(), &[&["-d"], &options[..]].concat()));
+    assert!(dry_run.contains("[dry-run] trash"), "{}", dry_run);
+    assert!(!dir.path().join("data/Trash").exists());
+
+    stdout(imgmv(dir.path(), &options));
+    assert_eq!(
+        fs::read_to_string(destination.join("x_0.jpg")).unwrap(),
+        "A"
+    );
+    let trashed = dir.path().join("data/Trash/files/x_0.jpg");
+    assert_eq!(fs::read_to_string(trashed).unwrap(), "old");
+}
+
+#[test]
+fn log_files_get_the_operations_of_the_last_run() {
+    let (dir, ..) = two_images();
+    let log = |run: &[&str]| {
+        stdout(imgmv(
+            dir.path(),
+            &[&["--log-file=imgmv.log", "-q"], run].concat(),
+        ));
+        fs::read_to_string(dir.path().join("imgmv.log")).unwrap()
+    };
+
+    let moved = log(&["-p", "x", "--sort=name", "src", "dst"]);
+    let line = |source: &str, destination: &str| {
+        format!(
+            " DEBUG imgmv_rs] move {:?} -> {:?}",
+            dir.path().join("src").join(source),
+            dir.path().join("dst").join(destination)
+        )
+    };
+    assert!(moved.contains(&line("a.jpg", "x_0.jpg")), "{}", moved);
+    assert!(moved.contains(&line("b.jpg", "x_1.jpg")), "{}", moved);
+    assert!(moved.lines().all(|line| line.starts_with('[')));
+
+    let undone = log(&["--undo", "dst/.imgmv-undo.jsonl"]);
+    assert!(!undone.contains(&line("a.jpg", "x_0.jpg")), "{}", undone);
+    assert!(undone.contains("a.jpg"), "{}", undone);
+
+    // The include pattern makes globset log its own debug messages.
+    let filtered = log(&["-p", "y", "--include=*.jpg", "src", "dst"]);
+    assert!(filtered.contains(" DEBUG imgmv_rs] "), "{}", filtered);
+    assert!(
+        filtered
+            .lines()
+            .all(|line| line.split_whitespace().nth(2).unwrap().starts_with("imgmv")),
+        "{}",
+        filtered
+    );
+}
+
+#[test]
+fn completions_are_generated_for_each_shell() {
+    let dir = tempfile::tempdir().unwrap();
+    for shell in ["bash", "zsh", "fish", "powershell", "elvish"] {
+        let script = stdout(imgmv(dir.path(), &["completions", shell]));
+
+        assert!(script.contains("lowercase-name"), "{}: {}", shell, script);
+        assert!(script.contains("imgmv"), "{}: {}", shell, script);
+    }
+    assert!(!imgmv(dir.path(), &["completions", "tcsh"]).status.success());
+}
+
+#[test]
+fn color_never_output_has_no_escape_codes() {
+    let (dir, ..) = two_images();
+    let output = |color: &str| {
+        let options = ["-c", "-v", "--dry-run", "-p", "x", color, "src", "dst"];
+        let output = imgmv(dir.path(), &options);
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        format!("{}{}", stdout(output), stderr)
+    };
+
+    let always = output("--color=always");
+    assert!(always.contains('\x1b'), "{}", always);
+    for color in ["--color=never", "--color=auto"] {
+        let plain = output(color);
+        assert!(plain.contains("x_0.jpg"), "{}", plain);
+        assert!(!plain.contains('\x1b'), "{}: {:?}", color, plain);
+    }
+}
+
+#[test]
+fn list_only_needs_no_prefix() {
+    let (dir, ..) = two_images();
+
+    let listed = stdout(imgmv_with_input(
+        dir.path(),
+        &["--stdin", "--list-only", "--sort=name"],
+        "src/b.jpg\nsrc/a.jpg\n",
+    ));
+
+    let listed: Vec<&str> = listed.lines().collect();
+    assert_eq!(listed.len(), 2, "{:?}", listed);
+    assert!(
+        listed[0].ends_with("a.jpg") && listed[1].ends_with("b.jpg"),
+        "{:?}",
+        listed
+    );
+    assert!(
+        !imgmv_with_input(dir.path(), &["--stdin", "-d"], "src/a.jpg\n")
+            .status
+            .success()
+    );
+}
+
+#[test]
+fn files_that_are_their_own_destination_are_skipped_with_a_warning() {
+    let (dir, ..) = two_images();
+
+    stdout(imgmv(
+        dir.path(),
+        &["--log-file=imgmv.log", "-c", "--no-rename", "src", "src"],
+    ));
+
+    let log = fs::read_to_string(dir.path().join("imgmv.log")).unwrap();
+    let warnings: Vec<&str> = log.lines().filter(|line| line.contains(" WARN ")).collect();
+    assert_eq!(warnings.len(), 2, "{}", log);
+    assert!(
+        warnings
+            .iter()
+            .all(|line| line.contains("its own destination")),
+        "{}",
+        log
+    );
+    assert_eq!(
+        fs::read_to_string(dir.path().join("src/a.jpg")).unwrap(),
+        "A"
+    );
+}